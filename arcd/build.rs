@@ -1,4 +1,8 @@
 fn main() {
     #[cfg(feature = "grpc")]
-    tonic_build::compile_protos("proto/node.proto").expect("protobuf creation failed")
+    {
+        tonic_build::compile_protos("proto/node.proto").expect("protobuf creation failed");
+        tonic_build::compile_protos("proto/modules/listener.proto")
+            .expect("protobuf creation failed");
+    }
 }
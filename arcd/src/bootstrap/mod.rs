@@ -1,6 +1,6 @@
 use cookie_factory::{bytes::be_u8, combinator::slice};
 use ensicoin_messages::resource::fn_block;
-use ensicoin_serializer::{serializer::fn_list, Sha256Result};
+use ensicoin_serializer::serializer::fn_list;
 
 use std::fs;
 #[cfg(feature = "service_discover")]
@@ -8,93 +8,127 @@ pub mod discover;
 #[cfg(feature = "matrix_discover")]
 pub mod matrix;
 
-pub fn clean(data_dir: std::path::PathBuf) -> Result<(), String> {
-    let mut settings = data_dir.clone();
-    settings.push("settings.ron");
+/// Databases of every chain state tree, used before they were merged in the chainstate database
+const LEGACY_CHAIN_DIRS: &[&str] = &[
+    "blockchain",
+    "utxo",
+    "reverse_chain",
+    "spent_tx",
+    "stats",
+    "past_block",
+    "work",
+    "headers",
+];
+
+fn has_legacy_layout(data_dir: &std::path::Path) -> bool {
+    LEGACY_CHAIN_DIRS
+        .iter()
+        .any(|dir| data_dir.join(dir).exists())
+}
 
-    let mut blockchain_dir = std::path::PathBuf::new();
-    blockchain_dir.push(data_dir.clone());
-    blockchain_dir.push("blockchain");
+/// Something already removed is not an error
+fn ignore_missing(result: std::io::Result<()>) -> std::io::Result<()> {
+    match result {
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
 
-    let mut utxo_dir = std::path::PathBuf::new();
-    utxo_dir.push(data_dir.clone());
-    utxo_dir.push("utxo");
+fn remove_legacy_dirs(data_dir: &std::path::Path) -> std::io::Result<()> {
+    for dir in LEGACY_CHAIN_DIRS {
+        ignore_missing(fs::remove_dir_all(data_dir.join(dir)))?;
+    }
+    Ok(())
+}
 
-    let mut rev_dir = std::path::PathBuf::new();
-    rev_dir.push(data_dir.clone());
-    rev_dir.push("reverse_chain");
+/// Copies the blocks stored with the old layout to the chain state and removes the old
+/// databases. Returns true if blocks were migrated, the chain state must then be reindexed
+pub fn migrate_legacy(
+    data_dir: &std::path::Path,
+    chainstate: &sled::Db,
+    network: &crate::constants::NetworkParams,
+) -> Result<bool, String> {
+    if !has_legacy_layout(data_dir) {
+        return Ok(false);
+    }
+    let stats = chainstate
+        .open_tree("stats")
+        .map_err(|e| format!("Can't open stats tree: {}", e))?;
+    match stats.contains_key("genesis_block") {
+        Ok(true) => (),
+        Ok(false) => init_chainstate(chainstate, network)?,
+        Err(e) => return Err(format!("Can't read stats: {}", e)),
+    }
+    let legacy_blocks = data_dir.join("blockchain");
+    if legacy_blocks.exists() {
+        let legacy = sled::Db::open(legacy_blocks)
+            .map_err(|e| format!("Can't open old blockchain database: {}", e))?;
+        let blocks = chainstate
+            .open_tree("blockchain")
+            .map_err(|e| format!("Can't open blockchain tree: {}", e))?;
+        for entry in legacy.iter() {
+            let (hash, block) = entry.map_err(|e| format!("Can't read old block: {}", e))?;
+            blocks
+                .insert(hash, block)
+                .map_err(|e| format!("Can't store old block: {}", e))?;
+        }
+        chainstate
+            .flush()
+            .map_err(|e| format!("Can't flush chainstate: {}", e))?;
+    }
+    remove_legacy_dirs(data_dir).map_err(|e| format!("Can't remove old databases: {}", e))?;
+    Ok(true)
+}
 
-    let mut spent_tx_dir = std::path::PathBuf::new();
-    spent_tx_dir.push(data_dir.clone());
-    spent_tx_dir.push("spent_tx");
+pub fn clean(data_dir: std::path::PathBuf) -> Result<(), String> {
+    if let Err(e) = remove_legacy_dirs(&data_dir) {
+        return Err(format!("Can't clean data_dir: {}", e));
+    }
 
-    let mut stats_dir = std::path::PathBuf::new();
-    stats_dir.push(data_dir.clone());
-    stats_dir.push("stats");
+    let mut settings = data_dir.clone();
+    settings.push("settings.ron");
+
+    let mut chainstate_dir = std::path::PathBuf::new();
+    chainstate_dir.push(data_dir.clone());
+    chainstate_dir.push("chainstate");
 
     let mut addr_dir = std::path::PathBuf::new();
-    addr_dir.push(data_dir.clone());
+    addr_dir.push(data_dir);
     addr_dir.push("adress_manager");
 
-    let mut work_dir = std::path::PathBuf::new();
-    work_dir.push(data_dir);
-    work_dir.push("work");
-
-    match std::fs::remove_dir_all(utxo_dir)
-        .and(std::fs::remove_dir_all(rev_dir))
-        .and(std::fs::remove_dir_all(spent_tx_dir))
-        .and(std::fs::remove_dir_all(stats_dir))
-        .and(std::fs::remove_dir_all(blockchain_dir.clone()))
-        .and(std::fs::remove_file(settings.clone()))
-        .and(std::fs::remove_dir_all(work_dir))
-        .and(std::fs::remove_dir_all(addr_dir))
+    match ignore_missing(std::fs::remove_dir_all(chainstate_dir))
+        .and(ignore_missing(std::fs::remove_file(settings.clone())))
+        .and(ignore_missing(std::fs::remove_dir_all(addr_dir)))
     {
         Ok(_) => Ok(()),
         Err(e) => Err(format!("Can't clean data_dir: {}", e)),
     }
 }
 
-pub fn bootstrap(data_dir: &std::path::PathBuf) -> Result<(), String> {
+pub fn bootstrap(
+    data_dir: &std::path::PathBuf,
+    network: &crate::constants::NetworkParams,
+) -> Result<(), String> {
     let mut settings = std::path::PathBuf::new();
     settings.push(data_dir);
     settings.push("settings.ron");
 
-    let mut blockchain_dir = std::path::PathBuf::new();
-    blockchain_dir.push(data_dir);
-    blockchain_dir.push("blockchain");
-
-    let mut stats_dir = std::path::PathBuf::new();
-    stats_dir.push(data_dir);
-    stats_dir.push("stats");
-
-    let mut work_dir = std::path::PathBuf::new();
-    work_dir.push(data_dir);
-    work_dir.push("work");
-    let work = sled::Db::open(work_dir).unwrap();
+    let mut chainstate_dir = std::path::PathBuf::new();
+    chainstate_dir.push(data_dir);
+    chainstate_dir.push("chainstate");
 
-    let _ = match fs::File::create(settings) {
+    let _ = match fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(settings)
+    {
         Ok(f) => f,
         Err(e) => {
             return Err(format!("Can't bootstrap at that location: {}", e));
         }
     };
 
-    let genesis = ensicoin_messages::resource::Block {
-        header: ensicoin_messages::resource::BlockHeader {
-            version: 0,
-            flags: vec!["ici cest limag".to_string()],
-            prev_block: Sha256Result::from([0; 32]),
-            merkle_root: Sha256Result::from([0; 32]),
-            timestamp: 1_558_540_052,
-            nonce: 42,
-            height: 0,
-            target: Sha256Result::from([
-                0, 0, 15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0,
-            ]),
-        },
-        txs: Vec::new(),
-    };
+    let genesis = network.genesis();
     let genesis_hash = genesis
         .double_hash()
         .iter()
@@ -105,18 +139,29 @@ pub fn bootstrap(data_dir: &std::path::PathBuf) -> Result<(), String> {
         });
     println!("Welcome to ensicoin ! Setting up the DB and storing settings");
     println!("Genesis hash: {}", &genesis_hash);
-    let blockchain_db = match sled::Db::open(blockchain_dir) {
+    let chainstate = match sled::Db::open(chainstate_dir) {
         Ok(db) => db,
         Err(e) => {
-            return Err(format!("Can't open blockchain database: {}", e));
+            return Err(format!("Can't open chainstate database: {}", e));
         }
     };
-    let stats_db = match sled::Db::open(stats_dir) {
-        Ok(db) => db,
-        Err(e) => {
-            return Err(format!("Can't open blockchain database: {}", e));
-        }
+    init_chainstate(&chainstate, network)
+}
+
+/// Stores the genesis block of the network as the best block of an empty chain state
+pub fn init_chainstate(
+    chainstate: &sled::Db,
+    network: &crate::constants::NetworkParams,
+) -> Result<(), String> {
+    let genesis = network.genesis();
+    let open_tree = |name: &str| {
+        chainstate
+            .open_tree(name)
+            .map_err(|e| format!("Can't open {} tree: {}", name, e))
     };
+    let blockchain_db = open_tree("blockchain")?;
+    let stats_db = open_tree("stats")?;
+    let work = open_tree("work")?;
     if let Err(e) = blockchain_db.insert(
         &genesis.double_hash(),
         ensicoin_messages::as_bytes(fn_block(&genesis)),
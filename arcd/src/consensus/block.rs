@@ -0,0 +1,423 @@
+use super::{tx, BlockContext, ValidationError};
+use crate::{
+    constants::{MAX_BLOCK_SIZE, MAX_FUTURE_BLOCK_TIME},
+    data::linkedblock::LinkedBlock,
+};
+use ensicoin_messages::resource::{BlockHeader, Transaction};
+use num_bigint::BigUint;
+use std::collections::HashSet;
+
+pub fn coinbase_height(coinbase: &Transaction) -> Result<u32, ValidationError> {
+    match coinbase.flags.get(0).map(|flag| flag.parse()) {
+        Some(Ok(height)) => Ok(height),
+        _ => Err(ValidationError::MissingCoinbaseHeight),
+    }
+}
+
+pub fn check_context_free(block: &LinkedBlock) -> Result<(), ValidationError> {
+    if block.txs.is_empty() {
+        return Err(ValidationError::EmptyBlock);
+    }
+    let size = block.size();
+    if size > MAX_BLOCK_SIZE {
+        return Err(ValidationError::BlockTooLarge {
+            size,
+            max: MAX_BLOCK_SIZE,
+        });
+    }
+    let target = BigUint::from_bytes_be(&block.header.target);
+    if BigUint::from_bytes_be(&block.header.double_hash()) >= target {
+        return Err(ValidationError::InsufficientProofOfWork);
+    }
+    let merkle_root = block.merkle_root();
+    if block.header.merkle_root != merkle_root {
+        return Err(ValidationError::InvalidMerkleRoot {
+            expected: merkle_root,
+            got: block.header.merkle_root,
+        });
+    }
+    let coinbase = &block.txs[0].transaction;
+    coinbase_height(coinbase)?;
+    tx::check_outputs(coinbase).map_err(|e| e.in_transaction(coinbase.double_hash()))?;
+    let mut spent = HashSet::new();
+    for ltx in &block.txs[1..] {
+        tx::check_context_free(&ltx.transaction)
+            .map_err(|e| e.in_transaction(ltx.transaction.double_hash()))?;
+        for input in &ltx.transaction.inputs {
+            if !spent.insert(&input.previous_output) {
+                return Err(ValidationError::DoubleSpend(input.previous_output.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn check_header(
+    header: &BlockHeader,
+    previous: &BlockHeader,
+    expected_target: &BigUint,
+) -> Result<(), ValidationError> {
+    if header.height != previous.height + 1 {
+        return Err(ValidationError::InvalidHeight {
+            expected: previous.height + 1,
+            got: header.height,
+        });
+    }
+    let target = BigUint::from_bytes_be(&header.target);
+    if BigUint::from_bytes_be(&header.double_hash()) >= target {
+        return Err(ValidationError::InsufficientProofOfWork);
+    }
+    if target != *expected_target {
+        return Err(ValidationError::InvalidTarget {
+            expected: expected_target.clone(),
+            got: target,
+        });
+    }
+    Ok(())
+}
+
+pub fn check_contextual(
+    block: &LinkedBlock,
+    context: &BlockContext,
+) -> Result<(), ValidationError> {
+    let header = &block.header;
+    if header.height != context.previous.height + 1 {
+        return Err(ValidationError::InvalidHeight {
+            expected: context.previous.height + 1,
+            got: header.height,
+        });
+    }
+    let target = BigUint::from_bytes_be(&header.target);
+    if target != context.target {
+        return Err(ValidationError::InvalidTarget {
+            expected: context.target.clone(),
+            got: target,
+        });
+    }
+    if header.timestamp <= context.median_time_past {
+        return Err(ValidationError::TimestampTooOld {
+            median_time_past: context.median_time_past,
+            got: header.timestamp,
+        });
+    }
+    if header.timestamp > context.now + MAX_FUTURE_BLOCK_TIME {
+        return Err(ValidationError::TimestampTooNew {
+            max: context.now + MAX_FUTURE_BLOCK_TIME,
+            got: header.timestamp,
+        });
+    }
+    let height = coinbase_height(&block.txs[0].transaction)?;
+    if height != header.height {
+        return Err(ValidationError::CoinbaseHeightMismatch {
+            expected: header.height,
+            got: height,
+        });
+    }
+    Ok(())
+}
+
+pub fn check_utxo(block: &LinkedBlock, context: &BlockContext) -> Result<(), ValidationError> {
+    let mut fees: u64 = 0;
+    for ltx in &block.txs[1..] {
+        tx::check_maturity(ltx, block.header.height, context.network.coinbase_maturity)
+            .map_err(|e| e.in_transaction(ltx.transaction.double_hash()))?;
+        let fee =
+            tx::check_inputs(ltx).map_err(|e| e.in_transaction(ltx.transaction.double_hash()))?;
+        fees = match fees.checked_add(fee) {
+            Some(f) => f,
+            None => return Err(ValidationError::InputValueOverflow),
+        };
+    }
+    let coinbase = &block.txs[0].transaction;
+    let coinbase_value =
+        tx::check_outputs(coinbase).map_err(|e| e.in_transaction(coinbase.double_hash()))?;
+    let max = context
+        .network
+        .block_subsidy(block.header.height)
+        .saturating_add(fees);
+    if coinbase_value > max {
+        return Err(ValidationError::CoinbaseValueTooHigh {
+            max,
+            got: coinbase_value,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_context_free, check_contextual, check_header, check_utxo};
+    use crate::{
+        consensus::{BlockContext, ValidationError},
+        constants::{Network, MAX_BLOCK_SIZE, MAX_FUTURE_BLOCK_TIME},
+        data::{
+            linkedblock::LinkedBlock,
+            linkedtx::{Dependency, DependencyType, LinkedTransaction},
+            UtxoData,
+        },
+        wallet::p2pkh_script,
+    };
+    use ensicoin_messages::resource::{
+        script::Script,
+        tx::{TransactionInput, TransactionOutput},
+        BlockHeader, Outpoint, Transaction,
+    };
+    use ensicoin_serializer::Sha256Result;
+    use num_bigint::BigUint;
+
+    const EASY_TARGET: [u8; 32] = [0xff; 32];
+
+    fn outpoint(index: u32) -> Outpoint {
+        Outpoint {
+            hash: Sha256Result::from([7; 32]),
+            index,
+        }
+    }
+
+    fn coinbase(height: u32, value: u64) -> Transaction {
+        Transaction {
+            version: 0,
+            flags: vec![height.to_string()],
+            inputs: Vec::new(),
+            outputs: vec![TransactionOutput {
+                value,
+                script: p2pkh_script(&[1; 20]),
+            }],
+        }
+    }
+
+    fn spend(index: u32) -> Transaction {
+        Transaction {
+            version: 0,
+            flags: Vec::new(),
+            inputs: vec![TransactionInput {
+                previous_output: outpoint(index),
+                script: Script::from(Vec::new()),
+            }],
+            outputs: vec![TransactionOutput {
+                value: 1,
+                script: p2pkh_script(&[2; 20]),
+            }],
+        }
+    }
+
+    fn header(height: u32, timestamp: u64) -> BlockHeader {
+        BlockHeader {
+            version: 0,
+            flags: Vec::new(),
+            prev_block: Sha256Result::from([0; 32]),
+            merkle_root: Sha256Result::from([0; 32]),
+            timestamp,
+            height,
+            target: Sha256Result::from(EASY_TARGET),
+            nonce: 0,
+        }
+    }
+
+    fn block(height: u32, txs: Vec<Transaction>) -> LinkedBlock {
+        let mut block = LinkedBlock {
+            header: header(height, 1_000),
+            txs: txs.into_iter().map(LinkedTransaction::new).collect(),
+        };
+        block.header.merkle_root = block.merkle_root();
+        block
+    }
+
+    fn context(height: u32) -> BlockContext {
+        BlockContext {
+            previous: header(height - 1, 900),
+            target: BigUint::from_bytes_be(&EASY_TARGET),
+            median_time_past: 900,
+            now: 1_000,
+            network: Network::Mainnet.params(),
+        }
+    }
+
+    #[test]
+    fn valid_block() {
+        let block = block(1, vec![coinbase(1, 50)]);
+        assert!(check_context_free(&block).is_ok());
+        assert!(check_contextual(&block, &context(1)).is_ok());
+        assert!(check_utxo(&block, &context(1)).is_ok());
+    }
+
+    #[test]
+    fn empty_block() {
+        match check_context_free(&block(1, Vec::new())) {
+            Err(ValidationError::EmptyBlock) => (),
+            r => panic!("expected EmptyBlock, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn block_too_large() {
+        let mut tx = coinbase(1, 50);
+        tx.flags.push("a".repeat(MAX_BLOCK_SIZE));
+        match check_context_free(&block(1, vec![tx])) {
+            Err(ValidationError::BlockTooLarge { .. }) => (),
+            r => panic!("expected BlockTooLarge, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn insufficient_proof_of_work() {
+        let mut block = block(1, vec![coinbase(1, 50)]);
+        block.header.target = Sha256Result::from([0; 32]);
+        match check_context_free(&block) {
+            Err(ValidationError::InsufficientProofOfWork) => (),
+            r => panic!("expected InsufficientProofOfWork, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn invalid_merkle_root() {
+        let mut block = block(1, vec![coinbase(1, 50)]);
+        block.header.merkle_root = Sha256Result::from([1; 32]);
+        match check_context_free(&block) {
+            Err(ValidationError::InvalidMerkleRoot { .. }) => (),
+            r => panic!("expected InvalidMerkleRoot, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn missing_coinbase_height() {
+        let mut tx = coinbase(1, 50);
+        tx.flags.clear();
+        match check_context_free(&block(1, vec![tx])) {
+            Err(ValidationError::MissingCoinbaseHeight) => (),
+            r => panic!("expected MissingCoinbaseHeight, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn invalid_transaction() {
+        let mut tx = spend(0);
+        tx.outputs.clear();
+        match check_context_free(&block(1, vec![coinbase(1, 50), tx])) {
+            Err(ValidationError::InvalidTransaction(_, e)) => match *e {
+                ValidationError::NoOutputs => (),
+                e => panic!("expected NoOutputs, got {:?}", e),
+            },
+            r => panic!("expected InvalidTransaction, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn double_spend_in_block() {
+        let mut other = spend(0);
+        other.outputs[0].value = 2;
+        match check_context_free(&block(1, vec![coinbase(1, 50), spend(0), other])) {
+            Err(ValidationError::DoubleSpend(o)) => assert_eq!(o, outpoint(0)),
+            r => panic!("expected DoubleSpend, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn header_height() {
+        let target = BigUint::from_bytes_be(&EASY_TARGET);
+        assert!(check_header(&header(2, 1_000), &header(1, 900), &target).is_ok());
+        match check_header(&header(3, 1_000), &header(1, 900), &target) {
+            Err(ValidationError::InvalidHeight {
+                expected: 2,
+                got: 3,
+            }) => (),
+            r => panic!("expected InvalidHeight, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn header_target() {
+        let target = BigUint::from_bytes_be(&EASY_TARGET) - 1u32;
+        match check_header(&header(2, 1_000), &header(1, 900), &target) {
+            Err(ValidationError::InvalidTarget { .. }) => (),
+            r => panic!("expected InvalidTarget, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn contextual_height() {
+        match check_contextual(&block(2, vec![coinbase(2, 50)]), &context(1)) {
+            Err(ValidationError::InvalidHeight { .. }) => (),
+            r => panic!("expected InvalidHeight, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn contextual_target() {
+        let mut context = context(1);
+        context.target = BigUint::from_bytes_be(&Network::Mainnet.params().initial_target);
+        match check_contextual(&block(1, vec![coinbase(1, 50)]), &context) {
+            Err(ValidationError::InvalidTarget { .. }) => (),
+            r => panic!("expected InvalidTarget, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn timestamp_too_old() {
+        let mut context = context(1);
+        context.median_time_past = 1_000;
+        match check_contextual(&block(1, vec![coinbase(1, 50)]), &context) {
+            Err(ValidationError::TimestampTooOld { .. }) => (),
+            r => panic!("expected TimestampTooOld, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn timestamp_too_new() {
+        let mut block = block(1, vec![coinbase(1, 50)]);
+        block.header.timestamp = 1_001 + MAX_FUTURE_BLOCK_TIME;
+        match check_contextual(&block, &context(1)) {
+            Err(ValidationError::TimestampTooNew { .. }) => (),
+            r => panic!("expected TimestampTooNew, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn coinbase_height_mismatch() {
+        match check_contextual(&block(1, vec![coinbase(2, 50)]), &context(1)) {
+            Err(ValidationError::CoinbaseHeightMismatch {
+                expected: 1,
+                got: 2,
+            }) => (),
+            r => panic!("expected CoinbaseHeightMismatch, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn coinbase_value_too_high() {
+        let subsidy = Network::Mainnet.params().block_subsidy(1);
+        assert!(check_utxo(&block(1, vec![coinbase(1, subsidy)]), &context(1)).is_ok());
+        match check_utxo(&block(1, vec![coinbase(1, subsidy + 1)]), &context(1)) {
+            Err(ValidationError::CoinbaseValueTooHigh { .. }) => (),
+            r => panic!("expected CoinbaseValueTooHigh, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn immature_coinbase_spend() {
+        let mut block = block(100, vec![coinbase(100, 50), spend(0)]);
+        block.txs[1].add_dependency(
+            outpoint(0),
+            Dependency {
+                dep_type: DependencyType::Block,
+                data: UtxoData {
+                    script: p2pkh_script(&[1; 20]),
+                    value: 50,
+                    block_height: 1,
+                    coin_base: true,
+                },
+            },
+        );
+        match check_utxo(&block, &context(100)) {
+            Err(ValidationError::InvalidTransaction(_, e)) => match *e {
+                ValidationError::ImmatureCoinbase {
+                    height: 1,
+                    spend_height: 100,
+                    ..
+                } => (),
+                e => panic!("expected ImmatureCoinbase, got {:?}", e),
+            },
+            r => panic!("expected InvalidTransaction, got {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use ensicoin_messages::resource::Outpoint;
+use ensicoin_serializer::{hash_to_string, Sha256Result};
+use num_bigint::BigUint;
+
+#[derive(Debug)]
+pub enum ValidationError {
+    EmptyBlock,
+    BlockTooLarge {
+        size: usize,
+        max: usize,
+    },
+    InsufficientProofOfWork,
+    InvalidMerkleRoot {
+        expected: Sha256Result,
+        got: Sha256Result,
+    },
+    MissingCoinbaseHeight,
+    CoinbaseHeightMismatch {
+        expected: u32,
+        got: u32,
+    },
+    CoinbaseValueTooHigh {
+        max: u64,
+        got: u64,
+    },
+    InvalidHeight {
+        expected: u32,
+        got: u32,
+    },
+    InvalidTarget {
+        expected: BigUint,
+        got: BigUint,
+    },
+    TimestampTooOld {
+        median_time_past: u64,
+        got: u64,
+    },
+    TimestampTooNew {
+        max: u64,
+        got: u64,
+    },
+    NoInputs,
+    NoOutputs,
+    ZeroValueOutput(usize),
+    OutputValueOverflow,
+    DuplicateInput(Outpoint),
+    DoubleSpend(Outpoint),
+    MissingInput(Outpoint),
+    InvalidScript(usize),
+    InputValueOverflow,
+    InputValueTooLow {
+        input: u64,
+        output: u64,
+    },
+    ImmatureCoinbase {
+        outpoint: Outpoint,
+        height: u32,
+        spend_height: u32,
+    },
+    InvalidTransaction(Sha256Result, Box<ValidationError>),
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ValidationError::EmptyBlock => write!(f, "Block has no transactions"),
+            ValidationError::BlockTooLarge { size, max } => {
+                write!(f, "Block is too large: {} bytes, max {}", size, max)
+            }
+            ValidationError::InsufficientProofOfWork => {
+                write!(f, "Block hash is above its target")
+            }
+            ValidationError::InvalidMerkleRoot { expected, got } => write!(
+                f,
+                "Invalid merkle root, expected {} got {}",
+                hash_to_string(expected),
+                hash_to_string(got)
+            ),
+            ValidationError::MissingCoinbaseHeight => {
+                write!(f, "Coinbase first flag is not the height")
+            }
+            ValidationError::CoinbaseHeightMismatch { expected, got } => write!(
+                f,
+                "Coinbase height does not match, expected {} got {}",
+                expected, got
+            ),
+            ValidationError::CoinbaseValueTooHigh { max, got } => write!(
+                f,
+                "Coinbase pays {} but subsidy and fees only allow {}",
+                got, max
+            ),
+            ValidationError::InvalidHeight { expected, got } => {
+                write!(f, "Invalid height, expected {} got {}", expected, got)
+            }
+            ValidationError::InvalidTarget { expected, got } => {
+                write!(f, "Invalid target, expected {} got {}", expected, got)
+            }
+            ValidationError::TimestampTooOld {
+                median_time_past,
+                got,
+            } => write!(
+                f,
+                "Timestamp {} is not after median time past {}",
+                got, median_time_past
+            ),
+            ValidationError::TimestampTooNew { max, got } => {
+                write!(f, "Timestamp {} is too far in the future, max {}", got, max)
+            }
+            ValidationError::NoInputs => write!(f, "Transaction has no inputs"),
+            ValidationError::NoOutputs => write!(f, "Transaction has no outputs"),
+            ValidationError::ZeroValueOutput(i) => write!(f, "Output {} has a zero value", i),
+            ValidationError::OutputValueOverflow => write!(f, "Output values overflow"),
+            ValidationError::DuplicateInput(outpoint) => write!(
+                f,
+                "Outpoint {}:{} is spent twice",
+                hash_to_string(&outpoint.hash),
+                outpoint.index
+            ),
+            ValidationError::DoubleSpend(outpoint) => write!(
+                f,
+                "Outpoint {}:{} is spent by two transactions",
+                hash_to_string(&outpoint.hash),
+                outpoint.index
+            ),
+            ValidationError::MissingInput(outpoint) => write!(
+                f,
+                "Outpoint {}:{} is not an unspent output",
+                hash_to_string(&outpoint.hash),
+                outpoint.index
+            ),
+            ValidationError::InvalidScript(i) => write!(f, "Script of input {} failed", i),
+            ValidationError::InputValueOverflow => write!(f, "Input values overflow"),
+            ValidationError::InputValueTooLow { input, output } => write!(
+                f,
+                "Inputs are worth {} but outputs are worth {}",
+                input, output
+            ),
+            ValidationError::ImmatureCoinbase {
+                outpoint,
+                height,
+                spend_height,
+            } => write!(
+                f,
+                "Coinbase output {}:{} from height {} is not mature at height {}",
+                hash_to_string(&outpoint.hash),
+                outpoint.index,
+                height,
+                spend_height
+            ),
+            ValidationError::InvalidTransaction(hash, e) => {
+                write!(f, "Invalid tx {}: {}", hash_to_string(hash), e)
+            }
+        }
+    }
+}
+
+impl ValidationError {
+    pub fn in_transaction(self, hash: Sha256Result) -> Self {
+        ValidationError::InvalidTransaction(hash, Box::new(self))
+    }
+
+    /// The block breaks a rule that depends neither on our clock nor on the chain it is
+    /// validated against, so whoever sent it is at fault
+    pub fn is_context_free(&self) -> bool {
+        match self {
+            ValidationError::EmptyBlock
+            | ValidationError::BlockTooLarge { .. }
+            | ValidationError::InsufficientProofOfWork
+            | ValidationError::InvalidMerkleRoot { .. }
+            | ValidationError::MissingCoinbaseHeight
+            | ValidationError::CoinbaseHeightMismatch { .. }
+            | ValidationError::CoinbaseValueTooHigh { .. }
+            | ValidationError::NoInputs
+            | ValidationError::NoOutputs
+            | ValidationError::ZeroValueOutput(_)
+            | ValidationError::OutputValueOverflow
+            | ValidationError::DuplicateInput(_)
+            | ValidationError::DoubleSpend(_) => true,
+            ValidationError::InvalidTransaction(_, e) => e.is_context_free(),
+            _ => false,
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
@@ -0,0 +1,47 @@
+mod block;
+mod error;
+mod tx;
+
+pub use block::coinbase_height;
+pub use error::ValidationError;
+pub use tx::{check_maturity, is_mature, signature_hash};
+
+use crate::{
+    constants::NetworkParams,
+    data::{linkedblock::LinkedBlock, linkedtx::LinkedTransaction},
+};
+use ensicoin_messages::resource::BlockHeader;
+use num_bigint::BigUint;
+
+/// State of the chain a block is validated against
+pub struct BlockContext {
+    pub previous: BlockHeader,
+    pub target: BigUint,
+    pub median_time_past: u64,
+    pub now: u64,
+    pub network: &'static NetworkParams,
+}
+
+/// Runs every consensus rule on a block: context-free rules first, then the rules depending on
+/// the previous block and finally the ones needing the linked utxos
+pub fn check_block(block: &LinkedBlock, context: &BlockContext) -> Result<(), ValidationError> {
+    block::check_context_free(block)?;
+    block::check_contextual(block, context)?;
+    block::check_utxo(block, context)
+}
+
+/// Checks a header against its parent and the target computed from the header chain, before
+/// the block is downloaded
+pub fn check_header(
+    header: &BlockHeader,
+    previous: &BlockHeader,
+    target: &BigUint,
+) -> Result<(), ValidationError> {
+    block::check_header(header, previous, target)
+}
+
+/// Runs the consensus rules of a standalone transaction, its utxos must already be linked
+pub fn check_transaction(ltx: &LinkedTransaction) -> Result<(), ValidationError> {
+    tx::check_context_free(&ltx.transaction)?;
+    tx::check_inputs(ltx).map(|_| ())
+}
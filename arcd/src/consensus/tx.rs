@@ -0,0 +1,365 @@
+use super::ValidationError;
+use crate::data::{linkedtx::LinkedTransaction, script_vm::execute_script, UtxoData};
+use cookie_factory::bytes::{be_u32, be_u64};
+use ensicoin_messages::resource::{
+    tx::{fn_outpoint, fn_tx_output},
+    Transaction,
+};
+use ensicoin_serializer::{
+    serializer::{fn_list, fn_str},
+    Sha256Result,
+};
+use sha2::Digest;
+use std::collections::HashSet;
+
+/// Hash signed by the input `i` spending an output of `referenced_value`.
+///
+/// The final round is a single SHA-256, unlike `Transaction::shash` which hashes it twice:
+/// signatures made over `shash` do not verify.
+pub fn signature_hash(tx: &Transaction, i: usize, referenced_value: u64) -> Sha256Result {
+    let mut hasher = sha2::Sha256::default();
+    for input in &tx.inputs {
+        hasher.input(ensicoin_messages::as_bytes(fn_outpoint(
+            &input.previous_output,
+        )));
+    }
+    let hash_outpoints = sha2::Sha256::digest(&hasher.result());
+
+    let mut hasher = sha2::Sha256::default();
+    for output in &tx.outputs {
+        hasher.input(ensicoin_messages::as_bytes(fn_tx_output(output)));
+    }
+    let hash_outputs = hasher.result();
+
+    let mut hasher = sha2::Sha256::default();
+    hasher.input(ensicoin_messages::as_bytes(be_u32(tx.version)));
+    hasher.input(ensicoin_messages::as_bytes(fn_list(
+        tx.flags.len() as u64,
+        tx.flags.iter().map(fn_str),
+    )));
+    hasher.input(&hash_outpoints);
+    hasher.input(ensicoin_messages::as_bytes(fn_outpoint(
+        &tx.inputs[i].previous_output,
+    )));
+    hasher.input(ensicoin_messages::as_bytes(be_u64(referenced_value)));
+    hasher.input(&hash_outputs);
+    hasher.result()
+}
+
+pub fn check_outputs(tx: &Transaction) -> Result<u64, ValidationError> {
+    if tx.outputs.is_empty() {
+        return Err(ValidationError::NoOutputs);
+    }
+    let mut output_sum: u64 = 0;
+    for (i, output) in tx.outputs.iter().enumerate() {
+        if output.value == 0 {
+            return Err(ValidationError::ZeroValueOutput(i));
+        }
+        output_sum = match output_sum.checked_add(output.value) {
+            Some(s) => s,
+            None => return Err(ValidationError::OutputValueOverflow),
+        };
+    }
+    Ok(output_sum)
+}
+
+pub fn check_context_free(tx: &Transaction) -> Result<(), ValidationError> {
+    if tx.inputs.is_empty() {
+        return Err(ValidationError::NoInputs);
+    }
+    check_outputs(tx)?;
+    let mut outpoints = HashSet::new();
+    for input in &tx.inputs {
+        if !outpoints.insert(&input.previous_output) {
+            return Err(ValidationError::DuplicateInput(
+                input.previous_output.clone(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// A coinbase output can only be spent `maturity` blocks after the block that created it
+pub fn is_mature(data: &UtxoData, spend_height: u32, maturity: u32) -> bool {
+    !data.coin_base || spend_height >= data.block_height.saturating_add(maturity)
+}
+
+pub fn check_maturity(
+    ltx: &LinkedTransaction,
+    spend_height: u32,
+    maturity: u32,
+) -> Result<(), ValidationError> {
+    for input in &ltx.transaction.inputs {
+        if let Some(dep) = ltx.dependency(&input.previous_output) {
+            if !is_mature(&dep.data, spend_height, maturity) {
+                return Err(ValidationError::ImmatureCoinbase {
+                    outpoint: input.previous_output.clone(),
+                    height: dep.data.block_height,
+                    spend_height,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn check_inputs(ltx: &LinkedTransaction) -> Result<u64, ValidationError> {
+    let tx = &ltx.transaction;
+    let mut input_sum: u64 = 0;
+    for (i, input) in tx.inputs.iter().enumerate() {
+        let dep = match ltx.dependency(&input.previous_output) {
+            Some(d) => d,
+            None => return Err(ValidationError::MissingInput(input.previous_output.clone())),
+        };
+        let mut script = input.script.clone();
+        script.concat(dep.data.script.clone());
+        if !execute_script(script, signature_hash(tx, i, dep.data.value)) {
+            return Err(ValidationError::InvalidScript(i));
+        }
+        input_sum = match input_sum.checked_add(dep.data.value) {
+            Some(s) => s,
+            None => return Err(ValidationError::InputValueOverflow),
+        };
+    }
+    let output_sum = check_outputs(tx)?;
+    if input_sum < output_sum {
+        return Err(ValidationError::InputValueTooLow {
+            input: input_sum,
+            output: output_sum,
+        });
+    }
+    Ok(input_sum - output_sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_context_free, check_inputs, check_maturity, check_outputs, signature_hash};
+    use crate::{
+        consensus::ValidationError,
+        data::{
+            linkedtx::{Dependency, DependencyType, LinkedTransaction},
+            PairedUtxo, UtxoData,
+        },
+        wallet::{p2pkh_script, pubkey_hash, TransactionBuilder},
+    };
+    use ensicoin_messages::resource::{
+        script::{Script, OP},
+        tx::{TransactionInput, TransactionOutput},
+        Outpoint, Transaction,
+    };
+    use ensicoin_serializer::Sha256Result;
+    use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+    fn key() -> SecretKey {
+        SecretKey::from_slice(&[1; 32]).unwrap()
+    }
+
+    fn coin(value: u64) -> PairedUtxo {
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &key());
+        PairedUtxo {
+            data: UtxoData {
+                script: p2pkh_script(&pubkey_hash(&public_key)),
+                value,
+                block_height: 1,
+                coin_base: false,
+            },
+            outpoint: Outpoint {
+                hash: Sha256Result::from([7; 32]),
+                index: 0,
+            },
+        }
+    }
+
+    fn signed(coin: &PairedUtxo) -> Transaction {
+        TransactionBuilder::new()
+            .coin(coin.clone(), key())
+            .pay(p2pkh_script(&[2; 20]), 40)
+            .fee(10)
+            .build()
+            .unwrap()
+    }
+
+    fn anyone_can_spend(index: u32, value: u64, coin_base: bool) -> PairedUtxo {
+        PairedUtxo {
+            data: UtxoData {
+                script: Script::from(vec![OP::True]),
+                value,
+                block_height: 10,
+                coin_base,
+            },
+            outpoint: Outpoint {
+                hash: Sha256Result::from([8; 32]),
+                index,
+            },
+        }
+    }
+
+    fn unsigned(coins: &[PairedUtxo], values: &[u64]) -> Transaction {
+        Transaction {
+            version: 0,
+            flags: Vec::new(),
+            inputs: coins
+                .iter()
+                .map(|coin| TransactionInput {
+                    previous_output: coin.outpoint.clone(),
+                    script: Script::from(Vec::new()),
+                })
+                .collect(),
+            outputs: values
+                .iter()
+                .map(|&value| TransactionOutput {
+                    value,
+                    script: p2pkh_script(&[2; 20]),
+                })
+                .collect(),
+        }
+    }
+
+    fn link_all(tx: Transaction, coins: &[PairedUtxo]) -> LinkedTransaction {
+        let mut ltx = LinkedTransaction::new(tx);
+        for coin in coins {
+            ltx.add_dependency(
+                coin.outpoint.clone(),
+                Dependency {
+                    dep_type: DependencyType::Block,
+                    data: coin.data.clone(),
+                },
+            );
+        }
+        ltx
+    }
+
+    #[test]
+    fn outputs() {
+        let coins = [anyone_can_spend(0, 10, false)];
+        match check_outputs(&unsigned(&coins, &[3, 4])) {
+            Ok(7) => (),
+            r => panic!("expected an output sum of 7, got {:?}", r),
+        }
+        match check_outputs(&unsigned(&coins, &[])) {
+            Err(ValidationError::NoOutputs) => (),
+            r => panic!("expected NoOutputs, got {:?}", r),
+        }
+        match check_outputs(&unsigned(&coins, &[3, 0])) {
+            Err(ValidationError::ZeroValueOutput(1)) => (),
+            r => panic!("expected ZeroValueOutput, got {:?}", r),
+        }
+        match check_outputs(&unsigned(&coins, &[u64::max_value(), 1])) {
+            Err(ValidationError::OutputValueOverflow) => (),
+            r => panic!("expected OutputValueOverflow, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn context_free() {
+        let coin = anyone_can_spend(0, 10, false);
+        assert!(check_context_free(&unsigned(&[coin.clone()], &[5])).is_ok());
+        match check_context_free(&unsigned(&[], &[5])) {
+            Err(ValidationError::NoInputs) => (),
+            r => panic!("expected NoInputs, got {:?}", r),
+        }
+        match check_context_free(&unsigned(&[coin.clone(), coin.clone()], &[5])) {
+            Err(ValidationError::DuplicateInput(o)) => assert_eq!(o, coin.outpoint),
+            r => panic!("expected DuplicateInput, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn maturity() {
+        let coins = [
+            anyone_can_spend(0, 10, true),
+            anyone_can_spend(1, 10, false),
+        ];
+        let ltx = link_all(unsigned(&coins, &[5]), &coins);
+        assert!(check_maturity(&ltx, 110, 100).is_ok());
+        match check_maturity(&ltx, 109, 100) {
+            Err(ValidationError::ImmatureCoinbase {
+                height: 10,
+                spend_height: 109,
+                ..
+            }) => (),
+            r => panic!("expected ImmatureCoinbase, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn missing_input() {
+        let coins = [
+            anyone_can_spend(0, 10, false),
+            anyone_can_spend(1, 10, false),
+        ];
+        match check_inputs(&link_all(unsigned(&coins, &[5]), &coins[..1])) {
+            Err(ValidationError::MissingInput(o)) => assert_eq!(o, coins[1].outpoint),
+            r => panic!("expected MissingInput, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn input_values() {
+        let coins = [
+            anyone_can_spend(0, 10, false),
+            anyone_can_spend(1, 10, false),
+        ];
+        match check_inputs(&link_all(unsigned(&coins, &[15]), &coins)) {
+            Ok(5) => (),
+            r => panic!("expected a fee of 5, got {:?}", r),
+        }
+        match check_inputs(&link_all(unsigned(&coins, &[21]), &coins)) {
+            Err(ValidationError::InputValueTooLow {
+                input: 20,
+                output: 21,
+            }) => (),
+            r => panic!("expected InputValueTooLow, got {:?}", r),
+        }
+        let coins = [
+            anyone_can_spend(0, u64::max_value(), false),
+            anyone_can_spend(1, 1, false),
+        ];
+        match check_inputs(&link_all(unsigned(&coins, &[5]), &coins)) {
+            Err(ValidationError::InputValueOverflow) => (),
+            r => panic!("expected InputValueOverflow, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn invalid_script() {
+        let mut coin = anyone_can_spend(0, 10, false);
+        coin.data.script = Script::from(vec![OP::False]);
+        match check_inputs(&link_all(unsigned(&[coin.clone()], &[5]), &[coin])) {
+            Err(ValidationError::InvalidScript(0)) => (),
+            r => panic!("expected InvalidScript, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn signature_over_signature_hash_is_valid() {
+        let coin = coin(50);
+        let tx = signed(&coin);
+        match check_inputs(&link_all(tx, &[coin])) {
+            Ok(10) => (),
+            r => panic!("expected a fee of 10, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn signature_over_shash_is_invalid() {
+        let coin = coin(50);
+        let mut tx = signed(&coin);
+        assert_ne!(tx.shash(0, 50), signature_hash(&tx, 0, 50));
+
+        let secp = Secp256k1::new();
+        let msg = Message::from_slice(&tx.shash(0, 50)).unwrap();
+        let signature = secp.sign(&msg, &key()).serialize_compact();
+        let public_key = PublicKey::from_secret_key(&secp, &key()).serialize();
+        let mut script = vec![OP::Push(signature.len() as u8)];
+        script.extend(signature.iter().copied().map(OP::Byte));
+        script.push(OP::Push(public_key.len() as u8));
+        script.extend(public_key.iter().copied().map(OP::Byte));
+        tx.inputs[0].script = Script::from(script);
+
+        match check_inputs(&link_all(tx, &[coin])) {
+            Err(ValidationError::InvalidScript(0)) => (),
+            r => panic!("signature over shash should not verify, got {:?}", r),
+        }
+    }
+}
@@ -1,14 +1,57 @@
-pub const MAGIC: u32 = 422_021;
-pub const VERSION: u32 = 1;
+mod params;
+
+pub use params::{Network, NetworkParams};
 
-pub const DEFAULT_PORT: &str = "4224";
+pub const VERSION: u32 = 1;
 
 pub const IP: &str = "127.0.0.1";
 pub const IP_BYTES: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 127, 0, 0, 1];
 
-pub const DEFAULT_MAX_CONN: &str = "42";
 pub const IMPLEMENTATION: &str = "another-rust-coin";
-pub const DEFAULT_GRPC_PORT: &str = "4225";
-pub const TIME_BEETWEEN_BLOCKS: u64 = 302_400;
+
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+pub const MAX_MEMPOOL_BYTES: usize = 50_000_000;
+pub const MAX_ORPHAN_BYTES: usize = 5_000_000;
+pub const MAX_ORPHANS_PER_PEER: usize = 100;
+pub const ORPHAN_EXPIRY: u64 = 1_200;
+pub const MEMPOOL_FILE: &str = "mempool.dat";
+pub const MEMPOOL_SAVE_TICKS: u64 = 60;
+pub const MEMPOOL_LISTENER_CAPACITY: usize = 256;
+/// Fee rate, in thousandths of a unit per byte, added to the one of an evicted package to get
+/// the minimum fee rate of the full mempool, and paid by a replacement on top of the fees of
+/// the txs it evicts
+pub const INCREMENTAL_FEE_RATE: u64 = 1_000;
+/// Seconds for the minimum fee rate of the mempool to halve
+pub const MEMPOOL_MIN_FEE_HALF_LIFE: u64 = 43_200;
+pub const REINDEX_LOG_INTERVAL: usize = 1_000;
+pub const MIN_BLOCKS_TO_KEEP: u32 = 288;
+pub const TXINDEX_BATCH: usize = 100;
+pub const FULL_NODE_SERVICE: &str = "node";
+pub const MAX_HEADERS: usize = 2_000;
+pub const MAX_BLOCKS_IN_FLIGHT: usize = 128;
+pub const MAX_BLOCKS_PER_PEER: usize = 16;
+pub const BLOCK_DOWNLOAD_TIMEOUT: u64 = 60;
+pub const TICK_INTERVAL: u64 = 10;
+/// Seconds after which a miner whose block did not become the best block mines again
+pub const MINER_RETRY_INTERVAL: u64 = 10;
+pub const TRICKLE_INTERVAL: u64 = 2;
+pub const MAX_INV_PER_TRICKLE: usize = 1_000;
+pub const MAX_INV_SIZE: usize = 50_000;
+pub const MAX_KNOWN_INVENTORY: usize = 50_000;
+pub const MAX_TX_REQUEST_ATTEMPTS: u32 = 3;
+pub const TX_REQUEST_TIMEOUT: u64 = 60;
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 7_200;
+pub const MEDIAN_TIME_SPAN: usize = 11;
+pub const MIN_CLOCK_SAMPLES: usize = 5;
+pub const MAX_CLOCK_OFFSET: i64 = 4_200;
 
 pub const FORGET_TIME: u64 = 604_800;
+pub const ADDR_BUCKET_SIZE: usize = 64;
+pub const ADDR_RETRY_DELAY: u64 = 600;
+pub const ATTEMPT_HALF_LIFE: u64 = 3_600;
+pub const MAX_NEW_ATTEMPTS: u8 = 3;
+pub const MAX_TRIED_ATTEMPTS: u8 = 10;
+pub const BAN_THRESHOLD: u32 = 100;
+/// Seconds for a misbehavior score to lose a point
+pub const SCORE_DECAY_INTERVAL: u64 = 36;
+pub const BAN_DURATION: u64 = 86_400;
@@ -0,0 +1,163 @@
+use ensicoin_messages::resource::{Block, BlockHeader};
+use ensicoin_serializer::Sha256Result;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Network::Mainnet
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower: &str = &s.to_ascii_lowercase();
+        match lower {
+            "mainnet" => Ok(Self::Mainnet),
+            "testnet" => Ok(Self::Testnet),
+            "regtest" => Ok(Self::Regtest),
+            s => Err(format!("Unknown network: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Network::Mainnet => "mainnet",
+                Network::Testnet => "testnet",
+                Network::Regtest => "regtest",
+            }
+        )
+    }
+}
+
+impl Network {
+    pub fn params(self) -> &'static NetworkParams {
+        match self {
+            Network::Mainnet => &MAINNET,
+            Network::Testnet => &TESTNET,
+            Network::Regtest => &REGTEST,
+        }
+    }
+}
+
+/// Consensus and networking parameters of a chain
+pub struct NetworkParams {
+    pub magic: u32,
+    pub port: u16,
+    pub grpc_port: u16,
+    pub listener_port: u16,
+    genesis_flag: &'static str,
+    genesis_timestamp: u64,
+    genesis_nonce: u64,
+    pub initial_target: [u8; 32],
+    /// Number of blocks the difficulty adjustment looks back
+    pub retarget_interval: u32,
+    /// The target never changes from the initial one
+    pub no_retargeting: bool,
+    /// Expected duration of a retarget interval in seconds
+    pub retarget_timespan: u64,
+    pub initial_subsidy: u64,
+    pub halving_interval: u32,
+    /// Number of blocks before a coinbase output can be spent
+    pub coinbase_maturity: u32,
+}
+
+pub const MAINNET: NetworkParams = NetworkParams {
+    magic: 422_021,
+    port: 4224,
+    grpc_port: 4225,
+    listener_port: 4226,
+    genesis_flag: "ici cest limag",
+    genesis_timestamp: 1_558_540_052,
+    genesis_nonce: 42,
+    initial_target: [
+        0, 0, 15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0,
+    ],
+    retarget_interval: 2016,
+    no_retargeting: false,
+    retarget_timespan: 302_400,
+    initial_subsidy: 5_000_000_000,
+    halving_interval: 210_000,
+    coinbase_maturity: 100,
+};
+
+pub const TESTNET: NetworkParams = NetworkParams {
+    magic: 422_022,
+    port: 14224,
+    grpc_port: 14225,
+    listener_port: 14226,
+    genesis_flag: "ici cest le test",
+    genesis_timestamp: 1_571_270_400,
+    genesis_nonce: 42,
+    initial_target: [
+        0, 15, 255, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0,
+    ],
+    retarget_interval: 2016,
+    no_retargeting: false,
+    retarget_timespan: 302_400,
+    initial_subsidy: 5_000_000_000,
+    halving_interval: 210_000,
+    coinbase_maturity: 100,
+};
+
+pub const REGTEST: NetworkParams = NetworkParams {
+    magic: 422_023,
+    port: 24224,
+    grpc_port: 24225,
+    listener_port: 24226,
+    genesis_flag: "ici cest le regtest",
+    genesis_timestamp: 1_571_270_400,
+    genesis_nonce: 0,
+    initial_target: [
+        127, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+        255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+    ],
+    retarget_interval: 150,
+    no_retargeting: true,
+    retarget_timespan: 90_000,
+    initial_subsidy: 5_000_000_000,
+    halving_interval: 150,
+    coinbase_maturity: 100,
+};
+
+impl NetworkParams {
+    pub fn genesis(&self) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 0,
+                flags: vec![self.genesis_flag.to_string()],
+                prev_block: Sha256Result::from([0; 32]),
+                merkle_root: Sha256Result::from([0; 32]),
+                timestamp: self.genesis_timestamp,
+                nonce: self.genesis_nonce,
+                height: 0,
+                target: Sha256Result::from(self.initial_target),
+            },
+            txs: Vec::new(),
+        }
+    }
+
+    pub fn block_subsidy(&self, height: u32) -> u64 {
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            0
+        } else {
+            self.initial_subsidy >> halvings
+        }
+    }
+}
@@ -21,11 +21,15 @@ impl From<tokio::io::Error> for MessageCodecError {
 
 pub struct MessageCodec {
     header: Option<MessageHeader>,
+    magic: u32,
 }
 
 impl MessageCodec {
-    pub fn new() -> MessageCodec {
-        MessageCodec { header: None }
+    pub fn new(magic: u32) -> MessageCodec {
+        MessageCodec {
+            header: None,
+            magic,
+        }
     }
 }
 
@@ -37,7 +41,7 @@ impl Decoder for MessageCodec {
         if self.header.is_none() && buf.len() >= 24 {
             trace!("Reading header");
             let header = buf.split_to(24);
-            let header = MessageHeader::from_bytes(crate::constants::MAGIC, header)?;
+            let header = MessageHeader::from_bytes(self.magic, header)?;
 
             trace!(
                 "message: {} of size {} to read",
@@ -66,9 +70,8 @@ impl Encoder for MessageCodec {
     type Error = MessageCodecError;
 
     fn encode(&mut self, message: Message, buf: &mut BytesMut) -> Result<(), Self::Error> {
-        let mut vec =
-            cookie_factory::gen_simple(fn_message(&message, crate::constants::MAGIC), Vec::new())
-                .expect("writing message to bytes");
+        let mut vec = cookie_factory::gen_simple(fn_message(&message, self.magic), Vec::new())
+            .expect("writing message to bytes");
         buf.extend_from_slice(&mut vec);
         Ok(())
     }
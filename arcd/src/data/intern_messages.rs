@@ -3,7 +3,7 @@ use bytes::Bytes;
 use cookie_factory::{bytes::be_u16, combinator::slice, sequence::tuple, SerializeFn};
 use ensicoin_messages::{
     message::{Address, GetBlocks, InvVect, Message},
-    resource::{Block, Transaction},
+    resource::{Block, BlockHeader, Transaction},
 };
 use ensicoin_serializer::{Deserialize, Deserializer};
 use std::io::Write;
@@ -17,6 +17,16 @@ pub enum Source {
     RPC,
 }
 
+impl Source {
+    /// Id of the connection the message comes from
+    pub fn connection_id(&self) -> Option<u64> {
+        match self {
+            Source::Connection(remote) => Some(remote.id),
+            _ => None,
+        }
+    }
+}
+
 impl std::fmt::Display for Source {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -35,7 +45,32 @@ impl std::fmt::Display for Source {
 #[derive(Clone, Debug, Eq, PartialEq, Default, Hash)]
 pub struct RemoteIdentity {
     pub id: u64,
+    /// Address the remote listens on
     pub peer: Peer,
+    /// Address of the socket the remote is connected from
+    pub socket: Peer,
+    /// The remote opened the connection
+    pub inbound: bool,
+}
+
+/// Offenses of a remote, the penalties add up until the remote is banned
+#[derive(Clone, Copy, Debug)]
+pub enum Misbehavior {
+    InvalidBlock,
+    InvalidHeaders,
+    MalformedMessage,
+    UnexpectedMessage,
+}
+
+impl Misbehavior {
+    pub fn penalty(self) -> u32 {
+        match self {
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::InvalidHeaders => 50,
+            Misbehavior::UnexpectedMessage => 20,
+            Misbehavior::MalformedMessage => 10,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Default, Hash, Copy)]
@@ -44,6 +79,17 @@ pub struct Peer {
     pub port: u16,
 }
 
+impl Peer {
+    /// Network group of the ip, the /16 for IPv4 and the /32 for IPv6
+    pub fn netgroup(&self) -> Vec<u8> {
+        if self.ip[..12] == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255] {
+            vec![4, self.ip[12], self.ip[13]]
+        } else {
+            vec![6, self.ip[0], self.ip[1], self.ip[2], self.ip[3]]
+        }
+    }
+}
+
 impl From<std::net::SocketAddr> for Peer {
     fn from(socket: std::net::SocketAddr) -> Self {
         let port = socket.port();
@@ -92,16 +138,24 @@ pub enum ConnectionMessageContent {
     Clean(u64),
     CheckInv(Vec<InvVect>),
     Retrieve(Vec<InvVect>),
+    NotFound(Vec<InvVect>),
     SyncBlocks(GetBlocks),
+    SyncHeaders(GetBlocks),
+    NewHeaders(Vec<BlockHeader>),
     NewTransaction(Box<Transaction>),
     NewBlock(Box<Block>),
     Connect(std::net::SocketAddr),
     NewConnection(tokio::net::TcpStream),
-    Register(mpsc::Sender<ServerMessage>, RemoteIdentity),
+    Register(mpsc::Sender<ServerMessage>, RemoteIdentity, i64),
     RetrieveAddr,
+    RetrieveMempool,
     ConnectionFailed(std::net::SocketAddr),
     NewAddr(Vec<Address>),
     VerifiedAddr(Address),
+    Misbehavior(Misbehavior),
+    Latency(std::time::Duration),
+    Tick,
+    Trickle,
     Quit,
 }
 
@@ -127,17 +181,25 @@ impl std::fmt::Display for ConnectionMessageContent {
                 ConnectionMessageContent::Disconnect(_, _) => "Disconnect",
                 ConnectionMessageContent::CheckInv(_) => "CheckInv",
                 ConnectionMessageContent::Retrieve(_) => "Retrieve",
+                ConnectionMessageContent::NotFound(_) => "NotFound",
                 ConnectionMessageContent::SyncBlocks(_) => "SyncBlocks",
+                ConnectionMessageContent::SyncHeaders(_) => "SyncHeaders",
+                ConnectionMessageContent::NewHeaders(_) => "NewHeaders",
                 ConnectionMessageContent::NewTransaction(_) => "NewTx",
                 ConnectionMessageContent::Connect(_) => "Connect",
                 ConnectionMessageContent::NewConnection(_) => "NewConnection",
-                ConnectionMessageContent::Register(_, _) => "Register",
+                ConnectionMessageContent::Register(_, _, _) => "Register",
                 ConnectionMessageContent::NewBlock(_) => "NewBlock",
                 ConnectionMessageContent::Clean(_) => "Clean",
                 ConnectionMessageContent::RetrieveAddr => "RetrieveAddr",
+                ConnectionMessageContent::RetrieveMempool => "RetrieveMempool",
                 ConnectionMessageContent::NewAddr(_) => "NewAddr",
                 ConnectionMessageContent::VerifiedAddr(_) => "VerifiedAddr",
                 ConnectionMessageContent::ConnectionFailed(_) => "ConnectionFailed",
+                ConnectionMessageContent::Misbehavior(_) => "Misbehavior",
+                ConnectionMessageContent::Latency(_) => "Latency",
+                ConnectionMessageContent::Tick => "Tick",
+                ConnectionMessageContent::Trickle => "Trickle",
                 ConnectionMessageContent::Quit => "Quit",
             }
         )
@@ -1,6 +1,9 @@
-use crate::data::{linkedtx::LinkedTransaction, PairedUtxo};
+use crate::{
+    consensus::{self, BlockContext, ValidationError},
+    data::{linkedtx::LinkedTransaction, PairedUtxo},
+};
 use ensicoin_messages::resource::{Block, BlockHeader};
-use ensicoin_serializer::{hash_to_string, Sha256Result};
+use ensicoin_serializer::{serializer::fn_varuint, Sha256Result, VarUint};
 use sha2::{Digest, Sha256};
 
 #[derive(Clone)]
@@ -22,6 +25,12 @@ fn double_hash(hash_a: Sha256Result, hash_b: Sha256Result) -> Sha256Result {
     hasher.result()
 }
 
+/// Expected number of hashes to find a block with the header target
+pub fn header_work(header: &BlockHeader) -> num_bigint::BigUint {
+    num_bigint::BigUint::from_bytes_be(&[0xff; 32])
+        - num_bigint::BigUint::from_bytes_be(&header.target)
+}
+
 impl LinkedBlock {
     pub fn merkle_root(&self) -> Sha256Result {
         let mut resources: Vec<_> = self
@@ -77,8 +86,7 @@ impl LinkedBlock {
     }
 
     pub fn work(&self) -> num_bigint::BigUint {
-        num_bigint::BigUint::from_bytes_be(&[0xff; 32])
-            - num_bigint::BigUint::from_bytes_be(&self.header.target)
+        header_work(&self.header)
     }
 
     pub fn into_block(self) -> Block {
@@ -89,58 +97,21 @@ impl LinkedBlock {
             txs: txs.into_iter().map(|txs| txs.transaction).collect(),
         }
     }
-    pub fn is_valid(&self, target: num_bigint::BigUint, previous_height: u32) -> bool {
-        if self.header.height != previous_height + 1 {
-            warn!(
-                "Invalid height: expected {} got {}",
-                previous_height + 1,
-                self.header.height
-            );
-            return false;
-        };
-        if num_bigint::BigUint::from_bytes_be(&self.header.target) != target {
-            warn!(
-                "Invalid target, expected: {} got {}",
-                target,
-                num_bigint::BigUint::from_bytes_be(&self.header.target),
-            );
-            return false;
-        };
-        if self.header.merkle_root != self.merkle_root() {
-            warn!(
-                "Invalid merkle root, expected {} got {}",
-                hash_to_string(&self.merkle_root()),
-                hash_to_string(&self.header.merkle_root),
-            );
-            return false;
-        }
-        if self.txs[0].transaction.flags.is_empty() {
-            warn!("Coinbase has no flags");
-            return false;
-        };
-        let coinbase_height: u32 = match self.txs[0].transaction.flags[0].parse() {
-            Ok(n) => n,
-            Err(_) => {
-                warn!("Coinbase first flag is not the height");
-                return false;
-            }
-        };
-        if coinbase_height != self.header.height {
-            return false;
-        };
-        for tx in &self.txs[1..] {
-            if !tx.is_complete() {
-                warn!("Tx is not complete");
-                return false;
-            }
-            match tx.is_valid() {
-                Ok(true) => (),
-                _ => {
-                    warn!("Invalid tx");
-                    return false;
-                }
-            }
-        }
-        true
+
+    pub fn size(&self) -> usize {
+        self.header.serialize().len()
+            + ensicoin_messages::as_bytes(fn_varuint(VarUint {
+                value: self.txs.len() as u64,
+            }))
+            .len()
+            + self
+                .txs
+                .iter()
+                .map(|ltx| ltx.transaction.serialize().len())
+                .sum::<usize>()
+    }
+
+    pub fn is_valid(&self, context: &BlockContext) -> Result<(), ValidationError> {
+        consensus::check_block(self, context)
     }
 }
@@ -1,10 +1,9 @@
-use crate::data::{validation::SanityCheck, PairedUtxo, UtxoData};
-use ensicoin_messages::resource::{Outpoint, Transaction, tx::{fn_outpoint, fn_tx_input, fn_tx_output}, script::fn_script};
+use crate::{
+    consensus::{self, ValidationError},
+    data::{PairedUtxo, UtxoData},
+};
+use ensicoin_messages::resource::{Outpoint, Transaction};
 use std::collections::{HashMap, HashSet};
-use cookie_factory::{bytes::{be_u32, be_u64}};
-use ensicoin_serializer::{serializer::{fn_str, fn_list}};
-
-use sha2::Digest;
 
 #[derive(PartialEq, Eq, Clone)]
 pub struct Dependency {
@@ -54,6 +53,7 @@ impl LinkedTransaction {
         for input in self.transaction.inputs.iter() {
             self.unknown_parent.insert(input.previous_output.clone());
         }
+        self.input_count = self.unknown_parent.len();
     }
 
     pub fn unknown(&self) -> &HashSet<Outpoint> {
@@ -85,57 +85,27 @@ impl LinkedTransaction {
         self.dep_count == self.input_count
     }
 
-    pub fn is_valid(&self) -> Result<bool, ()> {
-        if !self.transaction.sanity_check() {
-            return Ok(false);
-        };
-
-        let mut hasher_outpoints = sha2::Sha256::default();
-        for input in &self.transaction.inputs {
-            hasher_outpoints.input(ensicoin_messages::as_bytes(fn_outpoint(&input.previous_output)));
-        }
-        let simple_outpoint_hash = hasher_outpoints.result();
-
-        let mut hasher_outpoints = sha2::Sha256::default();
-        hasher_outpoints.input(simple_outpoint_hash);
-        let hash_outpoints = hasher_outpoints.result();
-
-        let mut hasher_outputs = sha2::Sha256::default();
-        for output in &self.transaction.outputs {
-            hasher_outputs.input(ensicoin_messages::as_bytes(fn_tx_output(output)));
+    pub fn fee(&self) -> Option<u64> {
+        if !self.is_complete() {
+            return None;
         }
-        let hash_outputs = hasher_outputs.result();
-
-        for input in &self.transaction.inputs {
-            let mut script = input.script.clone();
-            script.concat(match self.dependencies.get(&input.previous_output) {
-                Some(dep) => dep.data.script.clone(),
-                _ => return Err(()),
-            });
-            let mut hasher = sha2::Sha256::default();
-            hasher.input(ensicoin_messages::as_bytes(be_u32(self.transaction.version)));
-            hasher.input(ensicoin_messages::as_bytes(fn_list(self.transaction.flags.len() as u64, self.transaction.flags.iter().map(fn_str))));
-            hasher.input(&hash_outpoints);
-            hasher.input(ensicoin_messages::as_bytes(fn_outpoint(&input.previous_output)));
-            hasher.input(match self.dependencies.get(&input.previous_output) {
-                Some(dep) => ensicoin_messages::as_bytes(be_u64(dep.data.value)),
-                _ => return Err(()),
-            });
-            hasher.input(&hash_outputs);
+        let input_sum = self
+            .dependencies
+            .values()
+            .try_fold(0u64, |acc, dep| acc.checked_add(dep.data.value))?;
+        let output_sum = self
+            .transaction
+            .outputs
+            .iter()
+            .try_fold(0u64, |acc, output| acc.checked_add(output.value))?;
+        input_sum.checked_sub(output_sum)
+    }
 
-            if !crate::data::script_vm::execute_script(script, hasher.result()) {
-                return Ok(false);
-            }
-        }
+    pub fn dependency(&self, outpoint: &Outpoint) -> Option<&Dependency> {
+        self.dependencies.get(outpoint)
+    }
 
-        let mut output_sum = 0;
-        for output in &self.transaction.outputs {
-            output_sum += output.value;
-        }
-        let mut input_sum = 0;
-        for input in self.dependencies.values() {
-            input_sum += input.data.value
-        }
-        Ok(input_sum < output_sum)
+    pub fn is_valid(&self) -> Result<(), ValidationError> {
+        consensus::check_transaction(self)
     }
 }
@@ -4,7 +4,6 @@ pub mod linkedblock;
 pub mod linkedtx;
 pub mod script_vm;
 mod utxo;
-pub mod validation;
 
 pub use codec::{MessageCodec, MessageCodecError};
-pub use utxo::{PairedUtxo, UtxoData, ser_utxo_data, ser_paired_utxo};
+pub use utxo::{ser_paired_utxo, ser_utxo_data, PairedUtxo, UtxoData};
@@ -35,7 +35,7 @@ pub fn execute_script(code: Script, shash: Sha256Result) -> bool {
                 if i + (n as usize) >= code.len() {
                     return false;
                 };
-                for byte in &code[i..i + (n as usize)] {
+                for byte in &code[i + 1..=i + (n as usize)] {
                     match byte {
                         OP::Byte(b) => val.push(b.clone()),
                         _ => return false,
@@ -88,9 +88,60 @@ pub fn execute_script(code: Script, shash: Sha256Result) -> bool {
                 }
             }
         }
+        i += 1;
     }
     match stack.pop() {
         Some(n) => n.len() == 1 && n[0] == 1,
         _ => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::execute_script;
+    use crate::wallet::{p2pkh_script, pubkey_hash};
+    use ensicoin_messages::resource::script::{Script, OP};
+    use ensicoin_serializer::Sha256Result;
+    use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+    fn push(bytes: &[u8]) -> Vec<OP> {
+        let mut ops = vec![OP::Push(bytes.len() as u8)];
+        ops.extend(bytes.iter().copied().map(OP::Byte));
+        ops
+    }
+
+    /// The operands of a Push used to be read starting at the Push opcode itself, so every
+    /// script pushing data, P2PKH spends included, was rejected
+    #[test]
+    fn p2pkh_spend_is_valid() {
+        let secp = Secp256k1::new();
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let public_key = PublicKey::from_secret_key(&secp, &key);
+        let shash = Sha256Result::from([3; 32]);
+        let signature = secp
+            .sign(&Message::from_slice(&shash).unwrap(), &key)
+            .serialize_compact();
+        let mut ops = push(&signature);
+        ops.append(&mut push(&public_key.serialize()));
+        let mut script = Script::from(ops);
+        script.concat(p2pkh_script(&pubkey_hash(&public_key)));
+        assert!(execute_script(script, shash));
+    }
+
+    #[test]
+    fn push_operands_follow_the_opcode() {
+        let mut ops = push(&[1]);
+        ops.append(&mut push(&[1]));
+        ops.push(OP::Equal);
+        assert!(execute_script(
+            Script::from(ops),
+            Sha256Result::from([0; 32])
+        ));
+    }
+
+    #[test]
+    fn truncated_push_is_invalid() {
+        let script = Script::from(vec![OP::Push(2), OP::Byte(1)]);
+        assert!(!execute_script(script, Sha256Result::from([0; 32])));
+    }
+}
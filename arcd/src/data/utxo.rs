@@ -12,13 +12,15 @@ use cookie_factory::{
 };
 use std::io::Write;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct PairedUtxo {
     pub data: UtxoData,
     pub outpoint: Outpoint,
 }
 
-pub fn ser_paired_utxo<'c, 'a: 'c, W: Write + 'c>(paired: &'a PairedUtxo) -> impl SerializeFn<W> + 'c {
+pub fn ser_paired_utxo<'c, 'a: 'c, W: Write + 'c>(
+    paired: &'a PairedUtxo,
+) -> impl SerializeFn<W> + 'c {
     tuple((ser_utxo_data(&paired.data), fn_outpoint(&paired.outpoint)))
 }
 
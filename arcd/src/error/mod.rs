@@ -1,4 +1,4 @@
-use crate::constants::MAGIC;
+use crate::{consensus::ValidationError, wallet::WalletError};
 
 #[derive(Debug)]
 pub enum Error {
@@ -12,8 +12,13 @@ pub enum Error {
     TimerError(tokio::timer::Error),
     StreamError,
     DatabaseError(sled::Error),
-    InvalidBlock,
+    TransactionAborted,
+    WalletError(WalletError),
+    InvalidBlock(ValidationError),
     NotFound(String),
+    Pruned(String),
+    LegacyDataDir,
+    CorruptChainState(String),
     SignalError,
     Quit,
 }
@@ -29,18 +34,31 @@ impl std::fmt::Display for Error {
                 write!(f, "Connection is in invalid state: {}", st)
             }
             Error::IoError(e) => write!(f, "IoError: {}", e),
-            Error::InvalidMagic(n) => write!(f, "Invalid magic, got {} expected {}", n, MAGIC),
+            Error::InvalidMagic(n) => write!(f, "Invalid magic: {}", n),
             Error::ChannelError => write!(f, "Server channel failed"),
             Error::ServerTermination => write!(f, "Server terminated the connection"),
             Error::NotFound(r) => write!(f, "Resource not found: {}", r),
+            Error::Pruned(r) => write!(f, "Resource pruned: {}", r),
+            Error::LegacyDataDir => write!(
+                f,
+                "The data directory uses the old database layout and could not be migrated, run \
+                 with --clean to start over"
+            ),
+            Error::CorruptChainState(r) => {
+                write!(f, "Chain state is corrupted, run with --reindex: {}", r)
+            }
             Error::DatabaseError(e) => write!(f, "Database error: {}", e),
-            Error::InvalidBlock => write!(f, "Invalid Block"),
+            Error::TransactionAborted => write!(f, "Database transaction aborted"),
+            Error::WalletError(e) => write!(f, "Wallet error: {}", e),
+            Error::InvalidBlock(e) => write!(f, "Invalid Block: {}", e),
             Error::SignalError => write!(f, "Signal Error"),
             Error::Quit => write!(f, "Quit"),
         }
     }
 }
 
+impl std::error::Error for Error {}
+
 impl From<ensicoin_serializer::Error> for Error {
     fn from(error: ensicoin_serializer::Error) -> Self {
         Error::ParseError(error)
@@ -64,6 +82,28 @@ impl From<sled::Error> for Error {
         Error::DatabaseError(error)
     }
 }
+
+impl From<sled::TransactionError> for Error {
+    fn from(error: sled::TransactionError) -> Self {
+        match error {
+            sled::TransactionError::Storage(e) => Error::DatabaseError(e),
+            _ => Error::TransactionAborted,
+        }
+    }
+}
+
+impl From<WalletError> for Error {
+    fn from(error: WalletError) -> Self {
+        Error::WalletError(error)
+    }
+}
+
+impl From<ValidationError> for Error {
+    fn from(error: ValidationError) -> Self {
+        Error::InvalidBlock(error)
+    }
+}
+
 impl From<tokio::sync::mpsc::error::SendError> for Error {
     fn from(_: tokio::sync::mpsc::error::SendError) -> Self {
         Error::ChannelError
@@ -1,6 +1,7 @@
-#![type_length_limit="1546013"]
+#![type_length_limit = "1546013"]
 
 mod bootstrap;
+mod consensus;
 #[allow(dead_code)]
 mod constants;
 mod data;
@@ -8,6 +9,7 @@ mod error;
 mod manager;
 mod network;
 pub mod utils;
+mod wallet;
 pub use error::Error;
 
 use network::Server;
@@ -67,18 +69,45 @@ struct Config {
 #[cfg_attr(feature = "cli-config", derive(StructOpt))]
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct ServerConfig {
-    #[cfg_attr(
-        feature = "cli-config",
-        structopt(short = "c", long = "connections", default_value = "42")
-    )]
-    /// Sets the maximum number of connections
-    pub max_connections: u64,
+    #[cfg_attr(feature = "cli-config", structopt(long, default_value = "32"))]
+    #[serde(default = "default_max_inbound")]
+    /// Sets the maximum number of connections opened by remotes
+    pub max_inbound: u64,
+    #[cfg_attr(feature = "cli-config", structopt(long, default_value = "8"))]
+    #[serde(default = "default_max_outbound")]
+    /// Sets the maximum number of connections opened to remotes
+    pub max_outbound: u64,
+    #[cfg_attr(feature = "cli-config", structopt(short = "c", long = "connections"))]
+    #[serde(default, skip_serializing, deserialize_with = "optional_setting")]
+    /// Deprecated, sets the maximum number of connections split between inbound and outbound
+    pub max_connections: Option<u64>,
+    #[cfg_attr(feature = "cli-config", structopt(long, default_value = "mainnet"))]
+    #[serde(default)]
+    /// Chain to follow (can be "mainnet", "testnet", "regtest")
+    pub network: constants::Network,
     #[cfg_attr(feature = "cli-config", structopt(long))]
     /// Changes the default directory
     pub data_dir: Option<std::path::PathBuf>,
-    #[cfg_attr(feature = "cli-config", structopt(short, long, default_value = "4224"))]
-    /// Port listening for connections
-    pub port: u16,
+    #[cfg_attr(feature = "cli-config", structopt(long))]
+    #[serde(skip)]
+    /// Rebuilds the chain state by validating the stored blocks again
+    pub reindex: bool,
+    #[cfg_attr(feature = "cli-config", structopt(long))]
+    #[serde(default)]
+    /// Drops old blocks to keep the stored blocks under the given size in MiB
+    pub prune: Option<u64>,
+    #[cfg_attr(feature = "cli-config", structopt(long))]
+    #[serde(default)]
+    /// Indexes confirmed transactions so that they can be looked up by hash
+    pub txindex: bool,
+    #[cfg_attr(feature = "cli-config", structopt(long))]
+    #[serde(default)]
+    /// Indexes the history and the unspent outputs of every script
+    pub scriptindex: bool,
+    #[cfg_attr(feature = "cli-config", structopt(short, long))]
+    #[serde(default, deserialize_with = "optional_setting")]
+    /// Port listening for connections, defaults to the one of the network
+    pub port: Option<u16>,
     #[cfg(feature = "service_discover")]
     #[cfg_attr(feature = "cli-config", structopt(long))]
     /// URL of the service discovery service
@@ -88,13 +117,101 @@ pub struct ServerConfig {
     /// RON credentials for matrix
     pub matrix_creds: Option<std::path::PathBuf>,
     #[cfg(feature = "grpc")]
-    #[cfg_attr(feature = "cli-config", structopt(long, short, default_value = "4225"))]
-    /// Port listening for gRPC requests
-    pub grpc_port: u16,
+    #[cfg_attr(feature = "cli-config", structopt(long, short))]
+    #[serde(default, deserialize_with = "optional_setting")]
+    /// Port listening for gRPC requests, defaults to the one of the network
+    pub grpc_port: Option<u16>,
+    #[cfg(feature = "grpc")]
+    #[cfg_attr(feature = "cli-config", structopt(long))]
+    #[serde(default, deserialize_with = "optional_setting")]
+    /// Port of the gRPC listener service, defaults to the one of the network
+    pub listener_port: Option<u16>,
     #[cfg(feature = "grpc")]
     #[cfg_attr(feature = "cli-config", structopt(long))]
     /// Restrict gRPC requests to localhost
     pub grpc_localhost: bool,
+    #[cfg(feature = "miner")]
+    #[cfg_attr(feature = "cli-config", structopt(long))]
+    /// Mines blocks to a new wallet address with the given number of threads
+    pub mine: Option<u64>,
+}
+
+impl ServerConfig {
+    /// Replaces the deprecated connection limit by inbound and outbound limits
+    fn split_connections(&mut self) {
+        if let Some(max) = self.max_connections.take() {
+            warn!("--connections is deprecated, use --max-inbound and --max-outbound");
+            self.max_outbound = std::cmp::min(max, default_max_outbound());
+            self.max_inbound = max - self.max_outbound;
+        }
+    }
+}
+
+fn default_max_inbound() -> u64 {
+    32
+}
+
+fn default_max_outbound() -> u64 {
+    8
+}
+
+/// Reads an optional number saved either as a number, like the settings written before the
+/// field became optional, or as an option
+fn optional_setting<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::convert::TryFrom<u64> + serde::Deserialize<'de>,
+{
+    struct OptionalVisitor<T>(std::marker::PhantomData<T>);
+
+    impl<'de, T> serde::de::Visitor<'de> for OptionalVisitor<T>
+    where
+        T: std::convert::TryFrom<u64> + serde::Deserialize<'de>,
+    {
+        type Value = Option<T>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a number or an optional number")
+        }
+
+        fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+            match T::try_from(v) {
+                Ok(v) => Ok(Some(v)),
+                Err(_) => Err(E::invalid_value(serde::de::Unexpected::Unsigned(v), &self)),
+            }
+        }
+
+        fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+            if v < 0 {
+                return Err(E::invalid_value(serde::de::Unexpected::Signed(v), &self));
+            }
+            self.visit_u64(v as u64)
+        }
+
+        fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            serde::Deserialize::deserialize(deserializer).map(Some)
+        }
+    }
+
+    deserializer.deserialize_any(OptionalVisitor(std::marker::PhantomData))
+}
+
+/// Data directory of a network, the networks other than mainnet use sibling directories so
+/// that cleaning one never touches another
+fn default_data_dir(network: constants::Network) -> std::path::PathBuf {
+    let mut path = dirs::data_dir().unwrap();
+    match network {
+        constants::Network::Mainnet => path.push(constants::IMPLEMENTATION),
+        network => path.push(format!("{}-{}", constants::IMPLEMENTATION, network)),
+    }
+    path
 }
 
 #[tokio::main]
@@ -105,9 +222,7 @@ async fn main() {
         let mut config = Config::from_args();
 
         if config.server_config.data_dir.is_none() {
-            let mut path = dirs::data_dir().unwrap();
-            path.push(r"another-rust-coin");
-            config.server_config.data_dir = Some(path);
+            config.server_config.data_dir = Some(default_data_dir(config.server_config.network));
         };
         let data_dir = config.server_config.data_dir.clone().unwrap();
 
@@ -145,7 +260,7 @@ async fn main() {
             should_bootstrap = true;
         };
         if should_bootstrap {
-            if let Err(e) = bootstrap::bootstrap(&data_dir) {
+            if let Err(e) = bootstrap::bootstrap(&data_dir, config.server_config.network.params()) {
                 error!("Could not bootstrap: {}", e);
                 return;
             }
@@ -163,6 +278,7 @@ async fn main() {
                 return;
             }
         };
+        config.server_config.split_connections();
         if config.save {
             let mut pretty = ron::ser::PrettyConfig::default();
             pretty.depth_limit = 4;
@@ -179,18 +295,17 @@ async fn main() {
             }
         };
         if config.use_config {
+            let reindex = config.server_config.reindex;
             match ron::de::from_reader(settings_file) {
-                Ok(s) => config.server_config = s,
+                Ok(s) => config.server_config = ServerConfig { reindex, ..s },
                 Err(e) => warn!("Could not use config file: {}", e),
             }
         }
+        config.server_config.split_connections();
         config.server_config
     };
     #[cfg(not(feature = "cli-config"))]
     let server_config: ServerConfig = {
-        let mut data_dir = dirs::data_dir().unwrap();
-        data_dir.push(r"another-rust-coin");
-
         let log_level = simplelog::LevelFilter::Info;
         simplelog::TermLogger::init(
             log_level,
@@ -199,43 +314,44 @@ async fn main() {
         )
         .unwrap();
 
-        let mut should_bootstrap = match std::fs::create_dir_all(&data_dir) {
-            Err(e) => e.kind() == std::io::ErrorKind::AlreadyExists,
-            Ok(_) => false,
-        };
-
-        if let Some(s) = data_dir.to_str() {
-            info!("Using {} as data directory", s);
-        }
-
-        let mut settings_path = data_dir.clone();
+        let mut settings_path = default_data_dir(constants::Network::Mainnet);
         settings_path.push("settings.ron");
-
-        if !settings_path.exists() {
-            should_bootstrap = true;
-        }
-
-        if should_bootstrap {
-            if let Err(e) = bootstrap::bootstrap(&data_dir) {
-                error!("Could not bootstrap: {}", e);
-                return;
-            }
-        }
-
-        let mut settings_file = match std::fs::File::open(settings_path) {
+        let settings_file = match std::fs::File::open(settings_path) {
             Ok(f) => f,
             Err(e) => {
                 warn!("Settings file could not be opened: {}", e);
                 return;
             }
         };
-        match ron::de::from_reader(settings_file) {
+        let mut config: ServerConfig = match ron::de::from_reader(settings_file) {
             Ok(s) => s,
             Err(e) => {
                 error!("Could not use config file: {}", e);
                 return;
             }
+        };
+
+        let data_dir = match config.data_dir.clone() {
+            Some(d) => d,
+            None => default_data_dir(config.network),
+        };
+        if let Err(e) = std::fs::create_dir_all(&data_dir) {
+            error!("Could not create data directory: {}", e);
+            return;
+        }
+        if let Some(s) = data_dir.to_str() {
+            info!("Using {} as data directory", s);
+        }
+
+        if !data_dir.join("chainstate").exists() {
+            if let Err(e) = bootstrap::bootstrap(&data_dir, config.network.params()) {
+                error!("Could not bootstrap: {}", e);
+                return;
+            }
         }
+        config.data_dir = Some(data_dir);
+        config.split_connections();
+        config
     };
 
     if let Err(e) = Server::run(server_config).await {
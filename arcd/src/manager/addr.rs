@@ -1,11 +1,21 @@
-use crate::data::intern_messages::{Peer, Source, fn_peer};
+use super::BanList;
+use crate::constants::{
+    ADDR_BUCKET_SIZE, ADDR_RETRY_DELAY, ATTEMPT_HALF_LIFE, FORGET_TIME, MAX_NEW_ATTEMPTS,
+    MAX_TRIED_ATTEMPTS,
+};
+use crate::data::intern_messages::{fn_peer, Misbehavior, Peer, Source};
+use crate::utils::timestamp;
 use ensicoin_messages::message::Address;
-use ensicoin_serializer::{Deserialize};
-use rand::seq::IteratorRandom;
-use std::time::{SystemTime, UNIX_EPOCH};
+use ensicoin_serializer::Deserialize;
+use rand::{seq::SliceRandom, Rng};
+use std::collections::HashSet;
 
+use cookie_factory::{
+    bytes::{be_u64, be_u8},
+    sequence::tuple,
+    SerializeFn,
+};
 use std::io::Write;
-use cookie_factory::{SerializeFn, bytes::{be_u8, be_u64}, sequence::tuple};
 
 #[derive(Debug)]
 pub enum AddressManagerError {
@@ -38,32 +48,94 @@ impl From<sled::Error> for AddressManagerError {
     }
 }
 
-#[derive(Deserialize, Debug, Clone, Copy)]
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
 struct PeerData {
-    given: u8,
-    not_responded: u8,
-    pub timestamp: u64,
+    /// Last time the address was seen or announced
+    timestamp: u64,
+    last_success: u64,
+    last_attempt: u64,
+    /// Attempts since the last success
+    attempts: u8,
 }
 
 fn ser_peer_data<'c, W: Write + 'c>(data: PeerData) -> impl SerializeFn<W> {
     tuple((
-        be_u8(data.given),
-        be_u8(data.not_responded),
         be_u64(data.timestamp),
+        be_u64(data.last_success),
+        be_u64(data.last_attempt),
+        be_u8(data.attempts),
     ))
 }
 
+impl PeerData {
+    /// Attempts are halved for every half life elapsed since the last one
+    fn recent_attempts(&self, now: u64) -> u8 {
+        let half_lives = now.saturating_sub(self.last_attempt) / ATTEMPT_HALF_LIFE;
+        if half_lives >= 8 {
+            0
+        } else {
+            self.attempts >> half_lives
+        }
+    }
+
+    fn is_terrible(&self, now: u64) -> bool {
+        let seen = std::cmp::max(self.timestamp, self.last_success);
+        (seen + FORGET_TIME < now)
+            || (self.last_success == 0 && self.recent_attempts(now) >= MAX_NEW_ATTEMPTS)
+            || (self.last_success + FORGET_TIME < now
+                && self.recent_attempts(now) >= MAX_TRIED_ATTEMPTS)
+    }
+
+    /// Relative chance of the address to be selected
+    fn chance(&self, now: u64) -> f64 {
+        let mut chance = 1.0;
+        if now.saturating_sub(self.last_attempt) < ADDR_RETRY_DELAY {
+            chance *= 0.01;
+        }
+        chance * 0.66_f64.powi(std::cmp::min(self.recent_attempts(now), 8) as i32)
+    }
+}
+
+/// Keys are prefixed by the network group of the address so that a bucket is a prefix scan
+fn peer_key(peer: &Peer) -> Vec<u8> {
+    let mut key = peer.netgroup();
+    key.extend_from_slice(&ensicoin_messages::as_bytes(fn_peer(peer)));
+    key
+}
+
+fn key_peer(key: &[u8]) -> Result<Peer, AddressManagerError> {
+    let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
+        &key[key.len().saturating_sub(18)..],
+    ));
+    Ok(Peer::deserialize(&mut de)?)
+}
+
+fn parse_data(value: &[u8]) -> Result<PeerData, AddressManagerError> {
+    let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(value));
+    Ok(PeerData::deserialize(&mut de)?)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Table {
+    /// Addresses we heard of
+    New,
+    /// Addresses we successfully connected to
+    Tried,
+}
+
+/// Known addresses bucketed by network group (/16 for IPv4, /32 for IPv6) in a new and a
+/// tried table, no group can fill more than a bucket of each table
 pub struct AddressManager {
-    db: sled::Db,
-    pub no_response_limit: u8,
-    pub given_count: usize,
+    new: sled::Tree,
+    tried: sled::Tree,
+    bans: BanList,
 }
 
 impl AddressManager {
     #[cfg(feature = "matrix_discover")]
-    pub fn set_bots(&mut self, bots: Vec<String>) {
+    pub fn set_bots(&mut self, bots: Vec<String>, magic: u32) {
         for bot in bots {
-            let bot = bot.trim_start_matches(&format!("{}_", crate::constants::MAGIC));
+            let bot = bot.trim_start_matches(&format!("{}_", magic));
 
             let addr: std::net::SocketAddr = match bot.parse() {
                 Ok(addr) => addr,
@@ -72,273 +144,379 @@ impl AddressManager {
                     continue;
                 }
             };
-            let ip = match addr.ip() {
-                std::net::IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
-                std::net::IpAddr::V6(ip) => ip.octets(),
-            };
-
-            self.register_addr(Peer {
-                ip,
-                port: addr.port(),
-            })
+            self.add_peer(Peer::from(addr))
         }
     }
 
-    pub fn new(
-        data_dir: &std::path::Path,
-        no_response_limit: u8,
-    ) -> Result<Self, AddressManagerError> {
+    pub fn new(data_dir: &std::path::Path) -> Result<Self, AddressManagerError> {
         let mut db_dir = std::path::PathBuf::new();
         db_dir.push(data_dir);
         db_dir.push("adress_manager");
         let db = sled::Db::open(db_dir)?;
+        let bans = BanList::new(db.open_tree("bans")?);
 
-        Ok(AddressManager {
-            db,
-            no_response_limit,
-            given_count: 0,
-        })
+        let manager = AddressManager {
+            new: db.open_tree("new")?,
+            tried: db.open_tree("tried")?,
+            bans,
+        };
+        manager.import_legacy(&db)?;
+        Ok(manager)
     }
 
-    pub fn no_response(&mut self, peer_address: Peer) {
-        match self.get_peer(peer_address) {
-            Ok(Some(mut data)) => {
-                self.given_count -= 1;
-                data.not_responded += 1;
-                data.given = 0;
-                let resp = if data.not_responded >= self.no_response_limit {
-                    warn!(
-                        "Connection [{}] did not respond too many times",
-                        std::net::SocketAddr::from((peer_address.ip, peer_address.port))
-                    );
-                    self.db
-                        .remove(ensicoin_messages::as_bytes(fn_peer(&peer_address)))
-                        .map(|_| ())
-                        .map_err(AddressManagerError::from)
-                } else {
-                    self.set_peer(peer_address, data)
-                };
-                if let Err(e) = resp {
-                    warn!("Error in updating address info: {:?}", e)
-                }
+    /// Moves the addresses saved in the default tree before the new and tried tables existed to
+    /// the new table, only the entries that were imported are removed from the default tree
+    fn import_legacy(&self, db: &sled::Db) -> Result<(), AddressManagerError> {
+        let now = timestamp();
+        let mut imported = 0;
+        for res in db.iter() {
+            let (key, value) = res?;
+            // given and not_responded counters followed by the timestamp
+            if value.len() != 10 {
+                continue;
             }
-            Ok(None) => warn!("Unlisted address unresponded"),
-            Err(e) => warn!("Error in registering no_response: {:?}", e),
-        };
+            let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(&*key));
+            let peer = match Peer::deserialize(&mut de) {
+                Ok(peer) => peer,
+                Err(_) => continue,
+            };
+            let mut seen = [0; 8];
+            seen.copy_from_slice(&value[2..]);
+            let data = PeerData {
+                timestamp: std::cmp::min(u64::from_be_bytes(seen), now),
+                ..PeerData::default()
+            };
+            if data.is_terrible(now)
+                || std::net::IpAddr::from(peer.ip).is_unspecified()
+                || self.bans.is_banned(&peer.ip)
+                || self.get_peer(&peer)?.is_some()
+            {
+                continue;
+            }
+            self.insert_new(&peer, data)?;
+            db.remove(&key)?;
+            imported += 1;
+        }
+        if imported > 0 {
+            info!(
+                "Imported {} addresses from the previous address database",
+                imported
+            );
+        }
+        Ok(())
     }
 
-    fn get_peer(&self, peer_address: Peer) -> Result<Option<PeerData>, AddressManagerError> {
-        let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
-            match self.db.get(ensicoin_messages::as_bytes(fn_peer(&peer_address)))? {
-                Some(b) => (*b).to_owned(),
-                None => return Ok(None),
-            },
-        ));
-        PeerData::deserialize(&mut de)
-            .map(Some)
-            .map_err(AddressManagerError::ParseError)
+    /// Adds the penalty of the offense to the score of the ip, returns true if the ip got
+    /// banned
+    pub fn misbehaved(&mut self, ip: [u8; 16], offense: Misbehavior) -> bool {
+        if let Some(score) = self.bans.add_penalty(ip, offense.penalty()) {
+            debug!(
+                "{} misbehaved ({:?}), score is now {}",
+                std::net::IpAddr::from(ip),
+                offense,
+                score
+            );
+            return false;
+        }
+        warn!("Banning {}", std::net::IpAddr::from(ip));
+        if let Err(e) = self
+            .bans
+            .ban(ip, timestamp() + crate::constants::BAN_DURATION)
+        {
+            warn!("Could not persist ban: {}", e)
+        }
+        true
     }
 
-    fn set_peer(&self, peer: Peer, data: PeerData) -> Result<(), AddressManagerError> {
-        self.db
-            .insert(ensicoin_messages::as_bytes(fn_peer(&peer)), ensicoin_messages::as_bytes(ser_peer_data(data)))
-            .map(|_| ())
-            .map_err(AddressManagerError::DbError)
+    pub fn is_banned(&self, ip: &[u8; 16]) -> bool {
+        self.bans.is_banned(ip)
     }
 
-    pub fn get_addr(&self) -> Vec<Address> {
-        let mut addresses = Vec::new();
-        let now = SystemTime::now();
-        let since_epoch = now
-            .duration_since(UNIX_EPOCH)
-            .expect("Back in time are you ?");
-        for e in self.db.iter() {
-            match e {
-                Ok((k, v)) => {
-                    let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
-                        (*k).to_owned(),
-                    ));
-                    let peer = Peer::deserialize(&mut de);
-                    let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
-                        (*v).to_owned(),
-                    ));
-                    let data = PeerData::deserialize(&mut de);
-                    match (peer, data) {
-                        (Ok(peer), Ok(data)) => {
-                            if data.timestamp + crate::constants::FORGET_TIME
-                                > since_epoch.as_secs()
-                            {
-                                addresses.push(Address {
-                                    timestamp: data.timestamp,
-                                    ip: peer.ip,
-                                    port: peer.port,
-                                })
-                            } else if let Err(e) = self.db.remove(ensicoin_messages::as_bytes(fn_peer(&peer))) {
-                                warn!("Could not delete value in addr db: {}", e)
-                            }
-                        }
-                        _ => warn!("Error deserializing value in addr db"),
-                    }
-                }
-                Err(e) => warn!("Error reading addr from db: {}", e),
-            }
+    pub fn ban_list(&self) -> BanList {
+        self.bans.clone()
+    }
+
+    fn tree(&self, table: Table) -> &sled::Tree {
+        match table {
+            Table::New => &self.new,
+            Table::Tried => &self.tried,
         }
-        addresses
     }
 
-    fn retime_addr(&self, peer: Peer) {
-        let now = SystemTime::now();
-        let since_epoch = now
-            .duration_since(UNIX_EPOCH)
-            .expect("Back in time are you ?");
-        let data = match self.get_peer(peer) {
-            Ok(data) => data,
-            Err(e) => {
-                warn!("Error reading from db: {}", e);
-                return;
+    fn get_peer(&self, peer: &Peer) -> Result<Option<(Table, PeerData)>, AddressManagerError> {
+        let key = peer_key(peer);
+        for table in &[Table::Tried, Table::New] {
+            if let Some(value) = self.tree(*table).get(&key)? {
+                return Ok(Some((*table, parse_data(&value)?)));
             }
+        }
+        Ok(None)
+    }
+
+    fn set_peer(
+        &self,
+        table: Table,
+        peer: &Peer,
+        data: PeerData,
+    ) -> Result<(), AddressManagerError> {
+        self.tree(table).insert(
+            peer_key(peer),
+            ensicoin_messages::as_bytes(ser_peer_data(data)),
+        )?;
+        Ok(())
+    }
+
+    /// Frees a place in the bucket of the network group if it is full: the oldest address of
+    /// the new table is forgotten, the oldest success of the tried table goes back to the new
+    /// table
+    fn make_room(&self, table: Table, netgroup: &[u8]) -> Result<(), AddressManagerError> {
+        let age = |data: &PeerData| match table {
+            Table::New => data.timestamp,
+            Table::Tried => data.last_success,
         };
-        if let Some(mut data) = data {
-            data.timestamp = since_epoch.as_secs();
-            if let Err(e) = self.set_peer(peer, data) {
-                warn!("Error setting in addr db: {}", e)
+        let mut count = 0;
+        let mut oldest: Option<(sled::IVec, PeerData)> = None;
+        for res in self.tree(table).scan_prefix(netgroup) {
+            let (key, value) = res?;
+            let data = parse_data(&value)?;
+            count += 1;
+            if oldest.as_ref().map_or(true, |(_, o)| age(&data) < age(o)) {
+                oldest = Some((key, data));
+            }
+        }
+        if count < ADDR_BUCKET_SIZE {
+            return Ok(());
+        }
+        if let Some((key, data)) = oldest {
+            self.tree(table).remove(&key)?;
+            if table == Table::Tried {
+                self.make_room(Table::New, netgroup)?;
+                self.set_peer(Table::New, &key_peer(&key)?, data)?;
             }
         }
+        Ok(())
     }
 
+    fn insert_new(&self, peer: &Peer, data: PeerData) -> Result<(), AddressManagerError> {
+        self.make_room(Table::New, &peer.netgroup())?;
+        self.set_peer(Table::New, peer, data)
+    }
+
+    /// Records an address learned from a remote
     pub fn add_addr(&mut self, addr: Address) {
         let peer = Peer {
             ip: addr.ip,
             port: addr.port,
         };
-        match &self.get_peer(peer) {
-            Ok(Some(data)) if data.timestamp < addr.timestamp => (),
-            Err(_) => (),
-            _ => {
-                if let Err(e) = self.set_peer(
-                    peer,
-                    PeerData {
-                        given: 0,
-                        not_responded: 0,
-                        timestamp: addr.timestamp,
-                    },
-                ) {
-                    warn!("Error in addr db: {}", e)
+        if std::net::IpAddr::from(peer.ip).is_unspecified() || self.bans.is_banned(&peer.ip) {
+            return;
+        }
+        let seen = std::cmp::min(addr.timestamp, timestamp());
+        let res = match self.get_peer(&peer) {
+            Ok(Some((table, mut data))) => {
+                if data.timestamp < seen {
+                    data.timestamp = seen;
+                    self.set_peer(table, &peer, data)
+                } else {
+                    Ok(())
                 }
             }
+            Ok(None) => self.insert_new(
+                &peer,
+                PeerData {
+                    timestamp: seen,
+                    ..PeerData::default()
+                },
+            ),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            warn!("Error in addr db: {}", e)
         }
     }
 
-    pub fn register_addr(&mut self, peer: Peer, is_connected: bool) {
-        if !std::net::IpAddr::from(peer.ip).is_unspecified() {
-            let now = SystemTime::now();
-            let since_epoch = now
-                .duration_since(UNIX_EPOCH)
-                .expect("Back in time are you ?");
-            if let Err(e) = self.set_peer(
-                peer,
+    /// Records an address we were given directly
+    pub fn add_peer(&mut self, peer: Peer) {
+        self.add_addr(Address {
+            timestamp: timestamp(),
+            ip: peer.ip,
+            port: peer.port,
+        })
+    }
+
+    /// Records a connection attempt to the peer
+    pub fn attempt(&mut self, peer: Peer) {
+        let now = timestamp();
+        let res = match self.get_peer(&peer) {
+            Ok(Some((table, mut data))) => {
+                data.attempts = data.recent_attempts(now).saturating_add(1);
+                data.last_attempt = now;
+                self.set_peer(table, &peer, data)
+            }
+            Ok(None) => self.insert_new(
+                &peer,
                 PeerData {
-                    given: if is_connected { 1 } else { 0 },
-                    not_responded: 0,
-                    timestamp: since_epoch.as_secs(),
+                    timestamp: now,
+                    last_attempt: now,
+                    attempts: 1,
+                    ..PeerData::default()
                 },
-            ) {
-                warn!("Error registering peer: {}", e)
-            }
+            ),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            warn!("Error registering attempt: {}", e)
         }
     }
 
-    pub fn new_message(&mut self, source: &Source) {
-        if let Source::Connection(conn) = source {
-            self.retime_addr(conn.peer)
+    /// The peer could not be reached, it is forgotten if it failed too often
+    pub fn failed(&mut self, peer: Peer) {
+        let now = timestamp();
+        let res = match self.get_peer(&peer) {
+            Ok(Some((table, data))) if data.is_terrible(now) => {
+                warn!(
+                    "Connection [{}] did not respond too many times",
+                    std::net::SocketAddr::from((peer.ip, peer.port))
+                );
+                self.tree(table)
+                    .remove(peer_key(&peer))
+                    .map(|_| ())
+                    .map_err(AddressManagerError::from)
+            }
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            warn!("Error in registering failure: {:?}", e)
         }
     }
 
-    pub fn reset_state(&mut self) {
-        for res in self.db.iter() {
-            match res {
-                Err(e) => warn!("Error iterating addr db: {:?}", e),
-                Ok((key, value)) => {
-                    let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
-                        (*value).to_owned(),
-                    ));
-                    let mut value = match PeerData::deserialize(&mut de) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            warn!("Malformed data in addr db: {:?}", e);
-                            continue;
-                        }
-                    };
-                    value.given = 0;
-                    if let Err(e) = self.db.insert(key, ensicoin_messages::as_bytes(ser_peer_data(value))) {
-                        warn!("Error in reseting given in addr: {:?}", e)
-                    };
-                }
+    /// The peer completed a handshake, it is moved to the tried table
+    pub fn good(&mut self, peer: Peer) {
+        let now = timestamp();
+        let res = match self.get_peer(&peer) {
+            Ok(found) => {
+                let (table, mut data) = found.unwrap_or((Table::New, PeerData::default()));
+                data.timestamp = now;
+                data.last_success = now;
+                data.attempts = 0;
+                let moved = if table == Table::New {
+                    self.new
+                        .remove(peer_key(&peer))
+                        .map_err(AddressManagerError::from)
+                        .and_then(|_| self.make_room(Table::Tried, &peer.netgroup()))
+                } else {
+                    Ok(())
+                };
+                moved.and_then(|_| self.set_peer(Table::Tried, &peer, data))
             }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            warn!("Error registering peer: {}", e)
         }
     }
 
-    pub fn get_some_peers(&mut self, amount: usize) -> Vec<Peer> {
-        if self.given_count < self.len() {
-            let mut rng = rand::thread_rng();
-            self.db
-                .iter()
-                .filter(|res| match res {
-                    Ok(_) => true,
+    fn entries(&self, table: Table) -> Vec<(Peer, PeerData)> {
+        self.tree(table)
+            .iter()
+            .filter_map(|res| {
+                let parsed = res
+                    .map_err(AddressManagerError::from)
+                    .and_then(|(key, value)| Ok((key_peer(&key)?, parse_data(&value)?)));
+                match parsed {
+                    Ok(entry) => Some(entry),
                     Err(e) => {
-                        warn!("Error getting a peer: {:?}", e);
-                        false
-                    }
-                })
-                .choose_multiple(&mut rng, amount)
-                .into_iter()
-                .filter_map(|res| {
-                    let (key, value) = res.unwrap();
-                    let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
-                        (*value).to_owned(),
-                    ));
-                    let peer_data = match PeerData::deserialize(&mut de) {
-                        Ok(p) => p,
-                        Err(e) => {
-                            warn!("Error reading value from db addr: {:?}", e);
-                            return None;
-                        }
-                    };
-                    if peer_data.not_responded > self.no_response_limit {
-                        if let Err(e) = self.db.remove(key) {
-                            warn!("Could not clean value from addr db: {:?}", e)
-                        };
-                        return None;
+                        warn!("Error reading addr db: {}", e);
+                        None
                     }
-                    if peer_data.given != 0 {
-                        return None;
-                    }
-                    let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
-                        (*key).to_owned(),
-                    ));
-                    match Peer::deserialize(&mut de) {
-                        Ok(p) => {
-                            let mut peer_data = peer_data;
-                            peer_data.given = 1;
-                            if let Err(e) = self.set_peer(p.clone(), peer_data) {
-                                warn!("Could not update db: {:?}", e);
-                            };
-                            self.given_count += 1;
-                            Some(p)
-                        }
-                        Err(e) => {
-                            warn!("Error reading value from db addr: {:?}", e);
-                            None
-                        }
+                }
+            })
+            .collect()
+    }
+
+    /// Known addresses that are not terrible, the terrible ones are forgotten
+    fn usable_entries(&self, table: Table, now: u64) -> Vec<(Peer, PeerData)> {
+        let (usable, terrible): (Vec<_>, Vec<_>) = self
+            .entries(table)
+            .into_iter()
+            .partition(|(_, data)| !data.is_terrible(now));
+        for (peer, _) in terrible {
+            if let Err(e) = self.tree(table).remove(peer_key(&peer)) {
+                warn!("Could not delete value in addr db: {}", e)
+            }
+        }
+        usable
+    }
+
+    pub fn get_addr(&self) -> Vec<Address> {
+        let now = timestamp();
+        let mut addresses = Vec::new();
+        for table in &[Table::Tried, Table::New] {
+            for (peer, data) in self.usable_entries(*table, now) {
+                addresses.push(Address {
+                    timestamp: data.timestamp,
+                    ip: peer.ip,
+                    port: peer.port,
+                });
+            }
+        }
+        addresses
+    }
+
+    pub fn new_message(&mut self, source: &Source) {
+        if let Source::Connection(conn) = source {
+            let peer = if conn.inbound { conn.peer } else { conn.socket };
+            match self.get_peer(&peer) {
+                Ok(Some((table, mut data))) => {
+                    data.timestamp = timestamp();
+                    if let Err(e) = self.set_peer(table, &peer, data) {
+                        warn!("Error setting in addr db: {}", e)
                     }
-                })
-                .collect()
-        } else {
-            Vec::new()
+                }
+                Ok(None) => (),
+                Err(e) => warn!("Error reading from db: {}", e),
+            }
+        }
+    }
+
+    /// Selects up to `amount` peers to connect to, alternating between the tried and the new
+    /// table, with at most one peer per network group and none in `used_groups`. The attempts
+    /// are recorded.
+    pub fn get_some_peers(&mut self, amount: usize, used_groups: &HashSet<Vec<u8>>) -> Vec<Peer> {
+        let now = timestamp();
+        let mut rng = rand::thread_rng();
+        let mut groups = used_groups.clone();
+        let mut tried = self.usable_entries(Table::Tried, now);
+        let mut new = self.usable_entries(Table::New, now);
+        tried.shuffle(&mut rng);
+        new.shuffle(&mut rng);
+
+        let mut chosen = Vec::new();
+        while chosen.len() < amount {
+            let (peer, data) = match (tried.is_empty(), new.is_empty()) {
+                (true, true) => break,
+                (false, true) => tried.pop().unwrap(),
+                (true, false) => new.pop().unwrap(),
+                (false, false) if rng.gen_bool(0.5) => tried.pop().unwrap(),
+                (false, false) => new.pop().unwrap(),
+            };
+            if groups.contains(&peer.netgroup())
+                || self.bans.is_banned(&peer.ip)
+                || rng.gen::<f64>() >= data.chance(now)
+            {
+                continue;
+            }
+            groups.insert(peer.netgroup());
+            chosen.push(peer);
+        }
+        for peer in &chosen {
+            self.attempt(*peer);
         }
+        chosen
     }
 
     pub fn len(&self) -> usize {
-        self.db.len()
+        self.new.len() + self.tried.len()
     }
 }
@@ -0,0 +1,112 @@
+use super::AddressManagerError;
+use crate::constants::{BAN_THRESHOLD, SCORE_DECAY_INTERVAL};
+use ensicoin_serializer::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Misbehavior points of an ip and when they were last decayed
+struct Score {
+    points: u32,
+    updated: u64,
+}
+
+/// Persisted bans of misbehaving peers, keyed by ip with the end of the ban as value, and the
+/// in memory scores leading to them
+#[derive(Clone)]
+pub struct BanList {
+    tree: sled::Tree,
+    scores: Arc<Mutex<HashMap<[u8; 16], Score>>>,
+}
+
+fn ban_end(value: &[u8]) -> Option<u64> {
+    let mut de = Deserializer::new(bytes::BytesMut::from(value));
+    u64::deserialize(&mut de).ok()
+}
+
+impl BanList {
+    pub fn new(tree: sled::Tree) -> BanList {
+        BanList {
+            tree,
+            scores: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Adds a penalty to the score of the ip after a point per `SCORE_DECAY_INTERVAL` was taken
+    /// off, returns the new score if it stays under the ban threshold
+    pub fn add_penalty(&self, ip: [u8; 16], penalty: u32) -> Option<u32> {
+        let now = crate::utils::timestamp();
+        let mut scores = self.scores.lock().unwrap();
+        let score = scores.entry(ip).or_insert(Score {
+            points: 0,
+            updated: now,
+        });
+        let decayed = now.saturating_sub(score.updated) / SCORE_DECAY_INTERVAL;
+        score.points = score
+            .points
+            .saturating_sub(std::cmp::min(decayed, u32::max_value() as u64) as u32);
+        score.updated += decayed * SCORE_DECAY_INTERVAL;
+        score.points = score.points.saturating_add(penalty);
+        if score.points < BAN_THRESHOLD {
+            return Some(score.points);
+        }
+        scores.remove(&ip);
+        None
+    }
+
+    pub fn ban(&self, ip: [u8; 16], until: u64) -> Result<(), AddressManagerError> {
+        self.tree.insert(&ip, &until.to_be_bytes())?;
+        Ok(())
+    }
+
+    pub fn is_banned(&self, ip: &[u8; 16]) -> bool {
+        let until = match self.tree.get(ip) {
+            Ok(Some(value)) => ban_end(&value),
+            Ok(None) => return false,
+            Err(e) => {
+                warn!("Error reading bans: {}", e);
+                return false;
+            }
+        };
+        match until {
+            Some(until) if until > crate::utils::timestamp() => true,
+            _ => {
+                if let Err(e) = self.tree.remove(ip) {
+                    warn!("Could not remove expired ban: {}", e)
+                }
+                false
+            }
+        }
+    }
+
+    pub fn bans(&self) -> Vec<([u8; 16], u64)> {
+        let now = crate::utils::timestamp();
+        self.tree
+            .iter()
+            .filter_map(|res| match res {
+                Ok((key, value)) if key.len() == 16 => {
+                    let until = ban_end(&value)?;
+                    if until <= now {
+                        return None;
+                    }
+                    let mut ip = [0; 16];
+                    ip.copy_from_slice(&key);
+                    Some((ip, until))
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    warn!("Error reading bans: {}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Lifts every ban and forgets the misbehavior scores
+    pub fn clear(&self) -> Result<(), AddressManagerError> {
+        self.scores.lock().unwrap().clear();
+        for key in self.tree.iter().keys() {
+            self.tree.remove(key?)?;
+        }
+        Ok(())
+    }
+}
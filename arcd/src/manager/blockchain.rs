@@ -1,12 +1,31 @@
+use super::{
+    clear_tree,
+    utxo::{index_history, UtxoTrees},
+    UtxoManager,
+};
 use crate::{
-    data::{linkedblock::LinkedBlock, PairedUtxo, ser_paired_utxo},
+    consensus::ValidationError,
+    constants::NetworkParams,
+    data::{
+        linkedblock::{header_work, LinkedBlock},
+        linkedtx::{Dependency, DependencyType},
+        ser_paired_utxo, PairedUtxo, UtxoData,
+    },
     Error,
 };
-use cookie_factory::{bytes::be_u8, combinator::slice, multi::all, SerializeFn};
-use ensicoin_messages::resource::fn_block;
-use ensicoin_messages::resource::{Block, Outpoint, Transaction};
+use cookie_factory::{
+    bytes::{be_u32, be_u8},
+    combinator::slice,
+    multi::all,
+    sequence::tuple,
+    SerializeFn,
+};
+use ensicoin_messages::resource::{block::fn_block_header, fn_block};
+use ensicoin_messages::resource::{Block, BlockHeader, Outpoint, Transaction};
 use ensicoin_serializer::{hash_to_string, serializer::fn_list, Deserialize, Sha256Result};
 use num_bigint::BigUint;
+use sled::{TransactionResult, Transactional, TransactionalTree};
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
 
 fn ser_block(block: &Block) -> Vec<u8> {
@@ -18,13 +37,74 @@ fn ser_biguint<'c, 'a: 'c, W: Write + 'c>(num: &'a BigUint) -> impl SerializeFn<
     fn_list(bytes.len() as u64, bytes.into_iter().map(be_u8))
 }
 
+/// Maps the hash of every transaction of the block to the block hash and its position in the
+/// block
+fn index_txs(
+    txindex: &TransactionalTree,
+    block: &Block,
+    hash: Sha256Result,
+) -> TransactionResult<()> {
+    for (i, tx) in block.txs.iter().enumerate() {
+        txindex.insert(
+            tx.double_hash().as_slice(),
+            ensicoin_messages::as_bytes(tuple((slice(hash), be_u32(i as u32)))),
+        )?;
+    }
+    Ok(())
+}
+
+fn unindex_txs(txindex: &TransactionalTree, block: &Block) -> TransactionResult<()> {
+    for tx in &block.txs {
+        txindex.remove(tx.double_hash().as_slice())?;
+    }
+    Ok(())
+}
+
+/// Height the skip pointer of a header at `height` jumps to, as in a skip list any ancestor is
+/// then reachable in a logarithmic number of steps
+fn skip_height(height: u32) -> u32 {
+    fn invert_lowest_one(n: u32) -> u32 {
+        n & n.wrapping_sub(1)
+    }
+    if height < 2 {
+        0
+    } else if height & 1 == 1 {
+        invert_lowest_one(invert_lowest_one(height - 1)) + 1
+    } else {
+        invert_lowest_one(height)
+    }
+}
+
+/// Stored bodies are ordered by height, big endian so that a range gives the lowest first
+fn stored_height_key(height: u32, hash: &Sha256Result) -> Vec<u8> {
+    let mut key = height.to_be_bytes().to_vec();
+    key.extend_from_slice(hash);
+    key
+}
+
+/// Block storage and chain state, every tree lives in the same chainstate database so that
+/// connecting or disconnecting a block, utxo set included, is done in a single transaction
 pub struct Blockchain {
-    stats: sled::Db,
-    database: sled::Db,
-    reverse_chain: sled::Db,
-    spent_tx: sled::Db,
-    past_block: sled::Db,
-    work: sled::Db,
+    stats: sled::Tree,
+    database: sled::Tree,
+    reverse_chain: sled::Tree,
+    spent_tx: sled::Tree,
+    past_block: sled::Tree,
+    work: sled::Tree,
+    headers: sled::Tree,
+    skip: sled::Tree,
+    pruned: sled::Tree,
+    /// Height and hash of the stored block bodies while pruning, to find the side chain bodies
+    /// left under the pruned height
+    stored_heights: sled::Tree,
+    txindex: sled::Tree,
+    utxo: UtxoManager,
+    /// Confirmed transactions are indexed by hash
+    txindex_enabled: bool,
+    /// Size in bytes the stored block bodies are kept under, if pruning is enabled
+    prune_target: Option<u64>,
+    block_bytes: u64,
+    network: &'static NetworkParams,
 }
 
 pub enum NewAddition {
@@ -49,45 +129,33 @@ impl Blockchain {
         self
     }
 
-    pub fn new(data_dir: &std::path::Path) -> Blockchain {
-        let mut blockchain_dir = std::path::PathBuf::new();
-        blockchain_dir.push(data_dir);
-        blockchain_dir.push("blockchain");
-        let database = sled::Db::open(blockchain_dir).unwrap();
-
-        let mut rev_dir = std::path::PathBuf::new();
-        rev_dir.push(data_dir);
-        rev_dir.push("reverse_chain");
-        let reverse_chain = sled::Db::open(rev_dir).unwrap();
-
-        let mut spent_tx_dir = std::path::PathBuf::new();
-        spent_tx_dir.push(data_dir);
-        spent_tx_dir.push("spent_tx");
-        let spent_tx = sled::Db::open(spent_tx_dir).unwrap();
-
-        let mut stats_dir = std::path::PathBuf::new();
-        stats_dir.push(data_dir);
-        stats_dir.push("stats");
-        let stats = sled::Db::open(stats_dir).unwrap();
-
-        let mut past_dir = std::path::PathBuf::new();
-        past_dir.push(data_dir);
-        past_dir.push("past_block");
-        let past_block = sled::Db::open(past_dir).unwrap();
-
-        let mut work_dir = std::path::PathBuf::new();
-        work_dir.push(data_dir);
-        work_dir.push("work");
-        let work = sled::Db::open(work_dir).unwrap();
+    pub fn new(
+        chainstate: &sled::Db,
+        utxo: UtxoManager,
+        network: &'static NetworkParams,
+    ) -> Result<Blockchain, Error> {
+        Ok(Blockchain {
+            stats: chainstate.open_tree("stats")?,
+            database: chainstate.open_tree("blockchain")?,
+            reverse_chain: chainstate.open_tree("reverse_chain")?,
+            spent_tx: chainstate.open_tree("spent_tx")?,
+            past_block: chainstate.open_tree("past_block")?,
+            work: chainstate.open_tree("work")?,
+            headers: chainstate.open_tree("headers")?,
+            skip: chainstate.open_tree("skip")?,
+            pruned: chainstate.open_tree("pruned")?,
+            stored_heights: chainstate.open_tree("stored_heights")?,
+            txindex: chainstate.open_tree("txindex")?,
+            utxo,
+            txindex_enabled: false,
+            prune_target: None,
+            block_bytes: 0,
+            network,
+        })
+    }
 
-        Blockchain {
-            stats,
-            database,
-            reverse_chain,
-            spent_tx,
-            past_block,
-            work,
-        }
+    pub fn network(&self) -> &'static NetworkParams {
+        self.network
     }
 
     pub fn block_after(&self, hash: &Sha256Result) -> Result<Option<Sha256Result>, Error> {
@@ -114,11 +182,15 @@ impl Blockchain {
             .map_err(Error::ParseError)
     }
 
+    /// Returns `Error::Pruned` if the block body was dropped
     pub fn get_block(&self, hash: &Sha256Result) -> Result<Option<Block>, Error> {
         debug!("Getting block from db");
         let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
             match self.database.get(&hash)? {
                 Some(b) => (*b).to_owned(),
+                None if self.pruned.contains_key(&hash)? => {
+                    return Err(Error::Pruned(format!("block {}", hash_to_string(hash))))
+                }
                 None => return Ok(None),
             },
         ));
@@ -128,13 +200,13 @@ impl Blockchain {
         })
     }
 
-    pub fn block_2016_before(&self, hash: &Sha256Result) -> Result<Sha256Result, Error> {
+    pub fn block_interval_before(&self, hash: &Sha256Result) -> Result<Sha256Result, Error> {
         let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
             match self.past_block.get(&hash)? {
                 Some(b) => (*b).to_owned(),
                 None => {
                     return Err(Error::NotFound(format!(
-                        "2016 before {}",
+                        "interval before {}",
                         hash_to_string(hash)
                     )))
                 }
@@ -153,6 +225,17 @@ impl Blockchain {
         Sha256Result::deserialize(&mut de).map_err(Error::ParseError)
     }
 
+    pub fn best_height(&self) -> Result<u32, Error> {
+        let best_block_hash = self.best_block_hash()?;
+        match self.get_block(&best_block_hash)? {
+            Some(b) => Ok(b.header.height),
+            None => Err(Error::NotFound(format!(
+                "block {}",
+                hash_to_string(&best_block_hash)
+            ))),
+        }
+    }
+
     pub fn genesis_hash(&self) -> Result<Sha256Result, Error> {
         let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
             match self.stats.get("genesis_block")? {
@@ -163,8 +246,8 @@ impl Blockchain {
         Sha256Result::deserialize(&mut de).map_err(Error::ParseError)
     }
 
-    fn set_best_block(&mut self, hash: Sha256Result) -> Result<(), Error> {
-        self.stats.insert("best_block", ensicoin_messages::as_bytes(slice(hash)))?;
+    /// Last blocks list once the given block is connected
+    fn last_blocks_after_push(&self, hash: Sha256Result) -> Result<Vec<u8>, Error> {
         let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
             match self.stats.get("10_last")? {
                 Some(b) => (*b).to_owned(),
@@ -176,14 +259,14 @@ impl Blockchain {
         if blocks.len() > 10 {
             blocks = blocks.split_off(1);
         }
-        self.stats.insert(
-            "10_last",
-            ensicoin_messages::as_bytes(fn_list(blocks.len() as u64, blocks.iter().map(slice))),
-        )?;
-        Ok(())
+        Ok(ensicoin_messages::as_bytes(fn_list(
+            blocks.len() as u64,
+            blocks.iter().map(slice),
+        )))
     }
 
-    fn unset_best_block(&mut self) -> Result<(), Error> {
+    /// Last blocks list once the best block is disconnected
+    fn last_blocks_after_pop(&self) -> Result<Vec<u8>, Error> {
         let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
             match self.stats.get("10_last")? {
                 Some(b) => (*b).to_owned(),
@@ -192,17 +275,16 @@ impl Blockchain {
         ));
         let mut blocks: Vec<Sha256Result> = Vec::deserialize(&mut de)?;
         blocks.pop();
-        let last_block = self.get_block(&blocks[0])?.unwrap().header;
+        let last_block = self.get_header(&blocks[0])?.unwrap();
         if last_block.prev_block != Sha256Result::from([0; 32]) {
             let mut temp = blocks;
             blocks = vec![last_block.prev_block];
             blocks.append(&mut temp);
         }
-        self.stats.insert(
-            "10_last",
-            ensicoin_messages::as_bytes(fn_list(blocks.len() as u64, blocks.iter().map(slice))),
-        )?;
-        Ok(())
+        Ok(ensicoin_messages::as_bytes(fn_list(
+            blocks.len() as u64,
+            blocks.iter().map(slice),
+        )))
     }
 
     pub fn generate_get_blocks(&self) -> Result<ensicoin_messages::message::GetBlocks, Error> {
@@ -223,10 +305,384 @@ impl Blockchain {
         })
     }
 
+    /// Stored blocks descending from the genesis block, parents first
+    fn stored_chain(&self, genesis_hash: Sha256Result) -> Result<Vec<Sha256Result>, Error> {
+        let mut children: std::collections::HashMap<Sha256Result, Vec<(u32, Sha256Result)>> =
+            std::collections::HashMap::new();
+        for entry in self.database.iter() {
+            let (key, value) = entry?;
+            let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(&*value));
+            let header = BlockHeader::deserialize(&mut de)?;
+            children
+                .entry(header.prev_block)
+                .or_default()
+                .push((header.height, Sha256Result::clone_from_slice(&key)));
+        }
+        let mut blocks = Vec::new();
+        let mut to_visit = vec![genesis_hash];
+        while let Some(hash) = to_visit.pop() {
+            for (height, child) in children.remove(&hash).unwrap_or_default() {
+                blocks.push((height, child));
+                to_visit.push(child);
+            }
+        }
+        blocks.sort();
+        Ok(blocks.into_iter().map(|(_, hash)| hash).collect())
+    }
+
+    /// Wipes the chain state down to the genesis block, only the stored blocks and headers are
+    /// kept. Returns the stored blocks that descend from the genesis block in the order they
+    /// must be validated again
+    pub fn reindex_chain(&mut self) -> Result<Vec<Sha256Result>, Error> {
+        if self.pruned.iter().next().is_some() {
+            return Err(Error::Pruned("blocks needed to reindex".to_string()));
+        }
+        let genesis_hash = self.genesis_hash()?;
+        let blocks = self.stored_chain(genesis_hash)?;
+        let (utxo, history, script_utxo) = self.utxo.trees();
+        for tree in &[
+            utxo,
+            history,
+            script_utxo,
+            &self.txindex,
+            &self.work,
+            &self.reverse_chain,
+            &self.spent_tx,
+            &self.past_block,
+            &self.stats,
+        ] {
+            clear_tree(tree)?;
+        }
+        self.stats.insert(
+            "genesis_block",
+            ensicoin_messages::as_bytes(slice(genesis_hash)),
+        )?;
+        self.stats.insert(
+            "best_block",
+            ensicoin_messages::as_bytes(slice(genesis_hash)),
+        )?;
+        self.stats.insert(
+            "10_last",
+            ensicoin_messages::as_bytes(fn_list(1, std::iter::once(genesis_hash).map(slice))),
+        )?;
+        self.work.insert(
+            genesis_hash,
+            ensicoin_messages::as_bytes(ser_biguint(&BigUint::from(0 as u8))),
+        )?;
+        Ok(blocks)
+    }
+
+    /// The block passed validation, in the main chain or in a side chain
+    pub fn is_validated(&self, hash: &Sha256Result) -> Result<bool, Error> {
+        Ok(self.work.contains_key(hash)?)
+    }
+
     pub fn exists(&self, hash: &ensicoin_serializer::Sha256Result) -> Result<bool, Error> {
-        self.database
-            .contains_key(hash)
-            .map_err(Error::DatabaseError)
+        Ok(self.database.contains_key(hash)? || self.pruned.contains_key(hash)?)
+    }
+
+    /// Keeps the stored block bodies under the given size in bytes, the size of the blocks
+    /// already stored is computed here
+    pub fn set_prune_target(&mut self, target: u64) -> Result<(), Error> {
+        clear_tree(&self.stored_heights)?;
+        let mut block_bytes = 0;
+        for entry in self.database.iter() {
+            let (hash, raw_block) = entry?;
+            block_bytes += raw_block.len() as u64;
+            let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(&*raw_block));
+            let height = BlockHeader::deserialize(&mut de)?.height;
+            // The genesis block is never pruned
+            if height > 0 {
+                self.stored_heights
+                    .insert(stored_height_key(height, &hash), Vec::new())?;
+            }
+        }
+        self.block_bytes = block_bytes;
+        self.prune_target = Some(target);
+        Ok(())
+    }
+
+    /// Old blocks may be missing, the node can not serve the whole chain
+    pub fn is_pruned(&self) -> bool {
+        self.prune_target.is_some() || self.pruned.iter().next().is_some()
+    }
+
+    fn last_pruned(&self) -> Result<Sha256Result, Error> {
+        match self.stats.get("last_pruned")? {
+            Some(b) => {
+                let mut de =
+                    ensicoin_serializer::Deserializer::new(bytes::BytesMut::from((*b).to_owned()));
+                Sha256Result::deserialize(&mut de).map_err(Error::ParseError)
+            }
+            None => self.genesis_hash(),
+        }
+    }
+
+    /// Last block of the main chain whose transactions are indexed, the blocks after it are
+    /// indexed as they are connected once the index caught up
+    fn txindex_tip(&self) -> Result<Sha256Result, Error> {
+        match self.stats.get("txindex_tip")? {
+            Some(b) => {
+                let mut de =
+                    ensicoin_serializer::Deserializer::new(bytes::BytesMut::from((*b).to_owned()));
+                Sha256Result::deserialize(&mut de).map_err(Error::ParseError)
+            }
+            None => self.genesis_hash(),
+        }
+    }
+
+    /// A disabled index is wiped as it is not kept up to date
+    pub fn set_txindex(&mut self, enabled: bool) -> Result<(), Error> {
+        self.txindex_enabled = enabled;
+        if !enabled && self.txindex.iter().next().is_some() {
+            info!("Removing the transaction index");
+            clear_tree(&self.txindex)?;
+            self.stats.remove("txindex_tip")?;
+        }
+        Ok(())
+    }
+
+    /// Indexes the transactions of the block following the last indexed one, returns its height
+    /// or None once the index caught up with the best block
+    pub fn index_next_block(&mut self) -> Result<Option<u32>, Error> {
+        let hash = match self.block_after(&self.txindex_tip()?)? {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        let block = match self.get_block(&hash)? {
+            Some(b) => b,
+            None => return Err(Error::NotFound(format!("block {}", hash_to_string(&hash)))),
+        };
+        (&self.txindex, &self.stats).transaction(|(txindex, stats)| {
+            index_txs(txindex, &block, hash)?;
+            stats.insert(
+                "txindex_tip".as_bytes(),
+                ensicoin_messages::as_bytes(slice(hash)),
+            )?;
+            Ok(())
+        })?;
+        Ok(Some(block.header.height))
+    }
+
+    /// Last block of the main chain whose script history was built for an index enabled on an
+    /// existing chain, None once the history covers the whole chain
+    fn script_index_tip(&self) -> Result<Option<Sha256Result>, Error> {
+        match self.stats.get("script_index_tip")? {
+            Some(b) => {
+                let mut de =
+                    ensicoin_serializer::Deserializer::new(bytes::BytesMut::from((*b).to_owned()));
+                Ok(Some(Sha256Result::deserialize(&mut de)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Records that the script history of the stored chain must be built, or resumes a build
+    /// interrupted by a restart. Returns true while the history is incomplete
+    pub fn start_script_index(&mut self, build: bool) -> Result<bool, Error> {
+        if !self.utxo.script_index() {
+            self.stats.remove("script_index_tip")?;
+            return Ok(false);
+        }
+        if build {
+            self.stats.insert(
+                "script_index_tip",
+                ensicoin_messages::as_bytes(slice(self.genesis_hash()?)),
+            )?;
+        }
+        let building = self.script_index_tip()?.is_some();
+        self.utxo.set_script_index_building(building);
+        Ok(building)
+    }
+
+    /// Adds the block following the last one to the script history, returns its height or None
+    /// once the history covers the best block. Blocks connected during the build are already in
+    /// the history, marking them again is harmless
+    pub fn index_scripts_next_block(&mut self) -> Result<Option<u32>, Error> {
+        let tip = match self.script_index_tip()? {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        let hash = match self.block_after(&tip)? {
+            Some(h) => h,
+            None => {
+                self.stats.remove("script_index_tip")?;
+                self.utxo.set_script_index_building(false);
+                return Ok(None);
+            }
+        };
+        let block = match self.get_block(&hash)? {
+            Some(b) => b,
+            None => return Err(Error::NotFound(format!("block {}", hash_to_string(&hash)))),
+        };
+        let spent_utxo = self.spent_utxo(&hash)?;
+        let (_, history_tree, _) = self.utxo.trees();
+        (history_tree, &self.stats).transaction(|(history, stats)| {
+            index_history(history, &block, &spent_utxo)?;
+            stats.insert(
+                "script_index_tip".as_bytes(),
+                ensicoin_messages::as_bytes(slice(hash)),
+            )?;
+            Ok(())
+        })?;
+        Ok(Some(block.header.height))
+    }
+
+    /// Confirmed transaction with the hash of its block and its number of confirmations
+    pub fn get_tx(
+        &self,
+        hash: &Sha256Result,
+    ) -> Result<Option<(Transaction, Sha256Result, u32)>, Error> {
+        let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
+            match self.txindex.get(&hash)? {
+                Some(b) => (*b).to_owned(),
+                None => return Ok(None),
+            },
+        ));
+        let block_hash = Sha256Result::deserialize(&mut de)?;
+        let position = u32::deserialize(&mut de)? as usize;
+        let mut block = match self.get_block(&block_hash)? {
+            Some(b) => b,
+            None => {
+                return Err(Error::NotFound(format!(
+                    "block {}",
+                    hash_to_string(&block_hash)
+                )))
+            }
+        };
+        if position >= block.txs.len() {
+            return Err(Error::NotFound(format!("tx {}", hash_to_string(hash))));
+        }
+        let confirmations = self.best_height()? + 1 - block.header.height;
+        Ok(Some((
+            block.txs.swap_remove(position),
+            block_hash,
+            confirmations,
+        )))
+    }
+
+    fn count_block(
+        &mut self,
+        hash: &Sha256Result,
+        height: u32,
+        raw_block: &[u8],
+    ) -> Result<(), Error> {
+        if !self.database.contains_key(hash)? {
+            self.block_bytes += raw_block.len() as u64;
+            if self.prune_target.is_some() {
+                self.stored_heights
+                    .insert(stored_height_key(height, hash), Vec::new())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the block body and its undo data, keeping its header. A main chain block is
+    /// recorded as the last pruned one
+    fn prune_block(
+        &mut self,
+        hash: Sha256Result,
+        header: &BlockHeader,
+        main_chain: bool,
+    ) -> Result<(), Error> {
+        let raw_header = ensicoin_messages::as_bytes(tuple((
+            fn_block_header(header),
+            ser_biguint(&self.get_work(&hash)?),
+        )));
+        let stored_height = stored_height_key(header.height, &hash);
+        (
+            &self.database,
+            &self.spent_tx,
+            &self.headers,
+            &self.pruned,
+            &self.stored_heights,
+            &self.stats,
+        )
+            .transaction(
+                |(database, spent_tx, headers, pruned, stored_heights, stats)| {
+                    headers.insert(hash.as_slice(), raw_header.clone())?;
+                    database.remove(hash.as_slice())?;
+                    spent_tx.remove(hash.as_slice())?;
+                    pruned.insert(hash.as_slice(), Vec::new())?;
+                    stored_heights.remove(stored_height.clone())?;
+                    if main_chain {
+                        stats.insert(
+                            "last_pruned".as_bytes(),
+                            ensicoin_messages::as_bytes(slice(hash)),
+                        )?;
+                    }
+                    Ok(())
+                },
+            )?;
+        Ok(())
+    }
+
+    /// Drops the bodies stored at or under the pruned height, the main chain ones being
+    /// already pruned they belong to stale side chains
+    fn prune_side_blocks(&mut self, pruned_height: u32) -> Result<u32, Error> {
+        let keys = self
+            .stored_heights
+            .range(..(pruned_height + 1).to_be_bytes())
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut count = 0;
+        for key in keys {
+            let hash = Sha256Result::clone_from_slice(&key[4..]);
+            let raw_block = match self.database.get(&hash)? {
+                Some(b) => b,
+                None => {
+                    self.stored_heights.remove(key)?;
+                    continue;
+                }
+            };
+            let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(&*raw_block));
+            let header = BlockHeader::deserialize(&mut de)?;
+            self.prune_block(hash, &header, false)?;
+            self.block_bytes = self.block_bytes.saturating_sub(raw_block.len() as u64);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Drops the oldest block bodies of the main chain with their undo data while the stored
+    /// blocks are over the target, headers are kept and the last `MIN_BLOCKS_TO_KEEP` blocks
+    /// are never pruned. The side chain bodies under the pruned height are dropped too.
+    /// Returns the number of pruned blocks
+    pub fn prune(&mut self) -> Result<u32, Error> {
+        use crate::constants::MIN_BLOCKS_TO_KEEP;
+
+        let target = match self.prune_target {
+            Some(t) => t,
+            None => return Ok(0),
+        };
+        let best_height = self.best_height()?;
+        let mut last_pruned = self.last_pruned()?;
+        let mut pruned_height = match self.get_header(&last_pruned)? {
+            Some(h) => h.height,
+            None => 0,
+        };
+        let mut count = 0;
+        while self.block_bytes > target {
+            let hash = match self.block_after(&last_pruned)? {
+                Some(h) => h,
+                None => break,
+            };
+            let raw_block = match self.database.get(&hash)? {
+                Some(b) => b,
+                None => break,
+            };
+            let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(&*raw_block));
+            let header = BlockHeader::deserialize(&mut de)?;
+            if header.height + MIN_BLOCKS_TO_KEEP > best_height {
+                break;
+            }
+            self.prune_block(hash, &header, true)?;
+            self.block_bytes = self.block_bytes.saturating_sub(raw_block.len() as u64);
+            last_pruned = hash;
+            pruned_height = header.height;
+            count += 1;
+        }
+        Ok(count + self.prune_side_blocks(pruned_height)?)
     }
 
     pub fn get_unknown_blocks(
@@ -301,28 +757,28 @@ impl Blockchain {
             info!("One does not exist");
             return Ok(None);
         };
-        let mut block1 = match self.get_block(&hash1)? {
-            Some(b) => b,
+        let mut header1 = match self.get_header(&hash1)? {
+            Some(h) => h,
             None => {
                 info!("Orphan block");
                 return Ok(None);
             }
         };
-        let mut block2 = match self.get_block(&hash2)? {
-            Some(b) => b,
+        let mut header2 = match self.get_header(&hash2)? {
+            Some(h) => h,
             None => {
                 info!("Orphan block");
                 return Ok(None);
             }
         };
-        if block2.header.height > block1.header.height {
-            std::mem::swap(&mut block1, &mut block2);
+        if header2.height > header1.height {
+            std::mem::swap(&mut header1, &mut header2);
         }
 
-        if block1.header.height > block2.header.height {
-            while block1.header.height != block2.header.height {
-                block1 = match self.get_block(&block1.header.prev_block)? {
-                    Some(b) => b,
+        if header1.height > header2.height {
+            while header1.height != header2.height {
+                header1 = match self.get_header(&header1.prev_block)? {
+                    Some(h) => h,
                     None => {
                         info!("Orphan chain");
                         return Ok(None);
@@ -330,24 +786,24 @@ impl Blockchain {
                 }
             }
         };
-        while block1.double_hash() != block2.double_hash() {
-            block1 = match self.get_block(&block1.header.prev_block)? {
-                Some(b) => b,
+        while header1.double_hash() != header2.double_hash() {
+            header1 = match self.get_header(&header1.prev_block)? {
+                Some(h) => h,
                 None => {
                     info!("No merge point");
                     return Ok(None);
                 }
             };
-            block2 = match self.get_block(&block2.header.prev_block)? {
-                Some(b) => b,
+            header2 = match self.get_header(&header2.prev_block)? {
+                Some(h) => h,
                 None => {
                     info!("No merge point");
                     return Ok(None);
                 }
             };
         }
-        if block1.double_hash() == block2.double_hash() {
-            Ok(Some(block1.double_hash()))
+        if header1.double_hash() == header2.double_hash() {
+            Ok(Some(header1.double_hash()))
         } else {
             Ok(None)
         }
@@ -355,13 +811,17 @@ impl Blockchain {
 
     pub fn new_block(&mut self, block: LinkedBlock) -> Result<NewAddition, Error> {
         let hash = block.header.double_hash();
+        let height = block.header.height;
         let chain_work = self.get_work(&block.header.prev_block)? + block.work();
+        self.store_header(&block.header, &chain_work)?;
         let best_hash = self.best_block_hash()?;
         let best_work = self.get_work(&best_hash)?;
         Ok(
             if block.header.prev_block == best_hash || chain_work > best_work {
                 if block.header.prev_block != best_hash {
-                    self.database.insert(hash, ser_block(&block.into_block()))?;
+                    let raw_block = ser_block(&block.into_block());
+                    self.count_block(&hash, height, &raw_block)?;
+                    self.database.insert(hash, raw_block)?;
                     self.work
                         .insert(hash, ensicoin_messages::as_bytes(ser_biguint(&chain_work)))?;
                     NewAddition::Fork
@@ -370,8 +830,9 @@ impl Blockchain {
                     NewAddition::BestBlock
                 }
             } else {
-                self.database
-                    .insert(hash, ser_block(&block.into_block()))?;
+                let raw_block = ser_block(&block.into_block());
+                self.count_block(&hash, height, &raw_block)?;
+                self.database.insert(hash, raw_block)?;
                 self.work
                     .insert(hash, ensicoin_messages::as_bytes(ser_biguint(&chain_work)))?;
                 NewAddition::Nothing
@@ -379,49 +840,207 @@ impl Blockchain {
         )
     }
 
-    pub fn add_chain(&mut self, blocks: Vec<LinkedBlock>) -> Result<(), Error> {
-        for b in blocks {
-            self.add_block(b)?;
-        }
-        Ok(())
-    }
-
-    fn add_block(&mut self, block: LinkedBlock) -> Result<(), Error> {
+    /// Connects a block on top of the best block, the block, its metadata and the utxo set are
+    /// written in a single transaction
+    pub fn add_block(&mut self, block: LinkedBlock) -> Result<(), Error> {
         debug!(
             "Adding block {} to blockchain",
             hash_to_string(&block.header.double_hash())
         );
         let chain_work = self.get_work(&block.header.prev_block)? + block.work();
         let spent_utxo = block.spent_utxo();
-        let spent_utxo = ensicoin_messages::as_bytes(fn_list(spent_utxo.len() as u64, spent_utxo.iter().map(ser_paired_utxo)));
+        let raw_spent_utxo = ensicoin_messages::as_bytes(fn_list(
+            spent_utxo.len() as u64,
+            spent_utxo.iter().map(ser_paired_utxo),
+        ));
         let block = block.into_block();
         let raw_block = ser_block(&block);
+        let raw_work = ensicoin_messages::as_bytes(ser_biguint(&chain_work));
         let hash = block.header.double_hash();
-        if block.header.height == 2015 {
-            let genesis_hash = self.genesis_hash()?;
-            self.past_block
-                .insert(hash, ensicoin_messages::as_bytes(slice(genesis_hash)))?;
-        } else if block.header.height >= 2016 {
-            let past_of_previous = self.block_2016_before(&block.header.prev_block)?;
-            let next = self.block_after(&past_of_previous)?.unwrap();
-            self.past_block.insert(hash, ensicoin_messages::as_bytes(slice(next)))?;
+        let interval = self.network.retarget_interval;
+        let past = if block.header.height == interval - 1 {
+            Some(self.genesis_hash()?)
+        } else if block.header.height >= interval {
+            let past_of_previous = self.block_interval_before(&block.header.prev_block)?;
+            self.block_after(&past_of_previous)?
+        } else {
+            None
         };
-        self.work
-            .insert(hash, ensicoin_messages::as_bytes(ser_biguint(&chain_work)))?;
-        self.database.insert(hash, raw_block.clone())?;
-        self.reverse_chain
-            .insert(block.header.prev_block, ensicoin_messages::as_bytes(slice(hash)))?;
-        self.spent_tx.insert(hash, spent_utxo)?;
-        self.set_best_block(hash)?;
+        let last_blocks = self.last_blocks_after_push(hash)?;
+        let advance_txindex =
+            self.txindex_enabled && self.txindex_tip()? == block.header.prev_block;
+        self.count_block(&hash, block.header.height, &raw_block)?;
+        let (utxo_tree, history_tree, script_utxo_tree) = self.utxo.trees();
+        (
+            &self.database,
+            &self.work,
+            &self.reverse_chain,
+            &self.spent_tx,
+            &self.past_block,
+            &self.stats,
+            &self.txindex,
+            utxo_tree,
+            history_tree,
+            script_utxo_tree,
+        )
+            .transaction(
+                |(
+                    database,
+                    work,
+                    reverse_chain,
+                    spent_tx,
+                    past_block,
+                    stats,
+                    txindex,
+                    utxo,
+                    history,
+                    script_utxo,
+                )| {
+                    if let Some(past) = past {
+                        past_block
+                            .insert(hash.as_slice(), ensicoin_messages::as_bytes(slice(past)))?;
+                    }
+                    work.insert(hash.as_slice(), raw_work.clone())?;
+                    database.insert(hash.as_slice(), raw_block.clone())?;
+                    reverse_chain.insert(
+                        block.header.prev_block.as_slice(),
+                        ensicoin_messages::as_bytes(slice(hash)),
+                    )?;
+                    spent_tx.insert(hash.as_slice(), raw_spent_utxo.clone())?;
+                    stats.insert(
+                        "best_block".as_bytes(),
+                        ensicoin_messages::as_bytes(slice(hash)),
+                    )?;
+                    stats.insert("10_last".as_bytes(), last_blocks.clone())?;
+                    if self.txindex_enabled {
+                        index_txs(txindex, &block, hash)?;
+                    }
+                    if advance_txindex {
+                        stats.insert(
+                            "txindex_tip".as_bytes(),
+                            ensicoin_messages::as_bytes(slice(hash)),
+                        )?;
+                    }
+                    let trees = UtxoTrees {
+                        utxo,
+                        history,
+                        script_utxo,
+                    };
+                    self.utxo.register_block(&trees, &block, &spent_utxo)
+                },
+            )?;
         Ok(())
     }
 
-    pub fn pop_until(&mut self, hash: &Sha256Result) -> Result<PopContext, Error> {
-        let until_block = match self.get_block(hash)? {
+    /// Makes the parent of the best block the new best block and reverts the utxo changes of the
+    /// best block, in a single transaction
+    fn disconnect(&self, best_block: &Block, spent_utxo: &[PairedUtxo]) -> Result<(), Error> {
+        let last_blocks = self.last_blocks_after_pop()?;
+        let rewind_txindex =
+            self.txindex_enabled && self.txindex_tip()? == best_block.header.double_hash();
+        let (utxo_tree, history_tree, script_utxo_tree) = self.utxo.trees();
+        (
+            &self.reverse_chain,
+            &self.stats,
+            &self.txindex,
+            utxo_tree,
+            history_tree,
+            script_utxo_tree,
+        )
+            .transaction(
+                |(reverse_chain, stats, txindex, utxo, history, script_utxo)| {
+                    reverse_chain.remove(best_block.header.prev_block.as_slice())?;
+                    stats.insert(
+                        "best_block".as_bytes(),
+                        ensicoin_messages::as_bytes(slice(best_block.header.prev_block)),
+                    )?;
+                    stats.insert("10_last".as_bytes(), last_blocks.clone())?;
+                    if self.txindex_enabled {
+                        unindex_txs(txindex, best_block)?;
+                    }
+                    if rewind_txindex {
+                        stats.insert(
+                            "txindex_tip".as_bytes(),
+                            ensicoin_messages::as_bytes(slice(best_block.header.prev_block)),
+                        )?;
+                    }
+                    let trees = UtxoTrees {
+                        utxo,
+                        history,
+                        script_utxo,
+                    };
+                    self.utxo.unregister_block(&trees, best_block, spent_utxo)
+                },
+            )?;
+        Ok(())
+    }
+
+    /// Detects a best block whose utxo changes are missing and registers them again, returns
+    /// true if something had to be repaired. Blocks are connected in a single transaction so a
+    /// best block without its spent outputs record means the database is corrupted
+    pub fn check_consistency(&mut self) -> Result<bool, Error> {
+        let best_block_hash = self.best_block_hash()?;
+        let mut repaired = false;
+        if let Some(next) = self.block_after(&best_block_hash)? {
+            warn!(
+                "Block {} is linked after the best block but was never connected, unlinking it",
+                hash_to_string(&next)
+            );
+            self.reverse_chain.remove(&best_block_hash)?;
+            repaired = true;
+        }
+        if best_block_hash == self.genesis_hash()? {
+            return Ok(repaired);
+        }
+        let best_block = match self.get_block(&best_block_hash)? {
             Some(b) => b,
+            None => {
+                return Err(Error::NotFound(format!(
+                    "block {}",
+                    hash_to_string(&best_block_hash)
+                )))
+            }
+        };
+        let spent_utxo: Vec<PairedUtxo> = match self.spent_tx.get(&best_block_hash)? {
+            Some(b) => {
+                let mut de =
+                    ensicoin_serializer::Deserializer::new(bytes::BytesMut::from((*b).to_owned()));
+                Vec::deserialize(&mut de)?
+            }
+            None => {
+                return Err(Error::CorruptChainState(format!(
+                    "best block {} has no spent outputs record",
+                    hash_to_string(&best_block_hash)
+                )))
+            }
+        };
+        if !self.utxo.has_block(&best_block, &spent_utxo)? {
+            warn!(
+                "Utxo set does not match best block {}, connecting it again",
+                hash_to_string(&best_block_hash)
+            );
+            let (utxo_tree, history_tree, script_utxo_tree) = self.utxo.trees();
+            (utxo_tree, history_tree, script_utxo_tree).transaction(
+                |(utxo, history, script_utxo)| {
+                    let trees = UtxoTrees {
+                        utxo,
+                        history,
+                        script_utxo,
+                    };
+                    self.utxo.register_block(&trees, &best_block, &spent_utxo)
+                },
+            )?;
+            repaired = true;
+        }
+        Ok(repaired)
+    }
+
+    pub fn pop_until(&mut self, hash: &Sha256Result) -> Result<PopContext, Error> {
+        let until_header = match self.get_header(hash)? {
+            Some(h) => h,
             None => return Err(Error::NotFound(format!("block {}", hash_to_string(&hash)))),
         };
-        let until_height = until_block.header.height;
+        let until_height = until_header.height;
         let mut utxo_to_restore = Vec::new();
         let mut utxo_to_remove = Vec::new();
         let mut txs_to_restore = Vec::new();
@@ -452,8 +1071,8 @@ impl Blockchain {
         let mut blocks = Vec::new();
         let mut hash = *from;
         while hash != *until {
-            let block = match self.get_block(&hash)? {
-                Some(b) => b,
+            let header = match self.get_header(&hash)? {
+                Some(h) => h,
                 None => {
                     return Err(Error::NotFound(format!(
                         "prev of {}",
@@ -462,7 +1081,7 @@ impl Blockchain {
                 }
             };
             blocks.push(hash);
-            hash = block.header.prev_block;
+            hash = header.prev_block;
         }
         blocks.reverse();
         Ok(blocks)
@@ -479,27 +1098,103 @@ impl Blockchain {
         Ok(new_chain)
     }
 
-    pub fn pop_best_block(&mut self) -> Result<PopContext, Error> {
-        let best_block = self.best_block_hash()?;
+    fn spent_utxo(&self, hash: &Sha256Result) -> Result<Vec<PairedUtxo>, Error> {
         let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
-            match self.spent_tx.get(&best_block)? {
+            match self.spent_tx.get(hash)? {
                 Some(b) => (*b).to_owned(),
                 None => {
                     return Err(Error::NotFound(format!(
                         "spent tx {}",
-                        hash_to_string(&best_block)
+                        hash_to_string(hash)
                     )))
                 }
             },
         ));
+        Ok(Vec::deserialize(&mut de)?)
+    }
+
+    /// Links a block that does not extend the best block against the utxo set of its own
+    /// branch: the main chain outputs as they were at the fork point, then the outputs created
+    /// and spent by the side branch up to the parent of the block
+    pub fn link_fork_block(&self, block: &mut LinkedBlock) -> Result<(), Error> {
+        let best_block = self.best_block_hash()?;
+        let common_hash = match self.find_common_hash(best_block, block.header.prev_block)? {
+            Some(h) => h,
+            None => return Err(Error::NotFound("merge point".to_string())),
+        };
+        let mut main_created = HashSet::new();
+        let mut main_spent = HashMap::new();
+        for main_block in self.chain_to_blocks(self.chain_until(&best_block, &common_hash)?)? {
+            for pairedutxo in self.spent_utxo(&main_block.header.double_hash())? {
+                main_spent.insert(pairedutxo.outpoint, pairedutxo.data);
+            }
+            for tx in &main_block.txs {
+                let tx_hash = tx.double_hash();
+                for i in 0..tx.outputs.len() {
+                    main_created.insert(Outpoint {
+                        hash: tx_hash,
+                        index: (i as u32),
+                    });
+                }
+            }
+        }
+        let mut side_created = HashMap::new();
+        let mut side_spent = HashSet::new();
+        let side_chain = self.chain_until(&block.header.prev_block, &common_hash)?;
+        for side_block in self.chain_to_blocks(side_chain)? {
+            for (i, tx) in side_block.txs.iter().enumerate() {
+                for input in &tx.inputs {
+                    side_spent.insert(input.previous_output.clone());
+                }
+                let tx_hash = tx.double_hash();
+                for (index, output) in tx.outputs.iter().enumerate() {
+                    side_created.insert(
+                        Outpoint {
+                            hash: tx_hash,
+                            index: (index as u32),
+                        },
+                        UtxoData {
+                            script: output.script.clone(),
+                            value: output.value,
+                            block_height: side_block.header.height,
+                            coin_base: i == 0,
+                        },
+                    );
+                }
+            }
+        }
+        for ltx in block.txs.iter_mut() {
+            for parent in ltx.unknown().clone() {
+                let data = if side_spent.contains(&parent) {
+                    None
+                } else if let Some(data) = side_created.get(&parent) {
+                    Some(data.clone())
+                } else if main_created.contains(&parent) {
+                    None
+                } else if let Some(data) = main_spent.get(&parent) {
+                    Some(data.clone())
+                } else {
+                    self.utxo.get(&parent).ok()
+                };
+                if let Some(data) = data {
+                    ltx.add_dependency(
+                        parent,
+                        Dependency {
+                            data,
+                            dep_type: DependencyType::Block,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn pop_best_block(&mut self) -> Result<PopContext, Error> {
+        let best_block = self.best_block_hash()?;
+        let utxo_to_restore = self.spent_utxo(&best_block)?;
         let best_block = self.get_block(&best_block)?.unwrap();
-        self.reverse_chain.remove(&best_block.header.prev_block)?;
-        self.unset_best_block()?;
-        self.stats.insert(
-            "best_block",
-            ensicoin_messages::as_bytes(slice(best_block.header.prev_block)),
-        )?;
-        let utxo_to_restore = Vec::deserialize(&mut de)?;
+        self.disconnect(&best_block, &utxo_to_restore)?;
         let mut utxo_to_remove = Vec::new();
         let mut txs_to_restore = Vec::new();
         for tx in best_block.txs {
@@ -519,36 +1214,297 @@ impl Blockchain {
         })
     }
 
+    pub fn median_time_past(&self, hash: &Sha256Result) -> Result<u64, Error> {
+        use crate::constants::MEDIAN_TIME_SPAN;
+
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut hash = *hash;
+        while timestamps.len() < MEDIAN_TIME_SPAN {
+            let header = match self.get_header(&hash)? {
+                Some(h) => h,
+                None => break,
+            };
+            timestamps.push(header.timestamp);
+            if header.height == 0 {
+                break;
+            }
+            hash = header.prev_block;
+        }
+        if timestamps.is_empty() {
+            return Err(Error::NotFound(format!("block {}", hash_to_string(&hash))));
+        }
+        timestamps.sort();
+        Ok(timestamps[timestamps.len() / 2])
+    }
+
+    /// Timestamp of a block built on `previous` at `now`, kept above the median time past when
+    /// several blocks are found in the same second
+    pub fn next_block_timestamp(&self, previous: &Sha256Result, now: u64) -> Result<u64, Error> {
+        Ok(std::cmp::max(now, self.median_time_past(previous)? + 1))
+    }
+
     pub fn get_target_next_block(&self, timestamp: u64) -> Result<BigUint, Error> {
-        use crate::constants::TIME_BEETWEEN_BLOCKS;
+        let best_header = self.get_header(&self.best_block_hash()?)?.unwrap();
+        self.target_after(&best_header, timestamp)
+    }
+
+    /// Target of a block with the given timestamp built on `previous`, which does not need to
+    /// be in the main chain
+    pub fn target_after(&self, previous: &BlockHeader, timestamp: u64) -> Result<BigUint, Error> {
+        let retarget_timespan = self.network.retarget_timespan;
 
-        let best_block = self.get_block(&self.best_block_hash()?)?.unwrap();
-        let mut ancestor = self.get_block(&self.genesis_hash()?)?.unwrap();
-        if best_block.header.height >= 2015 {
+        let mut ancestor = self.get_header(&self.genesis_hash()?)?.unwrap();
+        if !self.network.no_retargeting && previous.height >= self.network.retarget_interval - 1 {
             ancestor = self
-                .get_block(
-                    &self
-                        .block_after(&self.block_2016_before(&self.best_block_hash()?)?)?
-                        .unwrap(),
+                .ancestor_at(
+                    &previous.double_hash(),
+                    previous.height + 2 - self.network.retarget_interval,
                 )?
-                .unwrap();
+                .1;
 
-            let old_target = BigUint::from_bytes_be(&best_block.header.target);
-            let mut time_diff = timestamp - ancestor.header.timestamp;
-            if time_diff > 4 * TIME_BEETWEEN_BLOCKS {
-                time_diff = 4 * TIME_BEETWEEN_BLOCKS
-            } else if time_diff < TIME_BEETWEEN_BLOCKS / 4 {
-                time_diff = TIME_BEETWEEN_BLOCKS / 4
+            let old_target = BigUint::from_bytes_be(&previous.target);
+            let mut time_diff = timestamp.saturating_sub(ancestor.timestamp);
+            if time_diff > 4 * retarget_timespan {
+                time_diff = 4 * retarget_timespan
+            } else if time_diff < retarget_timespan / 4 {
+                time_diff = retarget_timespan / 4
             };
             Ok(std::cmp::min(
-                (old_target * BigUint::from(time_diff)) / BigUint::from(TIME_BEETWEEN_BLOCKS),
+                (old_target * BigUint::from(time_diff)) / BigUint::from(retarget_timespan),
                 (BigUint::from(1 as u64) << 256) - (1 as u64),
             ))
         } else {
-            Ok(BigUint::from_bytes_be(&ancestor.header.target))
+            Ok(BigUint::from_bytes_be(&ancestor.target))
+        }
+    }
+
+    pub fn get_header(&self, hash: &Sha256Result) -> Result<Option<BlockHeader>, Error> {
+        match self.headers.get(&hash)? {
+            Some(b) => {
+                let mut de =
+                    ensicoin_serializer::Deserializer::new(bytes::BytesMut::from((*b).to_owned()));
+                BlockHeader::deserialize(&mut de)
+                    .map(Some)
+                    .map_err(Error::ParseError)
+            }
+            None => Ok(self.get_block(hash)?.map(|b| b.header)),
+        }
+    }
+
+    /// Ancestor at `height` of the block, following the skip pointers when they are stored
+    pub fn ancestor_at(
+        &self,
+        hash: &Sha256Result,
+        height: u32,
+    ) -> Result<(Sha256Result, BlockHeader), Error> {
+        let mut hash = *hash;
+        let mut header = match self.get_header(&hash)? {
+            Some(h) => h,
+            None => return Err(Error::NotFound(format!("header {}", hash_to_string(&hash)))),
+        };
+        if header.height < height {
+            return Err(Error::NotFound(format!(
+                "ancestor at {} of {}",
+                height,
+                hash_to_string(&hash)
+            )));
+        }
+        while header.height > height {
+            let skip = skip_height(header.height);
+            let skip_previous = skip_height(header.height - 1);
+            let take_skip = skip == height
+                || (skip > height && !(skip_previous + 2 < skip && skip_previous >= height));
+            let skip_hash = if take_skip {
+                match self.skip.get(&hash)? {
+                    Some(b) => {
+                        let mut de = ensicoin_serializer::Deserializer::new(bytes::BytesMut::from(
+                            (*b).to_owned(),
+                        ));
+                        Some(Sha256Result::deserialize(&mut de)?)
+                    }
+                    None => None,
+                }
+            } else {
+                None
+            };
+            hash = skip_hash.unwrap_or(header.prev_block);
+            header = match self.get_header(&hash)? {
+                Some(h) => h,
+                None => return Err(Error::NotFound(format!("header {}", hash_to_string(&hash)))),
+            };
         }
+        Ok((hash, header))
     }
 
+    fn get_header_work(&self, hash: &Sha256Result) -> Result<BigUint, Error> {
+        match self.headers.get(&hash)? {
+            Some(b) => {
+                let mut de =
+                    ensicoin_serializer::Deserializer::new(bytes::BytesMut::from((*b).to_owned()));
+                BlockHeader::deserialize(&mut de)?;
+                Vec::deserialize(&mut de)
+                    .map(|b| BigUint::from_bytes_be(&b))
+                    .map_err(Error::ParseError)
+            }
+            None => self.get_work(hash),
+        }
+    }
+
+    fn store_header(&mut self, header: &BlockHeader, chain_work: &BigUint) -> Result<(), Error> {
+        let hash = header.double_hash();
+        if header.height > 0 && !self.skip.contains_key(&hash)? {
+            let (skip, _) = self.ancestor_at(&header.prev_block, skip_height(header.height))?;
+            self.skip
+                .insert(hash, ensicoin_messages::as_bytes(slice(skip)))?;
+        }
+        self.headers.insert(
+            hash,
+            ensicoin_messages::as_bytes(tuple((fn_block_header(header), ser_biguint(chain_work)))),
+        )?;
+        if *chain_work > self.get_header_work(&self.best_header_hash()?)? {
+            self.stats
+                .insert("best_header", ensicoin_messages::as_bytes(slice(hash)))?;
+        }
+        Ok(())
+    }
+
+    pub fn best_header_hash(&self) -> Result<Sha256Result, Error> {
+        match self.stats.get("best_header")? {
+            Some(b) => {
+                let mut de =
+                    ensicoin_serializer::Deserializer::new(bytes::BytesMut::from((*b).to_owned()));
+                Sha256Result::deserialize(&mut de).map_err(Error::ParseError)
+            }
+            None => self.best_block_hash(),
+        }
+    }
+
+    pub fn best_header_height(&self) -> Result<u32, Error> {
+        let best_header_hash = self.best_header_hash()?;
+        match self.get_header(&best_header_hash)? {
+            Some(h) => Ok(h.height),
+            None => Err(Error::NotFound(format!(
+                "header {}",
+                hash_to_string(&best_header_hash)
+            ))),
+        }
+    }
+
+    /// Validates and stores headers in order until one is invalid, returns the hashes of the
+    /// headers whose block is not stored in chain order, known headers included so that a
+    /// download abandoned or interrupted by a restart is resumed, and the failure that stopped
+    /// the batch
+    pub fn add_headers(
+        &mut self,
+        headers: &[BlockHeader],
+    ) -> Result<(Vec<Sha256Result>, Option<ValidationError>), Error> {
+        let mut hashes = Vec::new();
+        for header in headers {
+            let hash = header.double_hash();
+            if self.exists(&hash)? {
+                continue;
+            }
+            if self.headers.contains_key(&hash)? {
+                hashes.push(hash);
+                continue;
+            }
+            let previous = match self.get_header(&header.prev_block)? {
+                Some(h) => h,
+                None => {
+                    return Err(Error::NotFound(format!(
+                        "header {}",
+                        hash_to_string(&header.prev_block)
+                    )))
+                }
+            };
+            let target = self.target_after(&previous, header.timestamp)?;
+            if let Err(e) = crate::consensus::check_header(header, &previous, &target) {
+                return Ok((hashes, Some(e)));
+            }
+            let chain_work = self.get_header_work(&header.prev_block)? + header_work(header);
+            self.store_header(header, &chain_work)?;
+            hashes.push(hash);
+        }
+        Ok((hashes, None))
+    }
+
+    fn in_main_chain(&self, hash: &Sha256Result) -> Result<bool, Error> {
+        Ok(*hash == self.best_block_hash()? || self.reverse_chain.contains_key(hash)?)
+    }
+
+    /// Locator starting at `from`: the last header received to continue the header sync, the
+    /// best block to have a new peer send again the headers whose blocks are still missing
+    pub fn generate_get_headers(
+        &self,
+        from: Sha256Result,
+    ) -> Result<ensicoin_messages::message::GetBlocks, Error> {
+        let mut block_locator = Vec::new();
+        let mut hash = from;
+        let mut step = 1;
+        loop {
+            let mut header = match self.get_header(&hash)? {
+                Some(h) => h,
+                None => break,
+            };
+            block_locator.push(hash);
+            if header.height == 0 {
+                break;
+            }
+            if block_locator.len() >= 10 {
+                step *= 2;
+            }
+            let height = header.height.saturating_sub(step);
+            while header.height > height {
+                hash = header.prev_block;
+                header = match self.get_header(&hash)? {
+                    Some(h) => h,
+                    None => break,
+                };
+            }
+        }
+        let genesis_hash = self.genesis_hash()?;
+        if block_locator.last() != Some(&genesis_hash) {
+            block_locator.push(genesis_hash);
+        }
+        Ok(ensicoin_messages::message::GetBlocks {
+            stop_hash: Sha256Result::from([0 as u8; 32]),
+            block_locator,
+        })
+    }
+
+    pub fn generate_headers(
+        &self,
+        get_headers: &ensicoin_messages::message::GetBlocks,
+    ) -> Result<Vec<BlockHeader>, Error> {
+        use crate::constants::MAX_HEADERS;
+
+        let mut headers = Vec::new();
+        let mut start = None;
+        for hash in &get_headers.block_locator {
+            if self.in_main_chain(hash)? {
+                start = Some(*hash);
+                break;
+            }
+        }
+        let mut hash = match start {
+            Some(h) => h,
+            None => return Ok(headers),
+        };
+        while headers.len() < MAX_HEADERS && hash != get_headers.stop_hash {
+            hash = match self.block_after(&hash)? {
+                Some(h) => h,
+                None => break,
+            };
+            match self.get_header(&hash)? {
+                Some(h) => headers.push(h),
+                None => break,
+            }
+        }
+        Ok(headers)
+    }
+
+    /// Blocks found in the chain and the requested blocks that were pruned, the rest of the
+    /// inventory is returned as is
     pub fn get_data(
         &self,
         inv: Vec<ensicoin_messages::message::InvVect>,
@@ -556,21 +1512,178 @@ impl Blockchain {
         (
             Vec<ensicoin_messages::resource::Block>,
             Vec<ensicoin_messages::message::InvVect>,
+            Vec<ensicoin_messages::message::InvVect>,
         ),
         Error,
     > {
         let mut remaining = Vec::new();
+        let mut pruned = Vec::new();
         let mut blocks = Vec::new();
         for inv_vect in inv {
             match inv_vect.data_type {
                 ensicoin_messages::message::ResourceType::Block => {
-                    if let Some(b) = self.get_block(&inv_vect.hash)? {
-                        blocks.push(b);
+                    match self.get_block(&inv_vect.hash) {
+                        Ok(Some(b)) => blocks.push(b),
+                        Ok(None) => remaining.push(inv_vect),
+                        Err(Error::Pruned(_)) => pruned.push(inv_vect),
+                        Err(e) => return Err(e),
                     }
                 }
                 _ => remaining.push(inv_vect),
             }
         }
-        Ok((blocks, remaining))
+        Ok((blocks, pruned, remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Blockchain, NewAddition};
+    use crate::{
+        consensus::BlockContext,
+        constants::Network,
+        data::linkedblock::LinkedBlock,
+        manager::{DownloadManager, UtxoManager},
+    };
+    use ensicoin_messages::resource::{
+        script::{Script, OP},
+        tx::TransactionOutput,
+        Block, BlockHeader, Transaction,
+    };
+    use ensicoin_serializer::Sha256Result;
+    use num_bigint::BigUint;
+
+    fn open(db: &sled::Db) -> Blockchain {
+        Blockchain::new(db, UtxoManager::new(db).unwrap(), Network::Regtest.params()).unwrap()
+    }
+
+    fn chain() -> (sled::Db, Blockchain) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        crate::bootstrap::init_chainstate(&db, Network::Regtest.params()).unwrap();
+        let blockchain = open(&db);
+        (db, blockchain)
+    }
+
+    /// Headers following `previous` with the proof of work of the regtest target
+    fn mine_headers(previous: &BlockHeader, count: u32) -> Vec<BlockHeader> {
+        let target = Network::Regtest.params().initial_target;
+        let mut headers = Vec::new();
+        let mut previous = previous.clone();
+        for _ in 0..count {
+            let mut header = BlockHeader {
+                version: 0,
+                flags: Vec::new(),
+                prev_block: previous.double_hash(),
+                merkle_root: Sha256Result::from([0; 32]),
+                timestamp: previous.timestamp + 1,
+                nonce: 0,
+                height: previous.height + 1,
+                target: Sha256Result::from(target),
+            };
+            while BigUint::from_bytes_be(&header.double_hash()) >= BigUint::from_bytes_be(&target) {
+                header.nonce += 1;
+            }
+            headers.push(header.clone());
+            previous = header;
+        }
+        headers
+    }
+
+    #[test]
+    fn restart_with_headers_ahead_of_blocks() {
+        let (db, mut blockchain) = chain();
+        let genesis_hash = blockchain.genesis_hash().unwrap();
+        let genesis = blockchain.get_header(&genesis_hash).unwrap().unwrap();
+        let headers = mine_headers(&genesis, 5);
+        let hashes: Vec<_> = headers.iter().map(|h| h.double_hash()).collect();
+        let (added, invalid) = blockchain.add_headers(&headers).unwrap();
+        assert!(invalid.is_none());
+        assert_eq!(added, hashes);
+        drop(blockchain);
+
+        let mut blockchain = open(&db);
+        assert_eq!(blockchain.best_header_hash().unwrap(), hashes[4]);
+        assert_eq!(blockchain.best_block_hash().unwrap(), genesis_hash);
+        let get_headers = blockchain
+            .generate_get_headers(blockchain.best_block_hash().unwrap())
+            .unwrap();
+        assert_eq!(get_headers.block_locator, vec![genesis_hash]);
+
+        // A peer answers the locator with the stored headers, their blocks are downloaded again
+        let (added, invalid) = blockchain.add_headers(&headers).unwrap();
+        assert!(invalid.is_none());
+        assert_eq!(added, hashes);
+        let mut download_manager = DownloadManager::new();
+        download_manager.add_peer(1);
+        download_manager.enqueue(added);
+        download_manager.announced(1, hashes.iter().copied());
+        assert_eq!(download_manager.schedule().remove(&1), Some(hashes));
+    }
+
+    /// Block template on the best block at `now` with only a coinbase, like the miner builds
+    fn mine_block(blockchain: &Blockchain, now: u64) -> LinkedBlock {
+        let best_hash = blockchain.best_block_hash().unwrap();
+        let best = blockchain.get_header(&best_hash).unwrap().unwrap();
+        let height = best.height + 1;
+        let timestamp = blockchain.next_block_timestamp(&best_hash, now).unwrap();
+        let target = blockchain.target_after(&best, timestamp).unwrap();
+        let coinbase = Transaction {
+            version: 0,
+            flags: vec![height.to_string()],
+            inputs: Vec::new(),
+            outputs: vec![TransactionOutput {
+                value: Network::Regtest.params().block_subsidy(height),
+                script: Script::from(vec![OP::True]),
+            }],
+        };
+        let mut block = LinkedBlock::new(Block {
+            header: BlockHeader {
+                version: 0,
+                flags: Vec::new(),
+                prev_block: best_hash,
+                merkle_root: Sha256Result::from([0; 32]),
+                timestamp,
+                nonce: 0,
+                height,
+                target: crate::utils::big_uint_to_hash(target.clone()),
+            },
+            txs: vec![coinbase],
+        });
+        block.header.merkle_root = block.merkle_root();
+        while BigUint::from_bytes_be(&block.header.double_hash()) >= target {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    #[test]
+    fn mines_consecutive_regtest_blocks_in_the_same_second() {
+        let (_db, mut blockchain) = chain();
+        let now = Network::Regtest.params().genesis().header.timestamp;
+        for _ in 0..20 {
+            let mut block = mine_block(&blockchain, now);
+            blockchain.utxo.link_block(&mut block);
+            let previous = blockchain
+                .get_header(&block.header.prev_block)
+                .unwrap()
+                .unwrap();
+            let context = BlockContext {
+                target: blockchain
+                    .target_after(&previous, block.header.timestamp)
+                    .unwrap(),
+                median_time_past: blockchain
+                    .median_time_past(&block.header.prev_block)
+                    .unwrap(),
+                previous,
+                now,
+                network: Network::Regtest.params(),
+            };
+            block.is_valid(&context).unwrap();
+            match blockchain.new_block(block).unwrap() {
+                NewAddition::BestBlock => (),
+                _ => panic!("mined block did not become the best block"),
+            }
+        }
+        assert_eq!(blockchain.best_height().unwrap(), 20);
     }
 }
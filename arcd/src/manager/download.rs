@@ -0,0 +1,326 @@
+use crate::{
+    constants::{BLOCK_DOWNLOAD_TIMEOUT, MAX_BLOCKS_IN_FLIGHT, MAX_BLOCKS_PER_PEER},
+    data::intern_messages::Source,
+    manager::orphan_block::OriginedBlock,
+};
+use ensicoin_messages::resource::Block;
+use ensicoin_serializer::Sha256Result;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+struct Request {
+    peer: u64,
+    since: Instant,
+}
+
+/// Schedules block downloads over the peers that announced the blocks, blocks are requested in
+/// chain order inside a bounded window and handed back in that same order. Blocks no peer can
+/// serve are abandoned so that they do not hold the window back
+pub struct DownloadManager {
+    queue: VecDeque<Sha256Result>,
+    window: VecDeque<Sha256Result>,
+    scheduled: HashSet<Sha256Result>,
+    retry: VecDeque<Sha256Result>,
+    in_flight: HashMap<Sha256Result, Request>,
+    buffered: HashMap<Sha256Result, OriginedBlock>,
+    /// Peers that announced a block, by inv or headers
+    announced: HashMap<Sha256Result, HashSet<u64>>,
+    /// Peers that answered NotFound for a block
+    missing: HashMap<Sha256Result, HashSet<u64>>,
+    /// Blocks no peer could serve, scheduled again once a peer announces them
+    abandoned: HashSet<Sha256Result>,
+    peers: HashMap<u64, usize>,
+}
+
+impl DownloadManager {
+    pub fn new() -> DownloadManager {
+        DownloadManager {
+            queue: VecDeque::new(),
+            window: VecDeque::new(),
+            scheduled: HashSet::new(),
+            retry: VecDeque::new(),
+            in_flight: HashMap::new(),
+            buffered: HashMap::new(),
+            announced: HashMap::new(),
+            missing: HashMap::new(),
+            abandoned: HashSet::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: u64) {
+        self.peers.entry(peer).or_insert(0);
+    }
+
+    /// Forgets a peer, its pending requests will be sent to other peers
+    pub fn remove_peer(&mut self, peer: u64) {
+        self.peers.remove(&peer);
+        for peers in self.missing.values_mut() {
+            peers.remove(&peer);
+        }
+        for peers in self.announced.values_mut() {
+            peers.remove(&peer);
+        }
+        let lost: Vec<_> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| request.peer == peer)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in lost {
+            self.in_flight.remove(&hash);
+            self.retry.push_back(hash);
+        }
+    }
+
+    /// Adds blocks to download, they must be given in chain order
+    pub fn enqueue<I: IntoIterator<Item = Sha256Result>>(&mut self, hashes: I) {
+        for hash in hashes {
+            self.abandoned.remove(&hash);
+            if self.scheduled.insert(hash) {
+                self.queue.push_back(hash);
+            }
+        }
+    }
+
+    /// Records that the peer has these blocks, only blocks still to be downloaded are kept and
+    /// abandoned blocks are scheduled again
+    pub fn announced<I: IntoIterator<Item = Sha256Result>>(&mut self, peer: u64, hashes: I) {
+        for hash in hashes {
+            if self.abandoned.contains(&hash) {
+                self.enqueue(std::iter::once(hash));
+            }
+            if self.scheduled.contains(&hash) {
+                self.announced.entry(hash).or_default().insert(peer);
+            }
+        }
+    }
+
+    /// Connected peers that announced the block and did not answer NotFound for it
+    fn holders<'a>(&'a self, hash: &Sha256Result) -> impl Iterator<Item = (u64, usize)> + 'a {
+        let announced = self.announced.get(hash);
+        let missing = self.missing.get(hash);
+        self.peers
+            .iter()
+            .filter(move |(peer, _)| announced.map_or(false, |a| a.contains(peer)))
+            .filter(move |(peer, _)| missing.map_or(true, |m| !m.contains(peer)))
+            .map(|(peer, count)| (*peer, *count))
+    }
+
+    /// Least busy holder of the block
+    fn least_busy_peer(&self, hash: &Sha256Result) -> Option<u64> {
+        self.holders(hash)
+            .filter(|(_, count)| *count < MAX_BLOCKS_PER_PEER)
+            .min_by_key(|(_, count)| *count)
+            .map(|(peer, _)| peer)
+    }
+
+    /// Stops downloading a block no peer can serve, it is requested again if it is announced
+    /// later
+    fn abandon(&mut self, hash: &Sha256Result) {
+        debug!(
+            "No peer can serve block {}",
+            ensicoin_serializer::hash_to_string(hash)
+        );
+        self.abandoned.insert(*hash);
+        self.scheduled.remove(hash);
+        self.queue.retain(|h| h != hash);
+        self.window.retain(|h| h != hash);
+        self.announced.remove(hash);
+        self.missing.remove(hash);
+    }
+
+    fn assign(
+        &mut self,
+        hash: Sha256Result,
+        peer: u64,
+        requests: &mut HashMap<u64, Vec<Sha256Result>>,
+    ) {
+        self.in_flight.insert(
+            hash,
+            Request {
+                peer,
+                since: Instant::now(),
+            },
+        );
+        *self.peers.entry(peer).or_insert(0) += 1;
+        requests.entry(peer).or_default().push(hash);
+    }
+
+    /// Assigns the next blocks to the least busy peers, returns the hashes to request from each
+    /// peer
+    pub fn schedule(&mut self) -> HashMap<u64, Vec<Sha256Result>> {
+        let mut requests: HashMap<u64, Vec<Sha256Result>> = HashMap::new();
+        let retries: Vec<_> = self.retry.drain(..).collect();
+        for hash in retries {
+            match self.least_busy_peer(&hash) {
+                Some(peer) => self.assign(hash, peer, &mut requests),
+                None if self.holders(&hash).next().is_none() => self.abandon(&hash),
+                None => self.retry.push_back(hash),
+            }
+        }
+        while self.window.len() < MAX_BLOCKS_IN_FLIGHT {
+            let hash = match self.queue.front() {
+                Some(hash) => *hash,
+                None => break,
+            };
+            let peer = match self.least_busy_peer(&hash) {
+                Some(peer) => peer,
+                None if self.holders(&hash).next().is_none() => {
+                    self.abandon(&hash);
+                    continue;
+                }
+                None => break,
+            };
+            self.queue.pop_front();
+            self.window.push_back(hash);
+            self.assign(hash, peer, &mut requests);
+        }
+        requests
+    }
+
+    /// Blocks at the front of the window that can be validated, abandoned blocks are skipped
+    pub fn ready(&mut self) -> Vec<OriginedBlock> {
+        let mut ready = Vec::new();
+        while let Some(front) = self.window.front().copied() {
+            if !self.scheduled.contains(&front) {
+                self.window.pop_front();
+                continue;
+            }
+            match self.buffered.remove(&front) {
+                Some(origined) => {
+                    self.scheduled.remove(&front);
+                    self.announced.remove(&front);
+                    self.window.pop_front();
+                    ready.push(origined);
+                }
+                None => break,
+            }
+        }
+        ready
+    }
+
+    /// The peer does not have these blocks, they will be requested from another peer that
+    /// announced them or abandoned on the next schedule if there is none
+    pub fn not_found(&mut self, peer: u64, hashes: &[Sha256Result]) {
+        for hash in hashes {
+            match self.in_flight.get(hash) {
+                Some(request) if request.peer == peer => (),
+                _ => continue,
+            }
+            self.in_flight.remove(hash);
+            if let Some(count) = self.peers.get_mut(&peer) {
+                *count = count.saturating_sub(1);
+            }
+            self.missing.entry(*hash).or_default().insert(peer);
+            self.retry.push_back(*hash);
+        }
+    }
+
+    /// Registers a received block and returns the blocks that can be validated, in chain order
+    pub fn received(&mut self, source: Source, block: Block) -> Vec<OriginedBlock> {
+        let hash = block.double_hash();
+        if let Some(request) = self.in_flight.remove(&hash) {
+            if let Some(count) = self.peers.get_mut(&request.peer) {
+                *count = count.saturating_sub(1);
+            }
+        }
+        self.retry.retain(|h| *h != hash);
+        self.missing.remove(&hash);
+        self.abandoned.remove(&hash);
+        if !self.scheduled.contains(&hash) {
+            return vec![(source, block)];
+        }
+        if !self.window.contains(&hash) {
+            self.queue.retain(|h| *h != hash);
+            self.scheduled.remove(&hash);
+            self.announced.remove(&hash);
+            return vec![(source, block)];
+        }
+        self.buffered.insert(hash, (source, block));
+        self.ready()
+    }
+
+    /// Requests older than the timeout are rescheduled, returns the peers that stalled: the
+    /// ones that announced a block and did not deliver it in time
+    pub fn timeouts(&mut self) -> Vec<u64> {
+        let timeout = Duration::from_secs(BLOCK_DOWNLOAD_TIMEOUT);
+        let expired: Vec<_> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| request.since.elapsed() > timeout)
+            .map(|(hash, request)| (*hash, request.peer))
+            .collect();
+        let mut stalled = Vec::new();
+        for (hash, peer) in expired {
+            if self
+                .announced
+                .get(&hash)
+                .map_or(false, |peers| peers.contains(&peer))
+            {
+                stalled.push(peer);
+            } else {
+                self.in_flight.remove(&hash);
+                if let Some(count) = self.peers.get_mut(&peer) {
+                    *count = count.saturating_sub(1);
+                }
+                self.retry.push_back(hash);
+            }
+        }
+        stalled.sort();
+        stalled.dedup();
+        for peer in &stalled {
+            self.remove_peer(*peer);
+        }
+        stalled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DownloadManager;
+    use crate::data::intern_messages::Source;
+    use ensicoin_messages::resource::{Block, BlockHeader};
+    use ensicoin_serializer::Sha256Result;
+
+    fn block(nonce: u64) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 0,
+                flags: Vec::new(),
+                prev_block: Sha256Result::from([0; 32]),
+                merkle_root: Sha256Result::from([0; 32]),
+                timestamp: 0,
+                nonce,
+                height: 1,
+                target: Sha256Result::from([0xff; 32]),
+            },
+            txs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn abandoned_block_is_downloaded_when_announced_again() {
+        let block = block(0);
+        let hash = block.double_hash();
+        let mut download_manager = DownloadManager::new();
+        download_manager.add_peer(1);
+        download_manager.enqueue(vec![hash]);
+        download_manager.announced(1, vec![hash]);
+        assert_eq!(download_manager.schedule().remove(&1), Some(vec![hash]));
+
+        download_manager.not_found(1, &[hash]);
+        assert!(download_manager.schedule().is_empty());
+        assert!(download_manager.abandoned.contains(&hash));
+
+        download_manager.add_peer(2);
+        download_manager.announced(2, vec![hash]);
+        assert_eq!(download_manager.schedule().remove(&2), Some(vec![hash]));
+        let ready = download_manager.received(Source::Server, block);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].1.double_hash(), hash);
+        assert!(download_manager.abandoned.is_empty());
+    }
+}
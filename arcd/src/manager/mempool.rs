@@ -1,21 +1,62 @@
-use crate::data::{
-    linkedblock::LinkedBlock,
-    linkedtx::{Dependency, DependencyType, LinkedTransaction},
-    UtxoData,
-};
-#[cfg(not(feature = "grpc"))]
 use crate::Error;
-use ensicoin_messages::resource::Outpoint;
-use ensicoin_serializer::Sha256Result;
-use std::collections::HashMap;
+use crate::{
+    consensus::{self, ValidationError},
+    constants::{
+        INCREMENTAL_FEE_RATE, MAX_BLOCK_SIZE, MAX_MEMPOOL_BYTES, MAX_ORPHANS_PER_PEER,
+        MAX_ORPHAN_BYTES, MEMPOOL_LISTENER_CAPACITY, MEMPOOL_MIN_FEE_HALF_LIFE, ORPHAN_EXPIRY,
+    },
+    data::{
+        linkedblock::LinkedBlock,
+        linkedtx::{Dependency, DependencyType, LinkedTransaction},
+        PairedUtxo, UtxoData,
+    },
+};
+use ensicoin_messages::resource::{tx::fn_tx, Outpoint, Transaction};
+use ensicoin_serializer::{
+    hash_to_string, serializer::fn_list, Deserialize, Deserializer, Sha256Result,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use tokio::sync::mpsc;
 
 type Dep = (Sha256Result, Outpoint);
 
+/// Fee in thousandths of a unit per byte
+type FeeRate = u64;
+
+fn tx_size(tx: &Transaction) -> usize {
+    ensicoin_messages::as_bytes(fn_tx(tx)).len()
+}
+
+fn fee_rate(fee: u64, size: usize) -> FeeRate {
+    fee.saturating_mul(1000) / std::cmp::max(size, 1) as u64
+}
+
+struct OrphanInfo {
+    peer: Option<u64>,
+    received: u64,
+}
+
 pub struct Mempool {
     pool: HashMap<Sha256Result, LinkedTransaction>,
     orphan: HashMap<Sha256Result, LinkedTransaction>,
 
     dependencies: HashMap<Sha256Result, Vec<Dep>>,
+    spent: HashMap<Outpoint, Sha256Result>,
+
+    sizes: HashMap<Sha256Result, usize>,
+    by_fee_rate: BTreeSet<(FeeRate, Sha256Result)>,
+    pool_bytes: usize,
+    max_pool_bytes: usize,
+    /// Raised when the pool is full, it then decays with `MEMPOOL_MIN_FEE_HALF_LIFE`
+    min_fee_rate: FeeRate,
+    min_fee_rate_updated: u64,
+    orphan_info: HashMap<Sha256Result, OrphanInfo>,
+    orphan_bytes: usize,
+
+    height: u32,
+    coinbase_maturity: u32,
+
+    listeners: Vec<mpsc::Sender<Transaction>>,
 }
 
 impl Mempool {
@@ -29,21 +70,471 @@ impl Mempool {
         self
     }
 
-    pub fn new() -> Mempool {
+    pub fn new(coinbase_maturity: u32) -> Mempool {
         Mempool {
             pool: HashMap::new(),
             orphan: HashMap::new(),
 
             dependencies: HashMap::new(),
+            spent: HashMap::new(),
+
+            sizes: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            pool_bytes: 0,
+            max_pool_bytes: MAX_MEMPOOL_BYTES,
+            min_fee_rate: 0,
+            min_fee_rate_updated: 0,
+            orphan_info: HashMap::new(),
+            orphan_bytes: 0,
+
+            height: 0,
+            coinbase_maturity,
+
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Receives every transaction accepted in the pool from now on
+    pub fn subscribe(&mut self) -> mpsc::Receiver<Transaction> {
+        let (sender, receiver) = mpsc::channel(MEMPOOL_LISTENER_CAPACITY);
+        self.listeners.push(sender);
+        receiver
+    }
+
+    /// Listeners that are gone are dropped, lagging ones miss the transaction
+    fn notify(&mut self, tx: &Transaction) {
+        let mut listeners = Vec::with_capacity(self.listeners.len());
+        for mut listener in self.listeners.drain(..) {
+            match listener.try_send(tx.clone()) {
+                Ok(()) => listeners.push(listener),
+                Err(e) if e.is_full() => {
+                    warn!("Mempool listener lagging behind, dropped a tx");
+                    listeners.push(listener);
+                }
+                Err(_) => (),
+            }
+        }
+        self.listeners = listeners;
+    }
+
+    pub fn set_height(&mut self, height: u32) {
+        self.height = height;
+    }
+
+    /// The output is not spent by a pool transaction and can be spent in the next block
+    pub fn can_spend(&self, coin: &PairedUtxo) -> bool {
+        !self.spent.contains_key(&coin.outpoint)
+            && consensus::is_mature(&coin.data, self.height + 1, self.coinbase_maturity)
+    }
+
+    /// Fee rate a transaction must pay to enter the pool, zero unless the pool was full
+    /// recently
+    fn min_fee_rate(&self) -> FeeRate {
+        if self.min_fee_rate == 0 {
+            return 0;
+        }
+        let elapsed = crate::utils::timestamp().saturating_sub(self.min_fee_rate_updated);
+        let halvings = elapsed as f64 / MEMPOOL_MIN_FEE_HALF_LIFE as f64;
+        let rate = (self.min_fee_rate as f64 / 2f64.powf(halvings)) as FeeRate;
+        if rate < INCREMENTAL_FEE_RATE / 2 {
+            0
+        } else {
+            rate
+        }
+    }
+
+    fn validate(&self, linked_tx: &LinkedTransaction) -> Result<(), ValidationError> {
+        linked_tx.is_valid()?;
+        consensus::check_maturity(linked_tx, self.height + 1, self.coinbase_maturity)
+    }
+
+    pub fn evict_immature(&mut self) {
+        let immature: Vec<_> = self
+            .pool
+            .iter()
+            .filter(|(_, ltx)| {
+                consensus::check_maturity(ltx, self.height + 1, self.coinbase_maturity).is_err()
+            })
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in immature {
+            info!("Evicting immature tx {}", hash_to_string(&hash));
+            self.remove_with_descendants(&hash);
+        }
+    }
+
+    fn remove_with_descendants(&mut self, hash: &Sha256Result) {
+        let linked_tx = match self.remove_from_pool(hash) {
+            Some(ltx) => ltx,
+            None => return,
+        };
+        for index in 0..linked_tx.transaction.outputs.len() {
+            let outpoint = Outpoint {
+                hash: *hash,
+                index: index as u32,
+            };
+            if let Some(child) = self.spent.get(&outpoint).copied() {
+                self.remove_with_descendants(&child);
+            }
         }
     }
 
     pub fn remove_tx(&mut self, block: &LinkedBlock) {
-        for tx in &block.txs {
-            self.pool.remove(&tx.transaction.double_hash());
+        for ltx in &block.txs {
+            self.remove_from_pool(&ltx.transaction.double_hash());
+            for input in &ltx.transaction.inputs {
+                if let Some(conflict) = self.spent.get(&input.previous_output).copied() {
+                    info!(
+                        "Evicting tx {} conflicting with block",
+                        hash_to_string(&conflict)
+                    );
+                    self.remove_with_descendants(&conflict);
+                }
+            }
+        }
+    }
+
+    fn conflicts(&self, linked_tx: &LinkedTransaction) -> HashSet<Sha256Result> {
+        linked_tx
+            .transaction
+            .inputs
+            .iter()
+            .filter_map(|input| self.spent.get(&input.previous_output))
+            .copied()
+            .collect()
+    }
+
+    fn accept(&mut self, hash: Sha256Result, linked_tx: LinkedTransaction) {
+        if let Err(e) = self.validate(&linked_tx) {
+            warn!("Invalid tx processed {}: {}", hash_to_string(&hash), e);
+            return;
+        }
+        let size = tx_size(&linked_tx.transaction);
+        let rate = fee_rate(linked_tx.fee().unwrap_or(0), size);
+        if rate < self.min_fee_rate() {
+            debug!(
+                "Tx {} pays less than the mempool minimum fee rate",
+                hash_to_string(&hash)
+            );
+            return;
+        }
+        let conflicts = self.conflicts(&linked_tx);
+        if !conflicts.is_empty() {
+            let evicted: HashSet<_> = conflicts
+                .iter()
+                .flat_map(|conflict| self.descendants(conflict))
+                .collect();
+            if linked_tx
+                .transaction
+                .inputs
+                .iter()
+                .any(|input| evicted.contains(&input.previous_output.hash))
+            {
+                warn!(
+                    "Tx {} spends an output of a tx it replaces",
+                    hash_to_string(&hash)
+                );
+                return;
+            }
+            // The replacement pays for the evicted txs and for its own relay
+            let required_fee = evicted
+                .iter()
+                .filter_map(|evicted| self.pool.get(evicted))
+                .filter_map(LinkedTransaction::fee)
+                .fold(0, u64::saturating_add)
+                .saturating_add(INCREMENTAL_FEE_RATE.saturating_mul(size as u64) / 1000);
+            if linked_tx.fee().unwrap_or(0) < required_fee {
+                warn!(
+                    "Tx {} double spends the mempool without paying enough fees",
+                    hash_to_string(&hash)
+                );
+                return;
+            }
+            for conflict in conflicts {
+                info!(
+                    "Tx {} replaced by {}",
+                    hash_to_string(&conflict),
+                    hash_to_string(&hash)
+                );
+                self.remove_with_descendants(&conflict);
+            }
+        }
+        for input in &linked_tx.transaction.inputs {
+            self.spent.insert(input.previous_output.clone(), hash);
+        }
+        self.by_fee_rate.insert((rate, hash));
+        self.sizes.insert(hash, size);
+        self.pool_bytes += size;
+        self.pool.insert(hash, linked_tx);
+        self.trim_pool();
+        // Trimming a full pool can evict the transaction straight away
+        if !self.pool.contains_key(&hash) {
+            return;
+        }
+        if !self.listeners.is_empty() {
+            let tx = self.pool[&hash].transaction.clone();
+            self.notify(&tx);
+        }
+        self.added_parent_to_pool(hash);
+    }
+
+    /// The transaction and every pool transaction spending its outputs, recursively
+    fn descendants(&self, hash: &Sha256Result) -> HashSet<Sha256Result> {
+        let mut descendants = HashSet::new();
+        let mut to_visit = vec![*hash];
+        while let Some(hash) = to_visit.pop() {
+            let ltx = match self.pool.get(&hash) {
+                Some(ltx) => ltx,
+                None => continue,
+            };
+            if !descendants.insert(hash) {
+                continue;
+            }
+            for index in 0..ltx.transaction.outputs.len() {
+                let outpoint = Outpoint {
+                    hash,
+                    index: index as u32,
+                };
+                if let Some(child) = self.spent.get(&outpoint) {
+                    to_visit.push(*child);
+                }
+            }
+        }
+        descendants
+    }
+
+    /// Fee rate of the transaction with its descendants, or its own if higher: a parent paid
+    /// for by its children is not evicted before them
+    fn descendant_score(&self, hash: &Sha256Result, own_rate: FeeRate) -> FeeRate {
+        let (fees, size) = self
+            .descendants(hash)
+            .iter()
+            .filter_map(|h| Some((self.pool.get(h)?.fee().unwrap_or(0), *self.sizes.get(h)?)))
+            .fold((0u64, 0usize), |(fees, size), (fee, s)| {
+                (fees.saturating_add(fee), size + s)
+            });
+        std::cmp::max(own_rate, fee_rate(fees, size))
+    }
+
+    /// Evicts the transactions with the lowest descendant score, with their descendants, until
+    /// the pool fits. The minimum fee rate is raised above the evicted packages
+    fn trim_pool(&mut self) {
+        while self.pool_bytes > self.max_pool_bytes {
+            let mut lowest: Option<(FeeRate, Sha256Result)> = None;
+            for (rate, hash) in &self.by_fee_rate {
+                // A descendant score is never under the own fee rate
+                if let Some((score, _)) = lowest {
+                    if *rate >= score {
+                        break;
+                    }
+                }
+                let score = self.descendant_score(hash, *rate);
+                if lowest.map(|(s, _)| score < s).unwrap_or(true) {
+                    lowest = Some((score, *hash));
+                }
+            }
+            let (score, hash) = match lowest {
+                Some(l) => l,
+                None => break,
+            };
+            debug!("Mempool full, evicting {}", hash_to_string(&hash));
+            self.remove_with_descendants(&hash);
+            self.min_fee_rate = std::cmp::max(
+                self.min_fee_rate(),
+                score.saturating_add(INCREMENTAL_FEE_RATE),
+            );
+            self.min_fee_rate_updated = crate::utils::timestamp();
+        }
+    }
+
+    fn remove_from_pool(&mut self, hash: &Sha256Result) -> Option<LinkedTransaction> {
+        let linked_tx = self.pool.remove(hash)?;
+        for input in &linked_tx.transaction.inputs {
+            if self.spent.get(&input.previous_output) == Some(hash) {
+                self.spent.remove(&input.previous_output);
+            }
+        }
+        if let Some(size) = self.sizes.remove(hash) {
+            self.pool_bytes -= size;
+            self.by_fee_rate
+                .remove(&(fee_rate(linked_tx.fee().unwrap_or(0), size), *hash));
+        }
+        Some(linked_tx)
+    }
+
+    fn remove_orphan(&mut self, hash: &Sha256Result) -> Option<LinkedTransaction> {
+        let orphan = self.orphan.remove(hash)?;
+        self.orphan_info.remove(hash);
+        if let Some(size) = self.sizes.remove(hash) {
+            self.orphan_bytes -= size;
+        }
+        self.remove_orphan_links(hash, &orphan);
+        Some(orphan)
+    }
+
+    fn insert_orphan(
+        &mut self,
+        hash: Sha256Result,
+        linked_tx: LinkedTransaction,
+        peer: Option<u64>,
+    ) {
+        if peer.is_some()
+            && self
+                .orphan_info
+                .values()
+                .filter(|info| info.peer == peer)
+                .count()
+                >= MAX_ORPHANS_PER_PEER
+        {
+            warn!(
+                "Too many orphans from the same peer, dropping {}",
+                hash_to_string(&hash)
+            );
+            self.remove_orphan_links(&hash, &linked_tx);
+            return;
+        }
+        let size = tx_size(&linked_tx.transaction);
+        self.sizes.insert(hash, size);
+        self.orphan_bytes += size;
+        self.orphan_info.insert(
+            hash,
+            OrphanInfo {
+                peer,
+                received: crate::utils::timestamp(),
+            },
+        );
+        self.orphan.insert(hash, linked_tx);
+        while self.orphan_bytes > MAX_ORPHAN_BYTES {
+            let oldest = match self
+                .orphan_info
+                .iter()
+                .min_by_key(|(_, info)| info.received)
+            {
+                Some((hash, _)) => *hash,
+                None => break,
+            };
+            debug!("Orphan set full, evicting {}", hash_to_string(&oldest));
+            self.remove_orphan(&oldest);
         }
     }
 
+    /// Forgets the dependencies registered by `link` for a rejected orphan
+    fn remove_orphan_links(&mut self, hash: &Sha256Result, linked_tx: &LinkedTransaction) {
+        for parent in linked_tx.unknown() {
+            if let Some(deps) = self.dependencies.get_mut(&parent.hash) {
+                deps.retain(|(orphan_hash, _)| orphan_hash != hash);
+                if deps.is_empty() {
+                    self.dependencies.remove(&parent.hash);
+                }
+            }
+        }
+    }
+
+    /// Removes the orphans whose parents did not show up in time
+    pub fn expire_orphans(&mut self) {
+        let now = crate::utils::timestamp();
+        let expired: Vec<_> = self
+            .orphan_info
+            .iter()
+            .filter(|(_, info)| info.received + ORPHAN_EXPIRY < now)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            debug!("Orphan {} expired", hash_to_string(&hash));
+            self.remove_orphan(&hash);
+        }
+    }
+
+    /// Writes the pool to `path`, parents before their children
+    pub fn save(&self, path: &std::path::Path) -> Result<(), Error> {
+        let mut included = HashSet::new();
+        let mut ordered = Vec::new();
+        for hash in self.pool.keys() {
+            let mut package = Vec::new();
+            self.push_with_ancestors(hash, &mut included, &mut package);
+            included.extend(package.iter().copied());
+            ordered.extend(package);
+        }
+        let bytes = ensicoin_messages::as_bytes(fn_list(
+            ordered.len() as u64,
+            ordered
+                .iter()
+                .map(|hash| fn_tx(&self.pool[hash].transaction)),
+        ));
+        let mut tmp = path.to_path_buf();
+        tmp.set_extension("tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(tmp, path)?;
+        debug!("Saved {} transactions from the mempool", ordered.len());
+        Ok(())
+    }
+
+    /// Reads the transactions written by `save`, they still have to be validated
+    pub fn load(path: &std::path::Path) -> Result<Vec<Transaction>, Error> {
+        let content = match std::fs::read(path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::from(e)),
+        };
+        let mut de = Deserializer::new(bytes::BytesMut::from(content));
+        Ok(Vec::deserialize(&mut de)?)
+    }
+
+    /// Drops every orphan
+    pub fn clear_orphans(&mut self) {
+        let orphans: Vec<_> = self.orphan.keys().copied().collect();
+        for hash in &orphans {
+            self.remove_orphan(hash);
+        }
+    }
+
+    /// Adds `hash` and its pool ancestors that are not included yet to the package, parents
+    /// first
+    fn push_with_ancestors(
+        &self,
+        hash: &Sha256Result,
+        included: &mut HashSet<Sha256Result>,
+        package: &mut Vec<Sha256Result>,
+    ) {
+        if included.contains(hash) || package.contains(hash) {
+            return;
+        }
+        let ltx = match self.pool.get(hash) {
+            Some(ltx) => ltx,
+            None => return,
+        };
+        for input in &ltx.transaction.inputs {
+            self.push_with_ancestors(&input.previous_output.hash, included, package);
+        }
+        package.push(*hash);
+    }
+
+    /// Transactions for a new block and their fees, picked by descending fee rate with
+    /// parents before their children
+    pub fn block_template(&self) -> (Vec<Transaction>, u64) {
+        let mut included = HashSet::new();
+        let mut txs = Vec::new();
+        let mut fees: u64 = 0;
+        // Room left for the header and the coinbase
+        let mut space = MAX_BLOCK_SIZE - 1_000;
+        for (_, hash) in self.by_fee_rate.iter().rev() {
+            let mut package = Vec::new();
+            self.push_with_ancestors(hash, &mut included, &mut package);
+            let size: usize = package.iter().filter_map(|h| self.sizes.get(h)).sum();
+            if size > space {
+                continue;
+            }
+            space -= size;
+            for h in package {
+                let ltx = &self.pool[&h];
+                fees = fees.saturating_add(ltx.fee().unwrap_or(0));
+                txs.push(ltx.transaction.clone());
+                included.insert(h);
+            }
+        }
+        (txs, fees)
+    }
+
     pub fn get_tx(&self) -> Vec<ensicoin_messages::resource::Transaction> {
         self.pool
             .values()
@@ -59,30 +550,54 @@ impl Mempool {
     }
 
     fn added_parent_to_pool(&mut self, hash_tx: Sha256Result) {
-        if let Some(dependencies) = self.dependencies.get(&hash_tx).cloned() {
+        if let Some(dependencies) = self.dependencies.remove(&hash_tx) {
             for (orphan_hash, outpoint) in dependencies {
-                self.orphan.get_mut(&orphan_hash).unwrap().add_dependency(
+                let output = self
+                    .pool
+                    .get(&hash_tx)
+                    .and_then(|parent| parent.transaction.outputs.get(outpoint.index as usize));
+                let data = match output {
+                    Some(output) => UtxoData::from_output(output, false, 0),
+                    None => {
+                        warn!(
+                            "Orphan {} spends a missing output of {}",
+                            hash_to_string(&orphan_hash),
+                            hash_to_string(&hash_tx)
+                        );
+                        self.remove_orphan(&orphan_hash);
+                        continue;
+                    }
+                };
+                let orphan = match self.orphan.get_mut(&orphan_hash) {
+                    Some(o) => o,
+                    None => continue,
+                };
+                orphan.add_dependency(
                     outpoint.clone(),
                     Dependency {
                         dep_type: DependencyType::Mempool,
-                        data: UtxoData::from_output(
-                            &self.pool.get(&hash_tx).unwrap().transaction.outputs
-                                [outpoint.index as usize],
-                            false,
-                            0,
-                        ),
+                        data,
                     },
                 );
                 if self.orphan.get(&orphan_hash).unwrap().is_complete() {
-                    self.pool
-                        .insert(orphan_hash, self.orphan.remove(&orphan_hash).unwrap());
-                    self.added_parent_to_pool(orphan_hash);
+                    let orphan = self.remove_orphan(&orphan_hash).unwrap();
+                    self.accept(orphan_hash, orphan);
                 }
             }
         }
     }
 
-    fn link(&mut self, linked_tx: &mut LinkedTransaction) {
+    /// Links the inputs spending pool transactions and registers the missing parents, returns
+    /// false without linking anything if an input spends an output a pool parent does not have
+    fn link(&mut self, linked_tx: &mut LinkedTransaction) -> bool {
+        let spends_missing_output = linked_tx.unknown().iter().any(|parent| {
+            self.pool.get(&parent.hash).map_or(false, |parent_tx| {
+                parent_tx.transaction.outputs.len() <= parent.index as usize
+            })
+        });
+        if spends_missing_output {
+            return false;
+        }
         for parent in linked_tx.unknown().clone() {
             match self.pool.get(&parent.hash) {
                 Some(parent_tx) => {
@@ -110,8 +625,10 @@ impl Mempool {
                 }
             }
         }
+        true
     }
 
+    /// Transactions found in the pool, the rest of the inventory is returned as is
     pub fn get_data(
         &self,
         inv: Vec<ensicoin_messages::message::InvVect>,
@@ -124,8 +641,9 @@ impl Mempool {
         for inv_vect in inv {
             match inv_vect.data_type {
                 ensicoin_messages::message::ResourceType::Transaction => {
-                    if let Some(tx) = self.get_tx_by_hash(&inv_vect.hash) {
-                        txs.push(tx);
+                    match self.get_tx_by_hash(&inv_vect.hash) {
+                        Some(tx) => txs.push(tx),
+                        None => remaining.push(inv_vect),
                     }
                 }
                 _ => remaining.push(inv_vect),
@@ -158,28 +676,280 @@ impl Mempool {
         (unknown, remaining)
     }
 
-    pub fn insert(&mut self, mut linked_tx: LinkedTransaction) {
-        self.link(&mut linked_tx);
+    /// Inserts a transaction relayed by `peer`, it is kept as an orphan until its parents are
+    /// known
+    pub fn insert(&mut self, mut linked_tx: LinkedTransaction, peer: Option<u64>) {
         let hash = linked_tx.transaction.double_hash();
+        if self.pool.contains_key(&hash) || self.orphan.contains_key(&hash) {
+            return;
+        }
+        if !self.link(&mut linked_tx) {
+            warn!("Tx {} spends a missing output", hash_to_string(&hash));
+            return;
+        }
         if linked_tx.is_complete() {
-            if linked_tx.is_valid().unwrap() {
-                self.pool.insert(hash, linked_tx);
-                self.added_parent_to_pool(hash);
-            } else {
-                warn!(
-                    "Invalid tx processed: {}",
-                    linked_tx
-                        .transaction
-                        .double_hash()
-                        .iter()
-                        .fold(String::new(), |mut acc, b| {
-                            acc.push_str(&format!("{:x}", b));
-                            acc
-                        })
-                );
-            }
+            self.accept(hash, linked_tx);
         } else {
-            self.orphan.insert(hash, linked_tx);
+            self.insert_orphan(hash, linked_tx, peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fee_rate, tx_size, Mempool};
+    use crate::{
+        constants::MAX_ORPHANS_PER_PEER,
+        data::{
+            linkedtx::{Dependency, DependencyType, LinkedTransaction},
+            UtxoData,
+        },
+    };
+    use ensicoin_messages::resource::{
+        script::{Script, OP},
+        tx::{TransactionInput, TransactionOutput},
+        Outpoint, Transaction,
+    };
+    use ensicoin_serializer::Sha256Result;
+
+    const COIN_VALUE: u64 = 100_000;
+
+    fn chain_coin(index: u32) -> Outpoint {
+        Outpoint {
+            hash: Sha256Result::from([7; 32]),
+            index,
+        }
+    }
+
+    /// Spends the outpoints to anyone-can-spend outputs
+    fn tx(inputs: &[Outpoint], values: &[u64]) -> Transaction {
+        Transaction {
+            version: 0,
+            flags: Vec::new(),
+            inputs: inputs
+                .iter()
+                .map(|outpoint| TransactionInput {
+                    previous_output: outpoint.clone(),
+                    script: Script::from(Vec::new()),
+                })
+                .collect(),
+            outputs: values
+                .iter()
+                .map(|&value| TransactionOutput {
+                    value,
+                    script: Script::from(vec![OP::True]),
+                })
+                .collect(),
         }
     }
+
+    fn output(tx: &Transaction, index: u32) -> Outpoint {
+        Outpoint {
+            hash: tx.double_hash(),
+            index,
+        }
+    }
+
+    /// Inserts the transaction with its inputs from the chain already linked
+    fn insert(mempool: &mut Mempool, tx: &Transaction, chain_inputs: &[Outpoint], peer: u64) {
+        let mut ltx = LinkedTransaction::new(tx.clone());
+        for outpoint in chain_inputs {
+            ltx.add_dependency(
+                outpoint.clone(),
+                Dependency {
+                    dep_type: DependencyType::Block,
+                    data: UtxoData {
+                        script: Script::from(vec![OP::True]),
+                        value: COIN_VALUE,
+                        block_height: 1,
+                        coin_base: false,
+                    },
+                },
+            );
+        }
+        mempool.insert(ltx, Some(peer));
+    }
+
+    fn contains(mempool: &Mempool, tx: &Transaction) -> bool {
+        mempool.get_tx_by_hash(&tx.double_hash()).is_some()
+    }
+
+    /// A low fee parent with a high fee child and an unrelated medium fee transaction
+    fn cpfp_package() -> (Transaction, Transaction, Transaction) {
+        let parent = tx(&[chain_coin(0)], &[COIN_VALUE - 10]);
+        let child = tx(&[output(&parent, 0)], &[COIN_VALUE - 10 - 50_000]);
+        let other = tx(&[chain_coin(1)], &[COIN_VALUE - 5_000]);
+        (parent, child, other)
+    }
+
+    #[test]
+    fn pool_limit_evicts_lowest_package_score() {
+        let (parent, child, other) = cpfp_package();
+        let mut mempool = Mempool::new(100);
+        insert(&mut mempool, &parent, &[chain_coin(0)], 1);
+        insert(&mut mempool, &child, &[], 1);
+        mempool.max_pool_bytes = mempool.pool_bytes + tx_size(&other) - 1;
+        insert(&mut mempool, &other, &[chain_coin(1)], 1);
+
+        assert!(contains(&mempool, &parent));
+        assert!(contains(&mempool, &child));
+        assert!(!contains(&mempool, &other));
+        assert!(mempool.pool_bytes <= mempool.max_pool_bytes);
+    }
+
+    #[test]
+    fn pool_limit_raises_min_fee_rate() {
+        let (parent, child, other) = cpfp_package();
+        let mut mempool = Mempool::new(100);
+        assert_eq!(mempool.min_fee_rate(), 0);
+        insert(&mut mempool, &parent, &[chain_coin(0)], 1);
+        insert(&mut mempool, &child, &[], 1);
+        mempool.max_pool_bytes = mempool.pool_bytes + tx_size(&other) - 1;
+        insert(&mut mempool, &other, &[chain_coin(1)], 1);
+
+        assert!(mempool.min_fee_rate() > fee_rate(5_000, tx_size(&other)));
+        mempool.max_pool_bytes = std::usize::MAX;
+        let cheap = tx(&[chain_coin(2)], &[COIN_VALUE - 5_000]);
+        insert(&mut mempool, &cheap, &[chain_coin(2)], 1);
+        assert!(!contains(&mempool, &cheap));
+        let rich = tx(&[chain_coin(3)], &[COIN_VALUE / 2]);
+        insert(&mut mempool, &rich, &[chain_coin(3)], 1);
+        assert!(contains(&mempool, &rich));
+    }
+
+    #[test]
+    fn orphans_expire() {
+        let parent = tx(&[chain_coin(0)], &[COIN_VALUE - 10]);
+        let orphan = tx(&[output(&parent, 0)], &[COIN_VALUE - 20]);
+        let mut mempool = Mempool::new(100);
+        insert(&mut mempool, &orphan, &[], 1);
+        assert!(!contains(&mempool, &orphan));
+
+        mempool.expire_orphans();
+        assert_eq!(mempool.orphan.len(), 1);
+        for info in mempool.orphan_info.values_mut() {
+            info.received = 0;
+        }
+        mempool.expire_orphans();
+        assert!(mempool.orphan.is_empty());
+        assert_eq!(mempool.orphan_bytes, 0);
+
+        insert(&mut mempool, &parent, &[chain_coin(0)], 1);
+        assert!(!contains(&mempool, &orphan));
+    }
+
+    #[test]
+    fn orphans_are_accepted_with_their_parent() {
+        let parent = tx(&[chain_coin(0)], &[COIN_VALUE - 10]);
+        let orphan = tx(&[output(&parent, 0)], &[COIN_VALUE - 20]);
+        let mut mempool = Mempool::new(100);
+        insert(&mut mempool, &orphan, &[], 1);
+        insert(&mut mempool, &parent, &[chain_coin(0)], 2);
+        assert!(contains(&mempool, &parent));
+        assert!(contains(&mempool, &orphan));
+        assert!(mempool.orphan.is_empty());
+    }
+
+    #[test]
+    fn orphans_per_peer_are_capped() {
+        let mut mempool = Mempool::new(100);
+        let missing = tx(&[chain_coin(0)], &[COIN_VALUE]);
+        for index in 0..=MAX_ORPHANS_PER_PEER as u32 {
+            let orphan = tx(&[output(&missing, index)], &[1]);
+            insert(&mut mempool, &orphan, &[], 1);
+        }
+        assert_eq!(mempool.orphan.len(), MAX_ORPHANS_PER_PEER);
+        assert_eq!(
+            mempool.dependencies[&missing.double_hash()].len(),
+            MAX_ORPHANS_PER_PEER
+        );
+
+        let orphan = tx(&[output(&missing, 1_000)], &[1]);
+        insert(&mut mempool, &orphan, &[], 2);
+        assert_eq!(mempool.orphan.len(), MAX_ORPHANS_PER_PEER + 1);
+    }
+
+    #[test]
+    fn orphans_spending_missing_outputs_are_dropped() {
+        let parent = tx(&[chain_coin(0)], &[COIN_VALUE - 10]);
+        let orphan = tx(&[output(&parent, 0)], &[COIN_VALUE - 20]);
+        let invalid_orphan = tx(&[output(&parent, 1_000)], &[1]);
+        let mut mempool = Mempool::new(100);
+        insert(&mut mempool, &orphan, &[], 1);
+        insert(&mut mempool, &invalid_orphan, &[], 1);
+        insert(&mut mempool, &parent, &[chain_coin(0)], 2);
+
+        assert!(contains(&mempool, &parent));
+        assert!(contains(&mempool, &orphan));
+        assert!(!contains(&mempool, &invalid_orphan));
+        assert!(mempool.orphan.is_empty());
+    }
+
+    #[test]
+    fn spending_missing_output_of_pool_tx_is_rejected() {
+        let parent = tx(&[chain_coin(0)], &[COIN_VALUE - 10]);
+        let invalid = tx(&[output(&parent, 1)], &[1]);
+        let mut mempool = Mempool::new(100);
+        insert(&mut mempool, &parent, &[chain_coin(0)], 1);
+        insert(&mut mempool, &invalid, &[], 1);
+
+        assert!(!contains(&mempool, &invalid));
+        assert!(mempool.orphan.is_empty());
+        assert!(mempool.dependencies.is_empty());
+    }
+
+    #[test]
+    fn block_template_puts_parents_first() {
+        let (parent, child, other) = cpfp_package();
+        let mut mempool = Mempool::new(100);
+        insert(&mut mempool, &other, &[chain_coin(1)], 1);
+        insert(&mut mempool, &parent, &[chain_coin(0)], 1);
+        insert(&mut mempool, &child, &[], 1);
+
+        let (txs, fees) = mempool.block_template();
+        assert_eq!(txs, vec![parent, child, other]);
+        assert_eq!(fees, 10 + 50_000 + 5_000);
+    }
+
+    #[test]
+    fn replacement_pays_for_the_evicted_package_and_its_relay() {
+        let (parent, child, _) = cpfp_package();
+        let mut mempool = Mempool::new(100);
+        insert(&mut mempool, &parent, &[chain_coin(0)], 1);
+        insert(&mut mempool, &child, &[], 1);
+
+        // Pays more than the parent alone but not for the child it evicts too
+        let cheap = tx(&[chain_coin(0)], &[COIN_VALUE - 1_000]);
+        insert(&mut mempool, &cheap, &[chain_coin(0)], 2);
+        assert!(!contains(&mempool, &cheap));
+        assert!(contains(&mempool, &parent));
+        assert!(contains(&mempool, &child));
+
+        // Pays exactly the evicted fees, without the incremental relay fee
+        let bump = tx(&[chain_coin(0)], &[COIN_VALUE - 10 - 50_000]);
+        insert(&mut mempool, &bump, &[chain_coin(0)], 2);
+        assert!(!contains(&mempool, &bump));
+
+        let rich = tx(&[chain_coin(0)], &[COIN_VALUE - 10 - 50_000 - 1_000]);
+        insert(&mut mempool, &rich, &[chain_coin(0)], 2);
+        assert!(contains(&mempool, &rich));
+        assert!(!contains(&mempool, &parent));
+        assert!(!contains(&mempool, &child));
+    }
+
+    #[test]
+    fn replacement_cannot_spend_the_tx_it_replaces() {
+        let parent = tx(&[chain_coin(0)], &[COIN_VALUE / 2, COIN_VALUE / 2 - 10]);
+        let child = tx(&[output(&parent, 0)], &[COIN_VALUE / 2 - 20]);
+        let mut mempool = Mempool::new(100);
+        insert(&mut mempool, &parent, &[chain_coin(0)], 1);
+        insert(&mut mempool, &child, &[], 1);
+
+        // Double spends the coin of the parent while spending the parent's second output
+        let replacement = tx(&[chain_coin(0), output(&parent, 1)], &[1]);
+        insert(&mut mempool, &replacement, &[chain_coin(0)], 2);
+        assert!(!contains(&mempool, &replacement));
+        assert!(contains(&mempool, &parent));
+        assert!(contains(&mempool, &child));
+    }
 }
@@ -1,11 +1,22 @@
 mod addr;
+mod ban;
 mod blockchain;
+mod download;
 mod mempool;
 mod orphan_block;
 mod utxo;
 
 pub use addr::{AddressManager, AddressManagerError};
+pub use ban::BanList;
 pub use blockchain::{Blockchain, NewAddition};
+pub use download::DownloadManager;
 pub use mempool::Mempool;
 pub use orphan_block::OrphanBlockManager;
-pub use utxo::UtxoManager;
+pub use utxo::{script_hash, HistoryEntry, UtxoManager};
+
+fn clear_tree(tree: &sled::Tree) -> Result<(), crate::Error> {
+    for key in tree.iter().keys() {
+        tree.remove(key?)?;
+    }
+    Ok(())
+}
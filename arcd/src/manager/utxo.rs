@@ -1,69 +1,314 @@
 use crate::{
-    data::{linkedblock::LinkedBlock, ser_paired_utxo, ser_utxo_data, PairedUtxo, UtxoData},
+    data::{ser_paired_utxo, ser_utxo_data, PairedUtxo, UtxoData},
     error::Error,
 };
 use bytes::BytesMut;
+use ensicoin_messages::resource::script::{fn_script, Script};
 use ensicoin_messages::resource::tx::fn_outpoint;
-use ensicoin_messages::resource::{Outpoint, Transaction};
+use ensicoin_messages::resource::{Block, Outpoint, Transaction};
+use sha2::{Digest, Sha256};
+use sled::{TransactionResult, TransactionalTree};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use ensicoin_serializer::{Deserialize, Sha256Result};
 
+const FUNDING: u8 = 1;
+const SPENDING: u8 = 2;
+
+/// Hash the script index is keyed by
+pub fn script_hash(script: &Script) -> Sha256Result {
+    let mut hasher = Sha256::default();
+    hasher.input(ensicoin_messages::as_bytes(fn_script(script)));
+    hasher.result()
+}
+
+fn history_key(script_hash: &Sha256Result, height: u32, tx_hash: &Sha256Result) -> Vec<u8> {
+    let mut key = script_hash.to_vec();
+    key.extend_from_slice(&height.to_be_bytes());
+    key.extend_from_slice(tx_hash);
+    key
+}
+
+fn script_utxo_key(script: &Script, outpoint: &Outpoint) -> Vec<u8> {
+    let mut key = script_hash(script).to_vec();
+    key.append(&mut ensicoin_messages::as_bytes(fn_outpoint(outpoint)));
+    key
+}
+
+/// Adds a funding or spending flag to the history entry of a transaction
+fn mark_history(history: &TransactionalTree, key: Vec<u8>, flag: u8) -> TransactionResult<()> {
+    let flags = history
+        .get(&key)?
+        .map_or(0, |v| v.first().copied().unwrap_or(0));
+    history.insert(key, vec![flags | flag])?;
+    Ok(())
+}
+
+/// Trees written when a block is connected or disconnected
+pub struct UtxoTrees<'a> {
+    pub utxo: &'a TransactionalTree,
+    pub history: &'a TransactionalTree,
+    pub script_utxo: &'a TransactionalTree,
+}
+
+/// A transaction paying to or spending from a script
+pub struct HistoryEntry {
+    pub tx_hash: Sha256Result,
+    pub height: u32,
+    pub funding: bool,
+    pub spending: bool,
+}
+
+/// Unspent outputs of the main chain, the trees live in the chainstate database and are only
+/// written inside the transactions connecting or disconnecting a block. The optional script
+/// index keeps the history and the unspent outputs of every script
+#[derive(Clone)]
 pub struct UtxoManager {
-    database: sled::Db,
+    database: sled::Tree,
+    history: sled::Tree,
+    script_utxo: sled::Tree,
+    script_index: bool,
+    /// The history of the blocks connected before the index was enabled is being built
+    script_index_building: Arc<AtomicBool>,
 }
 
 impl UtxoManager {
-    pub fn new(data_dir: &std::path::Path) -> UtxoManager {
-        let mut utxo_dir = std::path::PathBuf::new();
-        utxo_dir.push(data_dir);
-        utxo_dir.push("utxo");
-        let database = sled::Db::open(utxo_dir).unwrap();
-        UtxoManager { database }
+    pub fn new(chainstate: &sled::Db) -> Result<UtxoManager, Error> {
+        Ok(UtxoManager {
+            database: chainstate.open_tree("utxo")?,
+            history: chainstate.open_tree("script_history")?,
+            script_utxo: chainstate.open_tree("script_utxo")?,
+            script_index: false,
+            script_index_building: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Utxo set, script history and script utxo trees
+    pub fn trees(&self) -> (&sled::Tree, &sled::Tree, &sled::Tree) {
+        (&self.database, &self.history, &self.script_utxo)
+    }
+
+    pub fn script_index(&self) -> bool {
+        self.script_index
     }
 
-    fn spend_block(&mut self, block: &LinkedBlock) -> Result<(), Error> {
-        for pairedutxo in block.spent_utxo() {
-            self.delete(&pairedutxo.outpoint)?;
+    /// The index is enabled but does not cover the whole chain yet
+    pub fn script_index_building(&self) -> bool {
+        self.script_index_building.load(Ordering::SeqCst)
+    }
+
+    pub fn set_script_index_building(&self, building: bool) {
+        self.script_index_building.store(building, Ordering::SeqCst);
+    }
+
+    /// A disabled index is wiped as it is not kept up to date. An index enabled on an existing
+    /// chain gets the script utxos from the utxo set, returns true if the history of the
+    /// connected blocks must then be built
+    pub fn set_script_index(&mut self, enabled: bool) -> Result<bool, Error> {
+        self.script_index = enabled;
+        let indexed = self.history.iter().next().is_some();
+        if !enabled && indexed {
+            info!("Removing the script index");
+            super::clear_tree(&self.history)?;
+            super::clear_tree(&self.script_utxo)?;
+        } else if enabled && !indexed && self.database.iter().next().is_some() {
+            info!("Indexing the scripts of the utxo set");
+            for entry in self.database.iter() {
+                let (key, value) = entry?;
+                let outpoint = Outpoint::deserialize(&mut ensicoin_serializer::Deserializer::new(
+                    BytesMut::from(&*key),
+                ))?;
+                let data = UtxoData::deserialize(&mut ensicoin_serializer::Deserializer::new(
+                    BytesMut::from(&*value),
+                ))?;
+                self.script_utxo.insert(
+                    script_utxo_key(&data.script, &outpoint),
+                    ensicoin_messages::as_bytes(ser_paired_utxo(&PairedUtxo { data, outpoint })),
+                )?;
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Adds the outputs of the block and removes the ones it spends
+    pub fn register_block(
+        &self,
+        trees: &UtxoTrees,
+        block: &Block,
+        spent: &[PairedUtxo],
+    ) -> TransactionResult<()> {
+        let height = block.header.height;
+        for (i, tx) in block.txs.iter().enumerate() {
+            self.register(trees, tx, i == 0, height)?;
+        }
+        for pairedutxo in spent {
+            trees.utxo.remove(ensicoin_messages::as_bytes(fn_outpoint(
+                &pairedutxo.outpoint,
+            )))?;
+            if self.script_index {
+                trees.script_utxo.remove(script_utxo_key(
+                    &pairedutxo.data.script,
+                    &pairedutxo.outpoint,
+                ))?;
+            }
+        }
+        if self.script_index {
+            index_history(trees.history, block, spent)?;
         }
         Ok(())
     }
 
-    pub fn register_block(&mut self, block: &LinkedBlock) -> Result<(), Error> {
+    /// Restores the outputs spent by the block and removes the ones it created, outputs created
+    /// and spent in the block are restored first so that they end up removed
+    pub fn unregister_block(
+        &self,
+        trees: &UtxoTrees,
+        block: &Block,
+        spent: &[PairedUtxo],
+    ) -> TransactionResult<()> {
         let height = block.header.height;
-        let hash = block.header.double_hash();
-        self.register(&block.txs[0].transaction, &hash, true, height)?;
-        for tx in &block.txs[1..] {
-            self.register(&tx.transaction, &hash, false, height)?;
+        for pairedutxo in spent {
+            trees.utxo.insert(
+                ensicoin_messages::as_bytes(fn_outpoint(&pairedutxo.outpoint)),
+                ensicoin_messages::as_bytes(ser_utxo_data(&pairedutxo.data)),
+            )?;
+            if self.script_index {
+                trees.script_utxo.insert(
+                    script_utxo_key(&pairedutxo.data.script, &pairedutxo.outpoint),
+                    ensicoin_messages::as_bytes(ser_paired_utxo(pairedutxo)),
+                )?;
+            }
+        }
+        for tx in &block.txs {
+            let hash = tx.double_hash();
+            for (i, output) in tx.outputs.iter().enumerate() {
+                let outpoint = Outpoint {
+                    hash,
+                    index: (i as u32),
+                };
+                trees
+                    .utxo
+                    .remove(ensicoin_messages::as_bytes(fn_outpoint(&outpoint)))?;
+                if self.script_index {
+                    trees
+                        .script_utxo
+                        .remove(script_utxo_key(&output.script, &outpoint))?;
+                    trees.history.remove(history_key(
+                        &script_hash(&output.script),
+                        height,
+                        &hash,
+                    ))?;
+                }
+            }
         }
-        self.spend_block(block)
+        if self.script_index {
+            for (tx_hash, script) in spenders(block, spent) {
+                trees
+                    .history
+                    .remove(history_key(&script_hash(script), height, &tx_hash))?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn register(
+    fn register(
         &self,
+        trees: &UtxoTrees,
         tx: &Transaction,
-        hash: &[u8],
         coin_base: bool,
         block_height: u32,
-    ) -> Result<(), Error> {
+    ) -> TransactionResult<()> {
+        let hash = tx.double_hash();
         for (i, output) in tx.outputs.iter().enumerate() {
-            let data = ensicoin_messages::as_bytes(ser_utxo_data(&UtxoData {
+            let data = UtxoData {
                 script: output.script.clone(),
                 value: output.value,
                 block_height,
                 coin_base,
-            }));
+            };
             let outpoint = Outpoint {
-                hash: Sha256Result::clone_from_slice(hash),
+                hash,
                 index: (i as u32),
             };
-            self.database
-                .insert(ensicoin_messages::as_bytes(fn_outpoint(&outpoint)), data)?;
+            trees.utxo.insert(
+                ensicoin_messages::as_bytes(fn_outpoint(&outpoint)),
+                ensicoin_messages::as_bytes(ser_utxo_data(&data)),
+            )?;
+            if self.script_index {
+                trees.script_utxo.insert(
+                    script_utxo_key(&output.script, &outpoint),
+                    ensicoin_messages::as_bytes(ser_paired_utxo(&PairedUtxo { data, outpoint })),
+                )?;
+            }
         }
         Ok(())
     }
 
+    /// Transactions that paid to or spent from the script, by height
+    pub fn script_history(&self, script_hash: &Sha256Result) -> Result<Vec<HistoryEntry>, Error> {
+        let mut entries = Vec::new();
+        for entry in self.history.scan_prefix(script_hash.as_slice()) {
+            let (key, value) = entry?;
+            if key.len() != 68 {
+                continue;
+            }
+            let mut height = [0; 4];
+            height.copy_from_slice(&key[32..36]);
+            let flags = value.first().copied().unwrap_or(0);
+            entries.push(HistoryEntry {
+                tx_hash: Sha256Result::clone_from_slice(&key[36..]),
+                height: u32::from_be_bytes(height),
+                funding: flags & FUNDING != 0,
+                spending: flags & SPENDING != 0,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Unspent outputs paying to the script
+    pub fn script_utxos(&self, script_hash: &Sha256Result) -> Result<Vec<PairedUtxo>, Error> {
+        let mut utxos = Vec::new();
+        for entry in self.script_utxo.scan_prefix(script_hash.as_slice()) {
+            let (_, value) = entry?;
+            let mut de = ensicoin_serializer::Deserializer::new(BytesMut::from(&*value));
+            utxos.push(PairedUtxo::deserialize(&mut de)?);
+        }
+        Ok(utxos)
+    }
+
+    /// The set contains the outputs of the block that it does not spend itself and none of the
+    /// outputs it spends
+    pub fn has_block(&self, block: &Block, spent: &[PairedUtxo]) -> Result<bool, Error> {
+        let spent_outpoints: Vec<_> = spent
+            .iter()
+            .map(|pairedutxo| ensicoin_messages::as_bytes(fn_outpoint(&pairedutxo.outpoint)))
+            .collect();
+        for outpoint in created_outpoints(block) {
+            let key = ensicoin_messages::as_bytes(fn_outpoint(&outpoint));
+            if !spent_outpoints.contains(&key) && !self.database.contains_key(&key)? {
+                return Ok(false);
+            }
+        }
+        for key in spent_outpoints {
+            if self.database.contains_key(&key)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
     pub fn get(&self, utxo: &Outpoint) -> Result<UtxoData, Error> {
-        match self.database.get(ensicoin_messages::as_bytes(fn_outpoint(utxo)))? {
+        match self
+            .database
+            .get(ensicoin_messages::as_bytes(fn_outpoint(utxo)))?
+        {
             Some(x) => {
                 let mut de = ensicoin_serializer::Deserializer::new(BytesMut::from(&*x));
                 Ok(UtxoData::deserialize(&mut de)?)
@@ -72,11 +317,6 @@ impl UtxoManager {
         }
     }
 
-    pub fn delete(&self, utxo: &Outpoint) -> Result<(), Error> {
-        self.database.remove(ensicoin_messages::as_bytes(fn_outpoint(utxo)))?;
-        Ok(())
-    }
-
     pub fn link(&self, linkedtx: &mut crate::data::linkedtx::LinkedTransaction) {
         for parent in linkedtx.unknown().clone() {
             if let Ok(utxo) = self.get(&parent) {
@@ -96,14 +336,63 @@ impl UtxoManager {
             self.link(ltx);
         }
     }
+}
 
-    pub fn restore(&mut self, utxos: Vec<PairedUtxo>) -> Result<(), Error> {
-        for pairedtx in utxos {
-            self.database.insert(
-                ensicoin_messages::as_bytes(fn_outpoint(&pairedtx.outpoint)),
-                ensicoin_messages::as_bytes(ser_utxo_data(&pairedtx.data)),
+/// Adds the transactions of the block to the history of the scripts they pay to or spend from
+pub fn index_history(
+    history: &TransactionalTree,
+    block: &Block,
+    spent: &[PairedUtxo],
+) -> TransactionResult<()> {
+    let height = block.header.height;
+    for tx in &block.txs {
+        let hash = tx.double_hash();
+        for output in &tx.outputs {
+            mark_history(
+                history,
+                history_key(&script_hash(&output.script), height, &hash),
+                FUNDING,
             )?;
         }
-        Ok(())
     }
+    for (tx_hash, script) in spenders(block, spent) {
+        mark_history(
+            history,
+            history_key(&script_hash(script), height, &tx_hash),
+            SPENDING,
+        )?;
+    }
+    Ok(())
+}
+
+/// Spending transactions of the block with the script of each output they spend
+fn spenders<'a>(block: &Block, spent: &'a [PairedUtxo]) -> Vec<(Sha256Result, &'a Script)> {
+    let scripts: HashMap<&Outpoint, &Script> = spent
+        .iter()
+        .map(|pairedutxo| (&pairedutxo.outpoint, &pairedutxo.data.script))
+        .collect();
+    let mut spenders = Vec::new();
+    for tx in &block.txs {
+        let hash = tx.double_hash();
+        for input in &tx.inputs {
+            if let Some(script) = scripts.get(&input.previous_output) {
+                spenders.push((hash, *script));
+            }
+        }
+    }
+    spenders
+}
+
+fn created_outpoints(block: &Block) -> Vec<Outpoint> {
+    let mut outpoints = Vec::new();
+    for tx in &block.txs {
+        let hash = tx.double_hash();
+        for i in 0..tx.outputs.len() {
+            outpoints.push(Outpoint {
+                hash,
+                index: (i as u32),
+            })
+        }
+    }
+    outpoints
 }
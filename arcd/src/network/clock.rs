@@ -0,0 +1,45 @@
+use crate::constants::{MAX_CLOCK_OFFSET, MIN_CLOCK_SAMPLES};
+use std::collections::HashMap;
+
+/// Local clock corrected by the median offset announced by peers in their Whoami
+pub struct NetworkClock {
+    offsets: HashMap<u64, i64>,
+}
+
+impl NetworkClock {
+    pub fn new() -> NetworkClock {
+        NetworkClock {
+            offsets: HashMap::new(),
+        }
+    }
+
+    pub fn add_sample(&mut self, id: u64, offset: i64) {
+        self.offsets.insert(id, offset);
+    }
+
+    pub fn remove_sample(&mut self, id: u64) {
+        self.offsets.remove(&id);
+    }
+
+    pub fn offset(&self) -> i64 {
+        if self.offsets.len() < MIN_CLOCK_SAMPLES {
+            return 0;
+        }
+        let mut offsets: Vec<_> = self.offsets.values().copied().collect();
+        offsets.sort();
+        let median = offsets[offsets.len() / 2];
+        if median.abs() > MAX_CLOCK_OFFSET {
+            warn!(
+                "Peers clock is {}s away from ours, check the local time",
+                median
+            );
+            0
+        } else {
+            median
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        (crate::utils::timestamp() as i64 + self.offset()) as u64
+    }
+}
@@ -1,6 +1,8 @@
 use crate::{
     data::{
-        intern_messages::{self, ConnectionMessage, ConnectionMessageContent, ServerMessage},
+        intern_messages::{
+            self, ConnectionMessage, ConnectionMessageContent, Misbehavior, ServerMessage,
+        },
         MessageCodec, MessageCodecError,
     },
     network::create_self_address,
@@ -21,6 +23,9 @@ pub enum TerminationReason {
     RemoteTerminated,
     Quit,
     PingFailed,
+    Stalled,
+    Banned,
+    Evicted,
 }
 
 #[derive(Debug)]
@@ -64,6 +69,10 @@ impl From<MessageCodecError> for ConnectionError {
     }
 }
 
+fn time_offset(remote_id: &Whoami) -> i64 {
+    remote_id.address.timestamp as i64 - crate::utils::timestamp() as i64
+}
+
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum State {
     Initiated,
@@ -91,18 +100,41 @@ pub struct Connection {
     version: u32,
     remote: String,
     waiting_ping: bool,
+    ping_sent: std::time::Instant,
+    time_offset: i64,
     origin_port: u16,
+    pruned: bool,
     identity: crate::data::intern_messages::RemoteIdentity,
 }
 
+/// Our Whoami, a pruned node does not advertise the full node service
+fn create_self_whoami(origin_port: u16, pruned: bool) -> Whoami {
+    let mut whoami = Whoami::new(create_self_address(origin_port));
+    if pruned {
+        whoami
+            .services
+            .retain(|service| service != crate::constants::FULL_NODE_SERVICE);
+    }
+    whoami
+}
+
 impl Connection {
-    fn new(stream: TcpStream, sender: ConnectionSender, origin_port: u16, id: u64) -> Connection {
+    fn new(
+        stream: TcpStream,
+        sender: ConnectionSender,
+        origin_port: u16,
+        magic: u32,
+        pruned: bool,
+        id: u64,
+    ) -> Connection {
         let (sender_to_connection, reciever) = mpsc::channel(CHANNEL_CAPACITY);
-        let remote = stream.peer_addr().unwrap().to_string();
-        let frame = tokio::codec::Framed::new(stream, MessageCodec::new());
+        let socket = stream.peer_addr().unwrap();
+        let remote = socket.to_string();
+        let frame = tokio::codec::Framed::new(stream, MessageCodec::new(magic));
 
         let mut identity = crate::data::intern_messages::RemoteIdentity::default();
         identity.id = id;
+        identity.socket = intern_messages::Peer::from(socket);
         Connection {
             id,
             state: State::Idle,
@@ -113,7 +145,10 @@ impl Connection {
             server_sender: sender_to_connection.clone(),
             reciever,
             waiting_ping: false,
+            ping_sent: std::time::Instant::now(),
+            time_offset: 0,
             origin_port,
+            pruned,
             identity,
         }
     }
@@ -121,6 +156,8 @@ impl Connection {
         address: std::net::SocketAddr,
         sender: ConnectionSender,
         origin_port: u16,
+        magic: u32,
+        pruned: bool,
         id: u64,
     ) -> Result<(), CreationError> {
         let stream = match tokio::timer::Timeout::new(
@@ -135,8 +172,8 @@ impl Connection {
         };
         let remote = stream.peer_addr().unwrap().to_string();
         info!("connected to [{}]", remote);
-        let mut conn = Connection::new(stream, sender, origin_port, id);
-        let msg = Message::Whoami(Whoami::new(create_self_address(origin_port)));
+        let mut conn = Connection::new(stream, sender, origin_port, magic, pruned, id);
+        let msg = Message::Whoami(create_self_whoami(origin_port, pruned));
         conn.state = State::Initiated;
         if let Err(e) = conn.frame.send(msg).await {
             warn!("Could not create connection: {:?}", e);
@@ -145,8 +182,16 @@ impl Connection {
         tokio::spawn(conn.run());
         Ok(())
     }
-    pub fn accept(stream: TcpStream, sender: ConnectionSender, origin_port: u16, id: u64) {
-        let connection = Connection::new(stream, sender, origin_port, id);
+    pub fn accept(
+        stream: TcpStream,
+        sender: ConnectionSender,
+        origin_port: u16,
+        magic: u32,
+        pruned: bool,
+        id: u64,
+    ) {
+        let mut connection = Connection::new(stream, sender, origin_port, magic, pruned, id);
+        connection.identity.inbound = true;
         tokio::spawn(connection.run());
     }
     async fn run(mut self) {
@@ -198,6 +243,12 @@ impl Connection {
                         return;
                     } else {
                         self.waiting_ping = true;
+                        self.ping_sent = std::time::Instant::now();
+                        if self.state == State::Ack {
+                            if let Err(e) = self.send(Message::Ping).await {
+                                warn!("Could not send ping: {:?}", e);
+                            }
+                        }
                     }
                 }
                 Action::Remote(None) => {
@@ -206,6 +257,14 @@ impl Connection {
                 }
                 Action::Remote(Some(Err(e))) => {
                     warn!("Message error: {:?}", e);
+                    if let Err(e) = self
+                        .send_message(ConnectionMessageContent::Misbehavior(
+                            Misbehavior::MalformedMessage,
+                        ))
+                        .await
+                    {
+                        warn!("Could not report misbehavior: {:?}", e);
+                    }
                     continue;
                 }
                 Action::Remote(Some(Ok(message))) => {
@@ -273,7 +332,7 @@ impl Connection {
     async fn handle_message(&mut self, msg: Message) -> Result<(), ConnectionError> {
         match msg {
             Message::Whoami(remote_id) if self.state == State::Idle => {
-                let resp = Message::Whoami(Whoami::new(create_self_address(self.origin_port)));
+                let resp = Message::Whoami(create_self_whoami(self.origin_port, self.pruned));
                 self.send(resp).await?;
 
                 let ack = Message::WhoamiAck;
@@ -282,9 +341,11 @@ impl Connection {
                 self.identity.peer.ip = remote_id.address.ip;
                 self.identity.peer.port = remote_id.address.port;
                 self.version = std::cmp::min(self.version, remote_id.version);
+                self.time_offset = time_offset(&remote_id);
                 self.state = State::Confirm;
             }
-            Message::Whoami(_) if self.state == State::Initiated => {
+            Message::Whoami(remote_id) if self.state == State::Initiated => {
+                self.time_offset = time_offset(&remote_id);
                 self.state = State::Replied;
             }
             Message::WhoamiAck if self.state == State::Confirm => {
@@ -292,6 +353,7 @@ impl Connection {
                 self.send_message(ConnectionMessageContent::Register(
                     self.server_sender.clone(),
                     self.identity.clone(),
+                    self.time_offset,
                 ))
                 .await?;
             }
@@ -300,15 +362,24 @@ impl Connection {
                 self.send_message(ConnectionMessageContent::Register(
                     self.server_sender.clone(),
                     self.identity.clone(),
+                    self.time_offset,
                 ))
                 .await?;
                 self.send(Message::WhoamiAck).await?;
             }
             Message::Whoami(_) => {
                 warn!("[{}] is not in a state accepting whoami", self.remote());
+                self.send_message(ConnectionMessageContent::Misbehavior(
+                    Misbehavior::UnexpectedMessage,
+                ))
+                .await?;
             }
             Message::WhoamiAck => {
                 warn!("[{}] is not in a state accepting whoamiack", self.remote());
+                self.send_message(ConnectionMessageContent::Misbehavior(
+                    Misbehavior::UnexpectedMessage,
+                ))
+                .await?;
             }
             Message::Inv(inv) => {
                 self.send_message(ConnectionMessageContent::CheckInv(inv))
@@ -318,7 +389,10 @@ impl Connection {
                 self.send_message(ConnectionMessageContent::Retrieve(get_data))
                     .await?;
             }
-            Message::NotFound(_) => (),
+            Message::NotFound(not_found) => {
+                self.send_message(ConnectionMessageContent::NotFound(not_found))
+                    .await?;
+            }
             Message::Block(block) => {
                 self.send_message(ConnectionMessageContent::NewBlock(block))
                     .await?
@@ -327,8 +401,18 @@ impl Connection {
                 self.send_message(ConnectionMessageContent::SyncBlocks(get_blocks))
                     .await?;
             }
-            // TODO: getMempool
-            Message::GetMempool => (),
+            Message::GetHeaders(get_headers) => {
+                self.send_message(ConnectionMessageContent::SyncHeaders(get_headers))
+                    .await?;
+            }
+            Message::Headers(headers) => {
+                self.send_message(ConnectionMessageContent::NewHeaders(headers))
+                    .await?;
+            }
+            Message::GetMempool => {
+                self.send_message(ConnectionMessageContent::RetrieveMempool)
+                    .await?;
+            }
             Message::Tx(tx) => {
                 self.send_message(ConnectionMessageContent::NewTransaction(tx))
                     .await?
@@ -338,7 +422,11 @@ impl Connection {
                 self.send(Message::Pong).await?;
             }
             Message::Pong => {
-                self.waiting_ping = false;
+                if self.waiting_ping {
+                    self.waiting_ping = false;
+                    self.send_message(ConnectionMessageContent::Latency(self.ping_sent.elapsed()))
+                        .await?;
+                }
             }
             Message::GetAddr => {
                 self.send_message(ConnectionMessageContent::RetrieveAddr)
@@ -0,0 +1,220 @@
+pub mod listener {
+    tonic::include_proto!("ensicoin_listener");
+}
+
+use listener::{
+    ListenForAddressReceivingReply, ListenForAddressReceivingRequest, Outpoint, Reorg, Tx, TxInput,
+    TxOutput,
+};
+
+use crate::{
+    data::intern_messages::BroadcastMessage,
+    manager::{Blockchain, Mempool},
+    wallet::{script_pubkey_hash, PubKeyHash},
+    Error,
+};
+use ensicoin_messages::resource::{script::fn_script, Block, Transaction};
+use ensicoin_serializer::{hash_to_string, Sha256Result};
+use futures::stream::{self, StreamExt};
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch, Mutex};
+use tonic::{Request, Response, Status};
+
+fn tx_to_rpc(tx: &Transaction) -> Tx {
+    Tx {
+        hash: tx.double_hash().to_vec(),
+        version: tx.version,
+        flags: tx.flags.clone(),
+        inputs: tx
+            .inputs
+            .iter()
+            .map(|input| TxInput {
+                script: ensicoin_messages::as_bytes(fn_script(&input.script)),
+                previous_output: Some(Outpoint {
+                    hash: input.previous_output.hash.to_vec(),
+                    index: input.previous_output.index,
+                }),
+            })
+            .collect(),
+        outputs: tx
+            .outputs
+            .iter()
+            .map(|output| TxOutput {
+                value: output.value,
+                script: ensicoin_messages::as_bytes(fn_script(&output.script)),
+            })
+            .collect(),
+    }
+}
+
+fn pays(tx: &Transaction, address: &PubKeyHash) -> bool {
+    tx.outputs
+        .iter()
+        .any(|output| script_pubkey_hash(&output.script).as_ref() == Some(address))
+}
+
+enum Event {
+    Mempool(Transaction),
+    Broadcast(BroadcastMessage),
+}
+
+/// Blocks connected since `last_seen` up to `best`, with the reorg if `last_seen` is no longer
+/// in the best chain. A broadcast can be missed so `best` does not always follow `last_seen`.
+/// None if `best` is an ancestor of `last_seen`, like the value held by the watch when the
+/// listener subscribed
+async fn connected_blocks(
+    blockchain: &Arc<Mutex<Blockchain>>,
+    last_seen: Sha256Result,
+    best: Block,
+) -> Result<Option<(Vec<Block>, Option<Reorg>)>, Error> {
+    if best.header.prev_block == last_seen {
+        return Ok(Some((vec![best], None)));
+    }
+    let best_hash = best.header.double_hash();
+    let blockchain = blockchain.lock().await;
+    let common_hash = match blockchain.find_common_hash(last_seen, best_hash)? {
+        Some(h) => h,
+        None => return Err(Error::NotFound("merge point".to_string())),
+    };
+    if common_hash == best_hash {
+        return Ok(None);
+    }
+    let reorg = if common_hash != last_seen {
+        let mut disconnected = blockchain.chain_until(&last_seen, &common_hash)?;
+        disconnected.reverse();
+        Some(Reorg {
+            disconnected_blocks: disconnected.into_iter().map(|h| h.to_vec()).collect(),
+            common_block: common_hash.to_vec(),
+        })
+    } else {
+        None
+    };
+    let blocks = blockchain.chain_to_blocks(blockchain.chain_until(&best_hash, &common_hash)?)?;
+    Ok(Some((blocks, reorg)))
+}
+
+#[derive(Clone)]
+pub struct RPCListener {
+    mempool: Arc<Mutex<Mempool>>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    broadcast: watch::Receiver<BroadcastMessage>,
+}
+
+impl RPCListener {
+    pub fn new(
+        broadcast: watch::Receiver<BroadcastMessage>,
+        mempool: Arc<Mutex<Mempool>>,
+        blockchain: Arc<Mutex<Blockchain>>,
+    ) -> Self {
+        Self {
+            mempool,
+            blockchain,
+            broadcast,
+        }
+    }
+}
+
+type Reply<T> = Result<Response<T>, Status>;
+
+#[tonic::async_trait]
+impl listener::server::Listener for RPCListener {
+    type ListenForAddressReceivingStream =
+        mpsc::Receiver<Result<ListenForAddressReceivingReply, Status>>;
+
+    async fn listen_for_address_receiving(
+        &self,
+        request: Request<ListenForAddressReceivingRequest>,
+    ) -> Reply<Self::ListenForAddressReceivingStream> {
+        debug!("[grpc] ListenForAddressReceiving");
+        let request = request.into_inner();
+        if request.address.len() != 20 {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                "address is not 20 bytes",
+            ));
+        }
+        let mut address = [0; 20];
+        address.copy_from_slice(&request.address);
+
+        let mut last_seen = match self.blockchain.lock().await.best_block_hash() {
+            Ok(h) => h,
+            Err(e) => {
+                warn!("Internal error: {:?}", e);
+                return Err(Status::new(tonic::Code::Internal, ""));
+            }
+        };
+        let mempool_rx = self.mempool.lock().await.subscribe();
+        let mut events = stream::select(
+            mempool_rx.map(Event::Mempool),
+            self.broadcast.clone().map(Event::Broadcast),
+        );
+        let blockchain = self.blockchain.clone();
+        let (mut out_tx, out_rx) = mpsc::channel(4);
+
+        tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                let replies = match event {
+                    Event::Mempool(tx) => {
+                        if !pays(&tx, &address) {
+                            continue;
+                        }
+                        vec![ListenForAddressReceivingReply {
+                            tx: Some(tx_to_rpc(&tx)),
+                            block_hash: Vec::new(),
+                            reorg: None,
+                        }]
+                    }
+                    Event::Broadcast(BroadcastMessage::BestBlock(block)) => {
+                        let hash = block.header.double_hash();
+                        if hash == last_seen {
+                            continue;
+                        }
+                        let (blocks, reorg) =
+                            match connected_blocks(&blockchain, last_seen, block).await {
+                                Ok(Some(c)) => c,
+                                Ok(None) => continue,
+                                Err(e) => {
+                                    warn!(
+                                        "Listener could not follow the chain to {}: {}",
+                                        hash_to_string(&hash),
+                                        e
+                                    );
+                                    let _ = out_tx
+                                        .send(Err(Status::new(tonic::Code::Internal, "")))
+                                        .await;
+                                    break;
+                                }
+                            };
+                        last_seen = hash;
+                        let mut replies: Vec<_> = reorg
+                            .into_iter()
+                            .map(|reorg| ListenForAddressReceivingReply {
+                                tx: None,
+                                block_hash: Vec::new(),
+                                reorg: Some(reorg),
+                            })
+                            .collect();
+                        for block in blocks {
+                            let block_hash = block.header.double_hash().to_vec();
+                            replies.extend(block.txs.iter().filter(|tx| pays(tx, &address)).map(
+                                |tx| ListenForAddressReceivingReply {
+                                    tx: Some(tx_to_rpc(tx)),
+                                    block_hash: block_hash.clone(),
+                                    reorg: None,
+                                },
+                            ));
+                        }
+                        replies
+                    }
+                    Event::Broadcast(BroadcastMessage::Quit) => break,
+                };
+                for reply in replies {
+                    if out_tx.send(Ok(reply)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Response::new(out_rx))
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{
+    constants::MINER_RETRY_INTERVAL,
+    data::{
+        intern_messages::{BroadcastMessage, ConnectionMessage, ConnectionMessageContent, Source},
+        linkedblock::LinkedBlock,
+    },
+    manager::{Blockchain, Mempool},
+    network::RPCNode,
+};
+use ensicoin_messages::resource::{
+    script::Script, tx::TransactionOutput, Block, BlockHeader, Transaction,
+};
+use futures::stream::{self, StreamExt};
+use num_bigint::BigUint;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::sync::{mpsc, watch, Mutex};
+
+enum Event {
+    Broadcast(BroadcastMessage),
+    Retry,
+}
+
+/// CPU miner grinding the nonce of the block template on top of the best block, found blocks
+/// are sent to the server like any other block
+pub struct Miner {
+    broadcast: watch::Receiver<BroadcastMessage>,
+    mempool: Arc<Mutex<Mempool>>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    server_sender: mpsc::Sender<ConnectionMessage>,
+    script: Script,
+    threads: u64,
+}
+
+impl Miner {
+    pub fn new(
+        broadcast: watch::Receiver<BroadcastMessage>,
+        mempool: Arc<Mutex<Mempool>>,
+        blockchain: Arc<Mutex<Blockchain>>,
+        server_sender: mpsc::Sender<ConnectionMessage>,
+        script: Script,
+        threads: u64,
+    ) -> Self {
+        Self {
+            broadcast,
+            mempool,
+            blockchain,
+            server_sender,
+            script,
+            threads: std::cmp::max(threads, 1),
+        }
+    }
+
+    /// Mines on every new best block. A found block that was rejected or could not be submitted
+    /// does not change the best block, mining starts again on the best block after a while
+    pub async fn run(self) {
+        let mut stop = Arc::new(AtomicBool::new(true));
+        let mut best_block = None;
+        let mut events = stream::select(
+            self.broadcast.clone().map(Event::Broadcast),
+            tokio::timer::Interval::new_interval(std::time::Duration::from_secs(
+                MINER_RETRY_INTERVAL,
+            ))
+            .map(|_| Event::Retry),
+        );
+        while let Some(event) = events.next().await {
+            match event {
+                Event::Broadcast(BroadcastMessage::BestBlock(block)) => {
+                    stop.store(true, Ordering::Relaxed);
+                    best_block = Some(block);
+                }
+                Event::Broadcast(BroadcastMessage::Quit) => break,
+                Event::Retry => {
+                    if !stop.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                }
+            }
+            let best_block = match &best_block {
+                Some(block) => block,
+                None => continue,
+            };
+            let block = self.assemble(best_block).await;
+            debug!(
+                "Mining block of height {} with {} txs",
+                block.header.height,
+                block.txs.len()
+            );
+            stop = Arc::new(AtomicBool::new(false));
+            for start in 0..self.threads {
+                let header = block.header.clone();
+                let txs = block.txs.clone();
+                let step = self.threads;
+                let stop = stop.clone();
+                let sender = self.server_sender.clone();
+                std::thread::spawn(move || grind(header, txs, start, step, stop, sender));
+            }
+        }
+        stop.store(true, Ordering::Relaxed);
+        info!("Miner stopped");
+    }
+
+    async fn assemble(&self, best_block: &Block) -> Block {
+        let (mut txs, header, coinbase_value) = RPCNode::produce_block_template(
+            self.mempool.clone(),
+            self.blockchain.clone(),
+            best_block,
+        )
+        .await;
+        let coinbase = Transaction {
+            version: crate::constants::VERSION,
+            flags: vec![header.height.to_string()],
+            inputs: Vec::new(),
+            outputs: vec![TransactionOutput {
+                value: coinbase_value,
+                script: self.script.clone(),
+            }],
+        };
+        txs.insert(0, coinbase);
+        let mut lblock = LinkedBlock::new(Block { header, txs });
+        lblock.header.merkle_root = lblock.merkle_root();
+        lblock.into_block()
+    }
+}
+
+fn grind(
+    mut header: BlockHeader,
+    txs: Vec<Transaction>,
+    start: u64,
+    step: u64,
+    stop: Arc<AtomicBool>,
+    mut sender: mpsc::Sender<ConnectionMessage>,
+) {
+    let target = BigUint::from_bytes_be(&header.target);
+    header.nonce = start;
+    while !stop.load(Ordering::Relaxed) {
+        if BigUint::from_bytes_be(&header.double_hash()) < target {
+            if stop.swap(true, Ordering::Relaxed) {
+                return;
+            }
+            info!(
+                "Mined block {} at height {}",
+                ensicoin_serializer::hash_to_string(&header.double_hash()),
+                header.height
+            );
+            if let Err(e) = sender.try_send(ConnectionMessage {
+                content: ConnectionMessageContent::NewBlock(Box::new(Block { header, txs })),
+                source: Source::Server,
+            }) {
+                warn!("Could not submit mined block: {:?}", e);
+            }
+            return;
+        }
+        header.nonce = header.nonce.wrapping_add(step);
+    }
+}
@@ -1,13 +1,27 @@
+mod clock;
 mod connection;
 #[cfg(feature = "grpc")]
+mod listener;
+#[cfg(feature = "miner")]
+mod miner;
+mod relay;
+#[cfg(feature = "grpc")]
 mod rpc_server;
 mod server;
+mod slots;
 
+pub use clock::NetworkClock;
 pub use connection::TerminationReason;
 pub use connection::{Connection, State as ConnectionState};
 #[cfg(feature = "grpc")]
+pub use listener::{listener, RPCListener};
+#[cfg(feature = "miner")]
+pub use miner::Miner;
+pub use relay::InventoryRelay;
+#[cfg(feature = "grpc")]
 pub use rpc_server::{node, RPCNode};
 pub use server::Server;
+pub use slots::{Admission, ConnectionSlots};
 
 use std::time::{SystemTime, UNIX_EPOCH};
 
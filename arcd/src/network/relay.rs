@@ -0,0 +1,191 @@
+use crate::constants::{
+    MAX_INV_PER_TRICKLE, MAX_KNOWN_INVENTORY, MAX_TX_REQUEST_ATTEMPTS, TX_REQUEST_TIMEOUT,
+};
+use ensicoin_messages::message::{InvVect, ResourceType};
+use ensicoin_serializer::Sha256Result;
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+#[derive(Default)]
+struct PeerInventory {
+    known: HashSet<Sha256Result>,
+    order: VecDeque<Sha256Result>,
+    queue: Vec<Sha256Result>,
+}
+
+impl PeerInventory {
+    /// Returns false if the inventory was already known
+    fn insert(&mut self, hash: Sha256Result) -> bool {
+        if !self.known.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > MAX_KNOWN_INVENTORY {
+            if let Some(old) = self.order.pop_front() {
+                self.known.remove(&old);
+            }
+        }
+        true
+    }
+
+    fn remove(&mut self, hash: &Sha256Result) {
+        if self.known.remove(hash) {
+            self.order.retain(|h| h != hash);
+        }
+    }
+}
+
+/// Transaction asked to a peer with GetData
+struct TxRequest {
+    peer: u64,
+    since: u64,
+    attempts: u32,
+}
+
+/// Keeps track of the inventory each peer is known to have, transactions are queued and
+/// announced in shuffled batches on every trickle while blocks are announced right away
+pub struct InventoryRelay {
+    peers: HashMap<u64, PeerInventory>,
+    requested: HashMap<Sha256Result, TxRequest>,
+}
+
+impl InventoryRelay {
+    pub fn new() -> InventoryRelay {
+        InventoryRelay {
+            peers: HashMap::new(),
+            requested: HashMap::new(),
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: u64) {
+        self.peers.entry(peer).or_default();
+    }
+
+    pub fn remove_peer(&mut self, peer: u64) {
+        self.peers.remove(&peer);
+        self.requested.retain(|_, request| request.peer != peer);
+    }
+
+    /// The peer announced or sent us these resources
+    pub fn known<I: IntoIterator<Item = Sha256Result>>(&mut self, peer: u64, hashes: I) {
+        if let Some(inventory) = self.peers.get_mut(&peer) {
+            for hash in hashes {
+                inventory.insert(hash);
+            }
+        }
+    }
+
+    /// Queues a transaction for every peer but the one it came from
+    pub fn queue_tx(&mut self, hash: Sha256Result, source: Option<u64>) {
+        if let Some(peer) = source {
+            self.known(peer, std::iter::once(hash));
+        }
+        for inventory in self.peers.values_mut() {
+            if !inventory.known.contains(&hash) {
+                inventory.queue.push(hash);
+            }
+        }
+    }
+
+    /// Peers a block must be announced to, they are assumed to know it afterwards
+    pub fn block_targets(&mut self, hash: Sha256Result, source: Option<u64>) -> Vec<u64> {
+        if let Some(peer) = source {
+            self.known(peer, std::iter::once(hash));
+        }
+        self.peers
+            .iter_mut()
+            .filter_map(|(peer, inventory)| {
+                if inventory.insert(hash) {
+                    Some(*peer)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Another peer known to have the resource
+    fn other_holder(&self, hash: &Sha256Result, except: u64) -> Option<u64> {
+        self.peers
+            .iter()
+            .find(|(peer, inventory)| **peer != except && inventory.known.contains(hash))
+            .map(|(peer, _)| *peer)
+    }
+
+    /// Records the transactions about to be requested from the peer, returns the ones that
+    /// are not already requested from another peer
+    pub fn request_txs(&mut self, peer: u64, inv: Vec<InvVect>) -> Vec<InvVect> {
+        let now = crate::utils::timestamp();
+        inv.into_iter()
+            .filter(|inv_vect| {
+                match self.requested.get(&inv_vect.hash) {
+                    Some(request) if request.since + TX_REQUEST_TIMEOUT > now => return false,
+                    _ => (),
+                }
+                self.requested.insert(
+                    inv_vect.hash,
+                    TxRequest {
+                        peer,
+                        since: now,
+                        attempts: 1,
+                    },
+                );
+                true
+            })
+            .collect()
+    }
+
+    /// The transaction was received, from whichever peer
+    pub fn tx_received(&mut self, hash: &Sha256Result) {
+        self.requested.remove(hash);
+    }
+
+    /// The peer answered NotFound for a transaction, returns another peer to request it from
+    /// if it was requested from this peer and was not already asked too many times
+    pub fn tx_not_found(&mut self, hash: &Sha256Result, peer: u64) -> Option<u64> {
+        match self.requested.get(hash) {
+            Some(request) if request.peer == peer => (),
+            _ => return None,
+        }
+        if let Some(inventory) = self.peers.get_mut(&peer) {
+            inventory.remove(hash);
+        }
+        let mut request = self.requested.remove(hash)?;
+        if request.attempts >= MAX_TX_REQUEST_ATTEMPTS {
+            return None;
+        }
+        let other = self.other_holder(hash, peer)?;
+        request.peer = other;
+        request.since = crate::utils::timestamp();
+        request.attempts += 1;
+        self.requested.insert(*hash, request);
+        Some(other)
+    }
+
+    /// Takes the queued transactions of every peer in a random order, what is left over the
+    /// batch size waits for the next trickle
+    pub fn trickle(&mut self) -> Vec<(u64, Vec<InvVect>)> {
+        let mut rng = rand::thread_rng();
+        let mut batches = Vec::new();
+        for (peer, inventory) in self.peers.iter_mut() {
+            if inventory.queue.is_empty() {
+                continue;
+            }
+            inventory.queue.shuffle(&mut rng);
+            let split = inventory.queue.len().saturating_sub(MAX_INV_PER_TRICKLE);
+            let batch: Vec<_> = inventory.queue.split_off(split);
+            let inv: Vec<_> = batch
+                .into_iter()
+                .filter(|hash| inventory.insert(*hash))
+                .map(|hash| InvVect {
+                    data_type: ResourceType::Transaction,
+                    hash,
+                })
+                .collect();
+            if !inv.is_empty() {
+                batches.push((*peer, inv));
+            }
+        }
+        batches
+    }
+}
@@ -16,10 +16,11 @@ use crate::{
     data::intern_messages::{
         BroadcastMessage, ConnectionMessage, ConnectionMessageContent, Source,
     },
-    manager::{Blockchain, Mempool},
+    manager::{script_hash, BanList, Blockchain, Mempool, UtxoManager},
+    wallet::Wallet,
 };
+use ensicoin_messages::resource::script::{fn_script, Script};
 use ensicoin_serializer::{hash_to_string, Deserialize, Deserializer, Sha256Result};
-use ensicoin_messages::resource::script::fn_script;
 use std::sync::Arc;
 use tokio::sync::{mpsc, watch, Mutex};
 use tonic::{Request, Response, Status};
@@ -82,12 +83,42 @@ fn block_to_rpc(block: ensicoin_messages::resource::Block) -> Block {
     }
 }
 
+/// Hash of the script designated by the request
+fn request_script_hash(request: &node::ScriptRequest) -> Result<Sha256Result, Status> {
+    let script = if !request.address.is_empty() {
+        if request.address.len() != 20 {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                "address is not 20 bytes",
+            ));
+        }
+        let mut address = [0; 20];
+        address.copy_from_slice(&request.address);
+        crate::wallet::p2pkh_script(&address)
+    } else {
+        let mut de = Deserializer::new(bytes::BytesMut::from(&request.script[..]));
+        match Script::deserialize(&mut de) {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(Status::new(
+                    tonic::Code::InvalidArgument,
+                    format!("invalid script: {}", e),
+                ))
+            }
+        }
+    };
+    Ok(script_hash(&script))
+}
+
 #[derive(Clone)]
 pub struct RPCNode {
     mempool: Arc<Mutex<Mempool>>,
     blockchain: Arc<Mutex<Blockchain>>,
+    wallet: Arc<Mutex<Wallet>>,
     server_sender: mpsc::Sender<ConnectionMessage>,
     broadcast: watch::Receiver<BroadcastMessage>,
+    bans: BanList,
+    utxo_manager: UtxoManager,
 }
 
 impl RPCNode {
@@ -95,33 +126,47 @@ impl RPCNode {
         broadcast: watch::Receiver<BroadcastMessage>,
         mempool: Arc<Mutex<Mempool>>,
         blockchain: Arc<Mutex<Blockchain>>,
+        wallet: Arc<Mutex<Wallet>>,
         sender: mpsc::Sender<ConnectionMessage>,
+        bans: BanList,
+        utxo_manager: UtxoManager,
     ) -> Self {
         Self {
             mempool,
             blockchain,
+            wallet,
             broadcast,
             server_sender: sender,
+            bans,
+            utxo_manager,
         }
     }
-    async fn produce_block_template(
+    /// Transactions, header and coinbase value of the block following `block`, the merkle root
+    /// and the nonce are left to the miner
+    pub(crate) async fn produce_block_template(
         mempool: Arc<Mutex<Mempool>>,
         blockchain: Arc<Mutex<Blockchain>>,
         block: &ensicoin_messages::resource::Block,
-    ) -> (Vec<node::Tx>, BlockTemplate) {
-        let txs: Vec<node::Tx> = mempool
+    ) -> (
+        Vec<ensicoin_messages::resource::Transaction>,
+        ensicoin_messages::resource::BlockHeader,
+        u64,
+    ) {
+        let (txs, fees) = mempool.lock().await.block_template();
+        let prev_block = block.header.double_hash();
+        let now = crate::utils::timestamp();
+        let timestamp = blockchain
             .lock()
             .await
-            .get_tx()
-            .into_iter()
-            .map(tx_to_rpc)
-            .collect();
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+            .next_block_timestamp(&prev_block, now)
+            .unwrap_or(now);
         let height = block.header.height + 1;
-        let prev_block = block.header.double_hash();
+        let coinbase_value = blockchain
+            .lock()
+            .await
+            .network()
+            .block_subsidy(height)
+            .saturating_add(fees);
         let flags = Vec::new();
         let version = crate::constants::VERSION;
         let target = big_uint_to_hash(
@@ -133,13 +178,35 @@ impl RPCNode {
         );
         (
             txs,
-            BlockTemplate {
+            ensicoin_messages::resource::BlockHeader {
+                version,
+                flags,
+                prev_block,
+                merkle_root: Sha256Result::from([0; 32]),
                 timestamp,
                 height,
-                prev_block: prev_block.to_vec(),
-                flags,
-                version,
-                target: target.to_vec(),
+                target,
+                nonce: 0,
+            },
+            coinbase_value,
+        )
+    }
+
+    fn template_to_rpc(
+        txs: Vec<ensicoin_messages::resource::Transaction>,
+        header: ensicoin_messages::resource::BlockHeader,
+        coinbase_value: u64,
+    ) -> (Vec<node::Tx>, BlockTemplate) {
+        (
+            txs.into_iter().map(tx_to_rpc).collect(),
+            BlockTemplate {
+                timestamp: header.timestamp,
+                height: header.height,
+                prev_block: header.prev_block.to_vec(),
+                flags: header.flags,
+                version: header.version,
+                target: header.target.to_vec(),
+                coinbase_value,
             },
         )
     }
@@ -273,8 +340,9 @@ impl node::server::Node for RPCNode {
         let (mut out_tx, out_rx) = mpsc::channel(4);
         let mempool = self.mempool.clone();
         let blockchain = self.blockchain.clone();
-        let (txs, block_template) =
+        let (txs, header, coinbase_value) =
             RPCNode::produce_block_template(mempool.clone(), blockchain.clone(), &best_block).await;
+        let (txs, block_template) = RPCNode::template_to_rpc(txs, header, coinbase_value);
         out_tx
             .send(Ok(GetBlockTemplateReply {
                 txs,
@@ -288,9 +356,10 @@ impl node::server::Node for RPCNode {
                     BroadcastMessage::BestBlock(block) => block,
                     _ => unreachable!(),
                 };
-                let (txs, block_template) =
+                let (txs, header, coinbase_value) =
                     RPCNode::produce_block_template(mempool.clone(), blockchain.clone(), &block)
                         .await;
+                let (txs, block_template) = RPCNode::template_to_rpc(txs, header, coinbase_value);
                 out_tx
                     .send(Ok(GetBlockTemplateReply {
                         txs,
@@ -407,6 +476,12 @@ impl node::server::Node for RPCNode {
         let block = match self.blockchain.lock().await.get_block(&hash) {
             Ok(Some(b)) => b,
             Ok(None) => return Err(tonic::Status::new(tonic::Code::NotFound, "")),
+            Err(e @ crate::Error::Pruned(_)) => {
+                return Err(tonic::Status::new(
+                    tonic::Code::FailedPrecondition,
+                    e.to_string(),
+                ))
+            }
             Err(_) => return Err(tonic::Status::new(tonic::Code::Internal, "")),
         };
         Ok(Response::new(GetBlockByHashReply {
@@ -426,13 +501,13 @@ impl node::server::Node for RPCNode {
             ));
         };
         let hash = Sha256Result::clone_from_slice(&request.hash);
-        let block = match self.blockchain.lock().await.get_block(&hash) {
-            Ok(Some(b)) => b,
+        let header = match self.blockchain.lock().await.get_header(&hash) {
+            Ok(Some(h)) => h,
             Ok(None) => return Err(tonic::Status::new(tonic::Code::NotFound, "")),
             Err(_) => return Err(tonic::Status::new(tonic::Code::Internal, "")),
         };
         Ok(Response::new(node::GetBlockHeaderByHashReply {
-            header: Some(block_header_to_rpc(block.header)),
+            header: Some(block_header_to_rpc(header)),
             main_chain: true,
         }))
     }
@@ -449,19 +524,144 @@ impl node::server::Node for RPCNode {
             ));
         };
         let hash = Sha256Result::clone_from_slice(&request.hash);
-        let tx = match self.mempool.lock().await.get_tx_by_hash(&hash) {
-            Some(tx) => tx,
-            None => {
-                return Err(tonic::Status::new(
-                    tonic::Code::NotFound,
-                    hash_to_string(&hash),
-                ))
-            }
-        };
+        if let Some(tx) = self.mempool.lock().await.get_tx_by_hash(&hash) {
+            return Ok(Response::new(GetTxByHashReply {
+                tx: Some(tx_to_rpc(tx)),
+                block_hash: Vec::new(),
+                confirmations: 0,
+            }));
+        }
+        let (tx, block_hash, confirmations) =
+            match internal(self.blockchain.lock().await.get_tx(&hash))? {
+                Some(confirmed) => confirmed,
+                None => {
+                    return Err(tonic::Status::new(
+                        tonic::Code::NotFound,
+                        hash_to_string(&hash),
+                    ))
+                }
+            };
         Ok(Response::new(GetTxByHashReply {
             tx: Some(tx_to_rpc(tx)),
+            block_hash: block_hash.to_vec(),
+            confirmations,
         }))
     }
 
     type GetNewTxStream = mpsc::Receiver<Result<node::GetNewTxReply, Status>>;
+
+    async fn get_balance(
+        &self,
+        _request: Request<node::GetBalanceRequest>,
+    ) -> Reply<node::GetBalanceReply> {
+        debug!("[grpc] GetBalance");
+        let balance = {
+            let mempool = self.mempool.lock().await;
+            self.wallet.lock().await.balance(&mempool)
+        };
+        Ok(Response::new(node::GetBalanceReply {
+            confirmed: balance.confirmed,
+            unconfirmed: balance.unconfirmed,
+        }))
+    }
+
+    async fn get_new_address(
+        &self,
+        _request: Request<node::GetNewAddressRequest>,
+    ) -> Reply<node::GetNewAddressReply> {
+        debug!("[grpc] GetNewAddress");
+        let address = internal(self.wallet.lock().await.new_address())?;
+        Ok(Response::new(node::GetNewAddressReply {
+            address: address.to_vec(),
+        }))
+    }
+
+    async fn list_bans(
+        &self,
+        _request: Request<node::ListBansRequest>,
+    ) -> Reply<node::ListBansReply> {
+        debug!("[grpc] ListBans");
+        let bans = self
+            .bans
+            .bans()
+            .into_iter()
+            .map(|(ip, until)| {
+                let ip = std::net::Ipv6Addr::from(ip);
+                node::Ban {
+                    ip: match ip.to_ipv4() {
+                        Some(ipv4) => ipv4.to_string(),
+                        None => ip.to_string(),
+                    },
+                    until,
+                }
+            })
+            .collect();
+        Ok(Response::new(node::ListBansReply { bans }))
+    }
+
+    async fn clear_bans(
+        &self,
+        _request: Request<node::ClearBansRequest>,
+    ) -> Reply<node::ClearBansReply> {
+        debug!("[grpc] ClearBans");
+        internal(self.bans.clear())?;
+        Ok(Response::new(node::ClearBansReply {}))
+    }
+
+    async fn get_script_history(
+        &self,
+        request: Request<node::ScriptRequest>,
+    ) -> Reply<node::GetScriptHistoryReply> {
+        debug!("[grpc] GetScriptHistory");
+        if !self.utxo_manager.script_index() {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                "script index is disabled",
+            ));
+        }
+        if self.utxo_manager.script_index_building() {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                "script index is being built",
+            ));
+        }
+        let script_hash = request_script_hash(&request.into_inner())?;
+        let entries = internal(self.utxo_manager.script_history(&script_hash))?
+            .into_iter()
+            .map(|entry| node::HistoryEntry {
+                tx_hash: entry.tx_hash.to_vec(),
+                height: entry.height,
+                funding: entry.funding,
+                spending: entry.spending,
+            })
+            .collect();
+        Ok(Response::new(node::GetScriptHistoryReply { entries }))
+    }
+
+    async fn get_script_utxos(
+        &self,
+        request: Request<node::ScriptRequest>,
+    ) -> Reply<node::GetScriptUtxosReply> {
+        debug!("[grpc] GetScriptUtxos");
+        if !self.utxo_manager.script_index() {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                "script index is disabled",
+            ));
+        }
+        let script_hash = request_script_hash(&request.into_inner())?;
+        let utxos = internal(self.utxo_manager.script_utxos(&script_hash))?
+            .into_iter()
+            .map(|pairedutxo| node::Utxo {
+                outpoint: Some(Outpoint {
+                    hash: pairedutxo.outpoint.hash.to_vec(),
+                    index: pairedutxo.outpoint.index,
+                }),
+                value: pairedutxo.data.value,
+                block_height: pairedutxo.data.block_height,
+                coinbase: pairedutxo.data.coin_base,
+            })
+            .collect();
+        Ok(Response::new(node::GetScriptUtxosReply { utxos }))
+    }
 }
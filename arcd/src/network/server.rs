@@ -1,6 +1,6 @@
 #[cfg(feature = "matrix_discover")]
 use crate::bootstrap::matrix;
-use ensicoin_messages::message::Message;
+use ensicoin_messages::message::{InvVect, Message, ResourceType};
 use tokio::{
     net::TcpListener,
     prelude::*,
@@ -9,16 +9,32 @@ use tokio::{
 
 #[cfg(feature = "grpc")]
 use crate::data::intern_messages::BroadcastMessage;
+#[cfg(feature = "miner")]
+use crate::network::Miner;
 #[cfg(feature = "grpc")]
-use crate::network::RPCNode;
+use crate::network::{RPCListener, RPCNode};
 use crate::{
+    consensus::BlockContext,
+    constants::{
+        NetworkParams, MAX_HEADERS, MAX_INV_SIZE, MEMPOOL_FILE, MEMPOOL_SAVE_TICKS,
+        REINDEX_LOG_INTERVAL, TICK_INTERVAL, TRICKLE_INTERVAL, TXINDEX_BATCH,
+    },
     data::{
-        intern_messages::{ConnectionMessage, ConnectionMessageContent, ServerMessage, Source},
+        intern_messages::{
+            ConnectionMessage, ConnectionMessageContent, Misbehavior, RemoteIdentity,
+            ServerMessage, Source,
+        },
         linkedblock::LinkedBlock,
         linkedtx::LinkedTransaction,
     },
-    manager::{AddressManager, Blockchain, Mempool, NewAddition, OrphanBlockManager, UtxoManager},
-    network::{Connection, TerminationReason},
+    manager::{
+        AddressManager, Blockchain, DownloadManager, Mempool, NewAddition, OrphanBlockManager,
+        UtxoManager,
+    },
+    network::{
+        Admission, Connection, ConnectionSlots, InventoryRelay, NetworkClock, TerminationReason,
+    },
+    wallet::Wallet,
     Error, ServerConfig,
 };
 #[cfg(feature = "grpc")]
@@ -50,32 +66,44 @@ pub struct Server {
     mempool: Mempool,
     #[cfg(feature = "grpc")]
     mempool: Arc<Mutex<Mempool>>,
+    mempool_file: std::path::PathBuf,
+    #[cfg(not(feature = "grpc"))]
+    wallet: Wallet,
+    #[cfg(feature = "grpc")]
+    wallet: Arc<Mutex<Wallet>>,
 
     address_manager: AddressManager,
-    connection_count: u64,
-    max_connections_count: u64,
+    slots: ConnectionSlots,
 
     sync_counter: u64,
+    ticks: u64,
+
+    clock: NetworkClock,
 
     next_id_to_give: u64,
 
+    download_manager: DownloadManager,
+    relay: InventoryRelay,
+
     orphan_manager: OrphanBlockManager,
 
     #[cfg(feature = "matrix_discover")]
     matrix_client: Option<matrix::MatrixClient>,
 
     origin_port: u16,
+    /// Old blocks are not served
+    pruned: bool,
+    network: &'static NetworkParams,
 }
 
 impl Server {
     pub async fn run(config: ServerConfig) -> Result<(), Box<dyn std::error::Error>> {
         let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let network = config.network.params();
+        let port = config.port.unwrap_or(network.port);
 
-        let mut listener = TcpListener::bind(&std::net::SocketAddr::new(
-            "0.0.0.0".parse().unwrap(),
-            config.port,
-        ))
-        .await?;
+        let mut listener =
+            TcpListener::bind(&std::net::SocketAddr::new("0.0.0.0".parse().unwrap(), port)).await?;
         let mut sender_clone = sender.clone();
         tokio::spawn(async move {
             loop {
@@ -112,14 +140,124 @@ impl Server {
                 .await
                 .expect("Quit signal could not be processed");
         });
+        let mut sender_clone = sender.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::timer::Interval::new_interval(std::time::Duration::from_secs(TICK_INTERVAL));
+            while interval.next().await.is_some() {
+                if sender_clone
+                    .send(ConnectionMessage {
+                        content: ConnectionMessageContent::Tick,
+                        source: Source::Server,
+                    })
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        let mut sender_clone = sender.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::timer::Interval::new_interval(
+                std::time::Duration::from_secs(TRICKLE_INTERVAL),
+            );
+            while interval.next().await.is_some() {
+                if sender_clone
+                    .send(ConnectionMessage {
+                        content: ConnectionMessageContent::Trickle,
+                        source: Source::Server,
+                    })
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
 
-        let address_manager = AddressManager::new(config.data_dir.as_ref().unwrap(), 4)?;
-        let mempool = Mempool::new();
-        let blockchain = Blockchain::new(&config.data_dir.as_ref().unwrap());
+        let address_manager = AddressManager::new(config.data_dir.as_ref().unwrap())?;
+        let mut mempool = Mempool::new(network.coinbase_maturity);
+        let mut chainstate_dir = config.data_dir.clone().unwrap();
+        chainstate_dir.push("chainstate");
+        let chainstate = sled::Db::open(chainstate_dir)?;
+        let migrated = match crate::bootstrap::migrate_legacy(
+            config.data_dir.as_ref().unwrap(),
+            &chainstate,
+            network,
+        ) {
+            Ok(migrated) => migrated,
+            Err(e) => {
+                warn!("Could not migrate the old data directory: {}", e);
+                return Err(Box::new(Error::LegacyDataDir));
+            }
+        };
+        if migrated {
+            info!("Migrated the blocks of the old data directory, reindexing them");
+        }
+        let reindex = config.reindex || migrated;
+        let mut utxo_manager = UtxoManager::new(&chainstate)?;
+        let build_script_index = utxo_manager.set_script_index(config.scriptindex)?;
+        let mut blockchain = Blockchain::new(&chainstate, utxo_manager.clone(), network)?;
+        if blockchain.genesis_hash()? != network.genesis().double_hash() {
+            return Err(Box::new(Error::NotFound(format!(
+                "{} genesis block in the data directory",
+                config.network
+            ))));
+        }
+        let to_reindex = if reindex {
+            blockchain.reindex_chain()?
+        } else {
+            if blockchain.check_consistency()? {
+                info!("Repaired the utxo set");
+            }
+            Vec::new()
+        };
+        if let Some(target) = config.prune {
+            blockchain.set_prune_target(target * 1_048_576)?;
+            info!("Pruning stored blocks down to {} MiB", target);
+        }
+        let pruned = blockchain.is_pruned();
+        if config.txindex && pruned {
+            return Err(Box::new(Error::Pruned(
+                "blocks needed by the transaction index".to_string(),
+            )));
+        }
+        blockchain.set_txindex(config.txindex)?;
+        let script_index_building =
+            blockchain.start_script_index(build_script_index && !reindex)?;
+        if script_index_building && pruned {
+            return Err(Box::new(Error::Pruned(
+                "blocks needed by the script index".to_string(),
+            )));
+        }
+        mempool.set_height(blockchain.best_height()?);
+        let mut mempool_file = config.data_dir.clone().unwrap();
+        mempool_file.push(MEMPOOL_FILE);
         #[cfg(feature = "grpc")]
         let blockchain = Arc::new(Mutex::new(blockchain));
         #[cfg(feature = "grpc")]
         let mempool = Arc::new(Mutex::new(mempool));
+        #[cfg(feature = "grpc")]
+        {
+            if config.txindex {
+                tokio::spawn(Server::build_index(
+                    blockchain.clone(),
+                    Blockchain::index_next_block,
+                    "transaction",
+                ));
+            }
+            if script_index_building {
+                tokio::spawn(Server::build_index(
+                    blockchain.clone(),
+                    Blockchain::index_scripts_next_block,
+                    "script",
+                ));
+            }
+        }
+        let wallet = Wallet::new(config.data_dir.as_ref().unwrap())?;
+        #[cfg(feature = "grpc")]
+        let wallet = Arc::new(Mutex::new(wallet));
 
         #[cfg(feature = "grpc")]
         let (broadcast_channel_tx, broadcast_channel_rx) = {
@@ -135,19 +273,69 @@ impl Server {
                 .expect("Blockchain error");
             watch::channel(BroadcastMessage::BestBlock(best_block.unwrap()))
         };
+        #[cfg(feature = "miner")]
+        {
+            if let Some(threads) = config.mine {
+                let address = wallet.lock().await.new_address()?;
+                info!(
+                    "Mining with {} threads to {}",
+                    threads,
+                    address
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<String>()
+                );
+                let miner = Miner::new(
+                    broadcast_channel_rx.clone(),
+                    mempool.clone(),
+                    blockchain.clone(),
+                    sender.clone(),
+                    crate::wallet::p2pkh_script(&address),
+                    threads,
+                );
+                tokio::spawn(miner.run());
+            }
+        }
         #[cfg(feature = "grpc")]
         let rpc_abort = {
+            let listener = RPCListener::new(
+                broadcast_channel_rx.clone(),
+                mempool.clone(),
+                blockchain.clone(),
+            );
             let rpc = RPCNode::new(
                 broadcast_channel_rx,
                 mempool.clone(),
                 blockchain.clone(),
+                wallet.clone(),
                 sender.clone(),
+                address_manager.ban_list(),
+                utxo_manager.clone(),
             );
-            let addr = format!("{}:{}", "[::1]", config.grpc_port).parse().unwrap();
+            let addr = format!(
+                "{}:{}",
+                "[::1]",
+                config.grpc_port.unwrap_or(network.grpc_port)
+            )
+            .parse()
+            .unwrap();
+            let listener_addr = format!(
+                "{}:{}",
+                "[::1]",
+                config.listener_port.unwrap_or(network.listener_port)
+            )
+            .parse()
+            .unwrap();
             let (handle, registration) = AbortHandle::new_pair();
             let rpc_server = Abortable::new(
-                tonic::transport::Server::builder()
-                    .serve(addr, super::node::server::NodeServer::new(rpc)),
+                futures::future::try_join(
+                    tonic::transport::Server::builder()
+                        .serve(addr, super::node::server::NodeServer::new(rpc)),
+                    tonic::transport::Server::builder().serve(
+                        listener_addr,
+                        super::listener::server::ListenerServer::new(listener),
+                    ),
+                ),
                 registration,
             )
             .map_err(|_| ())
@@ -171,20 +359,34 @@ impl Server {
             connections: std::collections::HashMap::new(),
             connection_receiver: receiver,
             connection_sender: sender,
-            connection_count: 0,
-            max_connections_count: config.max_connections,
-            utxo_manager: UtxoManager::new(config.data_dir.as_ref().unwrap()),
+            slots: ConnectionSlots::new(config.max_inbound, config.max_outbound),
+            utxo_manager,
             blockchain,
             next_id_to_give: 0,
             mempool,
+            mempool_file,
+            wallet,
             sync_counter: 3,
+            ticks: 0,
+            clock: NetworkClock::new(),
+            download_manager: DownloadManager::new(),
+            relay: InventoryRelay::new(),
             orphan_manager: OrphanBlockManager::new(),
             #[cfg(feature = "matrix_discover")]
             matrix_client: None,
             address_manager,
-            origin_port: config.port,
+            origin_port: port,
+            pruned,
+            network,
         };
-        info!("Node created, listening on port {}", config.port);
+        info!(
+            "Node created on {}, listening on port {}",
+            config.network, port
+        );
+        if reindex {
+            server.reindex(to_reindex).await?;
+        }
+        server.restore_mempool().await;
         let mut discover_message = "Starting server with: ".to_string();
         #[cfg(feature = "matrix_discover")]
         {
@@ -195,7 +397,9 @@ impl Server {
                     Err(()) => (),
                 }
             }
-            server.address_manager.set_bots(initial_bots);
+            server
+                .address_manager
+                .set_bots(initial_bots, server.network.magic);
             discover_message.push_str(&format!("{} peers from matrix,", initial_bots.len()));
         }
         discover_message.push_str(&format!(
@@ -212,6 +416,113 @@ impl Server {
         Ok(())
     }
 
+    /// Validates the stored blocks again on top of a wiped chain state
+    async fn reindex(
+        &mut self,
+        blocks: Vec<ensicoin_serializer::Sha256Result>,
+    ) -> Result<(), Error> {
+        let total = blocks.len();
+        let mut invalid = 0;
+        info!("Reindexing {} stored blocks", total);
+        self.wallet.lock().await.clear_coins()?;
+        for (i, hash) in blocks.into_iter().enumerate() {
+            let block = match self.blockchain.lock().await.get_block(&hash)? {
+                Some(b) => b,
+                None => {
+                    return Err(Error::NotFound(format!(
+                        "block {} to reindex",
+                        ensicoin_serializer::hash_to_string(&hash)
+                    )))
+                }
+            };
+            self.handle_new_block(block, Source::Server).await?;
+            if !self.blockchain.lock().await.is_validated(&hash)? {
+                warn!(
+                    "Stored block {} is invalid, it is left out of the chain state",
+                    ensicoin_serializer::hash_to_string(&hash)
+                );
+                invalid += 1;
+            }
+            if (i + 1) % REINDEX_LOG_INTERVAL == 0 {
+                info!(
+                    "Reindexed {} of {} blocks, {} invalid",
+                    i + 1,
+                    total,
+                    invalid
+                );
+            }
+        }
+        let best_height = self.blockchain.lock().await.best_height()?;
+        if invalid > 0 {
+            warn!(
+                "Reindex done, best height is {}, {} of {} stored blocks were invalid",
+                best_height, invalid, total
+            );
+        } else {
+            info!("Reindex done, best height is {}", best_height);
+        }
+        Ok(())
+    }
+
+    /// Indexes the blocks connected before the index was enabled, the blockchain is released
+    /// between batches
+    #[cfg(feature = "grpc")]
+    async fn build_index(
+        blockchain: Arc<Mutex<Blockchain>>,
+        next: fn(&mut Blockchain) -> Result<Option<u32>, Error>,
+        name: &'static str,
+    ) {
+        let mut indexed: usize = 0;
+        loop {
+            let mut blockchain = blockchain.lock().await;
+            for _ in 0..TXINDEX_BATCH {
+                match next(&mut *blockchain) {
+                    Ok(Some(height)) => {
+                        indexed += 1;
+                        if indexed % REINDEX_LOG_INTERVAL == 0 {
+                            info!("Built the {} index up to height {}", name, height);
+                        }
+                    }
+                    Ok(None) => {
+                        if indexed > 0 {
+                            info!("The {} index is built, {} blocks indexed", name, indexed);
+                        }
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("Could not build the {} index: {}", name, e);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn restore_mempool(&mut self) {
+        let txs = match Mempool::load(&self.mempool_file) {
+            Ok(txs) => txs,
+            Err(e) => {
+                warn!("Could not read the saved mempool: {}", e);
+                return;
+            }
+        };
+        let saved = txs.len();
+        let mut mempool = self.mempool.lock().await;
+        for tx in txs {
+            let mut ltx = LinkedTransaction::new(tx);
+            self.utxo_manager.link(&mut ltx);
+            mempool.insert(ltx, None);
+        }
+        mempool.clear_orphans();
+        let restored = mempool.get_tx().len();
+        info!(
+            "Restored {} of {} saved transactions, {} were confirmed or invalid",
+            restored,
+            saved,
+            saved.saturating_sub(restored)
+        );
+    }
+
     async fn main_loop(mut self) -> Result<(), Error> {
         while let Some(message) = self.connection_receiver.recv().await {
             self.handle_message(message).await?;
@@ -244,15 +555,13 @@ impl Server {
                     warn!("Could not pass online on matrix: {}", e)
                 } else {
                     if let Err(e) = matrix_client.set_name(
-                        &format!("{}", crate::constants::MAGIC),
+                        &format!("{}", self.network.magic),
                         crate::constants::IP,
-                        &format!("{}", config.port),
+                        &format!("{}", self.origin_port),
                     ) {
                         warn!("Could not set matrix displayname: {}", e)
                     } else {
-                        match matrix_client
-                            .get_bots(&room_id, &format!("{}", crate::constants::MAGIC))
-                        {
+                        match matrix_client.get_bots(&room_id, &format!("{}", self.network.magic)) {
                             Ok(b) => initial_bots = b,
                             Err(e) => warn!("Could not retrieve initial_peers: {}", e),
                         }
@@ -266,13 +575,25 @@ impl Server {
         Ok(initial_bots)
     }
 
-    async fn broadcast_to_connections(&mut self, message: ServerMessage) -> Result<(), Error> {
-        let remotes: Vec<_> = self.connections.keys().copied().collect();
-        for remote in remotes {
-            self.send(remote, message.clone()).await?;
+    #[cfg(feature = "grpc")]
+    async fn broadcast_best_block(&mut self) -> Result<(), Error> {
+        let best_block = {
+            let blockchain = self.blockchain.lock().await;
+            blockchain.get_block(&blockchain.best_block_hash()?)?
+        };
+        if let Some(block) = best_block {
+            if self
+                .broadcast_channel_tx
+                .send(BroadcastMessage::BestBlock(block))
+                .await
+                .is_err()
+            {
+                error!("Could not broadcast");
+            }
         }
         Ok(())
     }
+
     async fn send(&mut self, id: u64, message: ServerMessage) -> Result<(), Error> {
         match self.connections.get_mut(&id) {
             Some(h) => {
@@ -285,6 +606,16 @@ impl Server {
         Ok(())
     }
 
+    /// Sends from a timer handler: a peer whose connection is gone is forgotten instead of
+    /// stopping the server, its connection cleans up the rest
+    async fn send_or_forget(&mut self, id: u64, message: ServerMessage) {
+        if let Err(e) = self.send(id, message).await {
+            warn!("Could not send to [{}]: {}", id, e);
+            self.relay.remove_peer(id);
+            self.download_manager.remove_peer(id);
+        }
+    }
+
     // The boolean means should execution continue or not
     async fn handle_message(&mut self, message: ConnectionMessage) -> Result<bool, Error> {
         debug!("Server handling {}", message);
@@ -293,10 +624,8 @@ impl Server {
             ConnectionMessageContent::ConnectionFailed(address) => {
                 info!("Connection {} failed", address);
                 self.address_manager
-                    .no_response(crate::data::intern_messages::Peer::from(address));
-                if self.connection_count < 10 {
-                    self.find_new_peer().await;
-                }
+                    .failed(crate::data::intern_messages::Peer::from(address));
+                self.find_new_peer().await;
             }
             ConnectionMessageContent::Quit => {
                 #[cfg(feature = "matrix_discover")]
@@ -328,8 +657,7 @@ impl Server {
                         warn!("Could not shutdown connection: {:?}", e)
                     }
                 }
-                info!("Reseting connection state");
-                self.address_manager.reset_state();
+                self.save_mempool().await;
                 info!("Node shutdown !");
                 return Err(Error::Quit);
             }
@@ -342,6 +670,30 @@ impl Server {
                     }
                 }
             }
+            ConnectionMessageContent::RetrieveMempool => {
+                if let Source::Connection(remote) = message.source {
+                    let hashes: Vec<_> = self
+                        .mempool
+                        .lock()
+                        .await
+                        .get_tx()
+                        .iter()
+                        .map(|tx| tx.double_hash())
+                        .collect();
+                    self.relay.known(remote.id, hashes.iter().copied());
+                    for chunk in hashes.chunks(MAX_INV_SIZE) {
+                        let inv = chunk
+                            .iter()
+                            .map(|hash| InvVect {
+                                data_type: ResourceType::Transaction,
+                                hash: *hash,
+                            })
+                            .collect();
+                        self.send(remote.id, ServerMessage::SendMsg(Message::Inv(inv)))
+                            .await?;
+                    }
+                }
+            }
             ConnectionMessageContent::NewAddr(addr) => {
                 for address in addr {
                     match tokio::net::TcpStream::connect((
@@ -364,44 +716,75 @@ impl Server {
             ConnectionMessageContent::VerifiedAddr(address) => {
                 self.address_manager.add_addr(address)
             }
-            ConnectionMessageContent::Register(mut sender, host) => {
-                if self.connection_count < self.max_connections_count {
+            ConnectionMessageContent::Latency(latency) => {
+                if let Source::Connection(remote) = message.source {
+                    self.slots.set_latency(remote.id, latency);
+                }
+            }
+            ConnectionMessageContent::Misbehavior(offense) => {
+                if let Source::Connection(remote) = message.source {
+                    self.punish(&remote, offense).await?;
+                }
+            }
+            ConnectionMessageContent::Register(mut sender, host, time_offset) => {
+                let admission = self.slots.register(&host);
+                if let Admission::Evict(evicted) = admission {
+                    info!("Evicting [{}] in favor of [{}]", evicted, host.id);
+                    self.send(
+                        evicted,
+                        ServerMessage::Terminate(TerminationReason::Evicted),
+                    )
+                    .await?;
+                }
+                if let Admission::Refused = admission {
+                    warn!("Too many connections to accept [{}]", &host.id);
+                    sender
+                        .send(ServerMessage::Terminate(
+                            TerminationReason::TooManyConnections,
+                        ))
+                        .await?;
+                } else {
                     info!("Registered [{}]", &host.id);
+                    self.clock.add_sample(host.id, time_offset);
                     self.connections.insert(host.id, sender);
-                    self.address_manager.register_addr(host.peer, true);
-                    self.connection_count += 1;
+                    self.download_manager.add_peer(host.id);
+                    self.relay.add_peer(host.id);
+                    if host.inbound {
+                        self.address_manager.add_peer(host.peer);
+                    } else {
+                        self.address_manager.good(host.socket);
+                    }
 
                     if self.sync_counter > 0 {
                         self.sync_counter -= 1;
-                        let getblocks =
-                            Message::GetBlocks(self.blockchain.lock().await.generate_get_blocks()?);
-                        let msg = ServerMessage::SendMsg(getblocks);
+                        let get_headers = {
+                            let blockchain = self.blockchain.lock().await;
+                            Message::GetHeaders(
+                                blockchain.generate_get_headers(blockchain.best_block_hash()?)?,
+                            )
+                        };
+                        let msg = ServerMessage::SendMsg(get_headers);
                         let msg_get_mempool = ServerMessage::SendMsg(Message::GetMempool);
                         self.send(host.id, msg).await?;
                         self.send(host.id, msg_get_mempool).await?;
                     };
-                } else {
-                    warn!("Too many connections to accept [{}]", &host.id);
-                    sender
-                        .send(ServerMessage::Terminate(
-                            TerminationReason::TooManyConnections,
-                        ))
-                        .await?;
+                    self.request_blocks().await;
                 }
             }
             ConnectionMessageContent::Clean(host) => {
-                if self.connections.remove(&host).is_some() {
-                    self.connection_count -= 1;
-                };
-                if self.connection_count < 10 {
-                    self.find_new_peer().await;
-                }
+                self.connections.remove(&host);
+                self.slots.remove(host);
+                self.clock.remove_sample(host);
+                self.download_manager.remove_peer(host);
+                self.relay.remove_peer(host);
+                self.request_blocks().await;
+                self.find_new_peer().await;
                 trace!("Cleaned connection [{}]", host);
             }
             ConnectionMessageContent::Disconnect(e, host) => {
                 /*if let Error::NoResponse = &e {
                     match host.parse() {
-                        Ok(p) => self.address_manager.no_response(p),
+                        Ok(p) => self.address_manager.failed(p),
                         Err(e) => warn!("Host [{}] is not a socket addr: {:?}", host, e),
                     }
                 };
@@ -415,14 +798,26 @@ impl Server {
                 // TODO what to doo
             }
             ConnectionMessageContent::CheckInv(inv) => {
-                let (mut unknown, txs) = self.blockchain.lock().await.get_unknown_blocks(inv)?;
-                let (mut unknown_tx, _) = self.mempool.lock().await.get_unknown_tx(txs);
-                unknown.append(&mut unknown_tx);
-                if !unknown.is_empty() {
-                    let get_data = Message::GetData(unknown);
-                    if let crate::data::intern_messages::Source::Connection(remote) = message.source
-                    {
-                        self.send(remote.id, ServerMessage::SendMsg(get_data))
+                if let Source::Connection(remote) = &message.source {
+                    self.relay
+                        .known(remote.id, inv.iter().map(|inv_vect| inv_vect.hash));
+                }
+                let (unknown_blocks, txs) = self.blockchain.lock().await.get_unknown_blocks(inv)?;
+                let unknown_blocks: Vec<_> = unknown_blocks
+                    .into_iter()
+                    .map(|inv_vect| inv_vect.hash)
+                    .collect();
+                self.download_manager
+                    .enqueue(unknown_blocks.iter().copied());
+                if let Source::Connection(remote) = &message.source {
+                    self.download_manager.announced(remote.id, unknown_blocks);
+                }
+                self.request_blocks().await;
+                let (unknown, _) = self.mempool.lock().await.get_unknown_tx(txs);
+                if let crate::data::intern_messages::Source::Connection(remote) = message.source {
+                    let unknown = self.relay.request_txs(remote.id, unknown);
+                    if !unknown.is_empty() {
+                        self.send(remote.id, ServerMessage::SendMsg(Message::GetData(unknown)))
                             .await?;
                     }
                 };
@@ -430,7 +825,13 @@ impl Server {
             ConnectionMessageContent::Retrieve(get_data) => {
                 // GetData
                 if let crate::data::intern_messages::Source::Connection(remote) = message.source {
-                    let (blocks, remaining) = self.blockchain.lock().await.get_data(get_data)?;
+                    let (blocks, pruned, remaining) =
+                        self.blockchain.lock().await.get_data(get_data)?;
+                    if !pruned.is_empty() {
+                        debug!("[{}] requested {} pruned blocks", remote.id, pruned.len());
+                    }
+                    self.relay
+                        .known(remote.id, blocks.iter().map(|block| block.double_hash()));
                     for block in blocks {
                         self.send(
                             remote.id,
@@ -438,11 +839,48 @@ impl Server {
                         )
                         .await?;
                     }
-                    let (txs, _) = self.mempool.lock().await.get_data(remaining);
+                    let (txs, mut missing) = self.mempool.lock().await.get_data(remaining);
+                    missing.extend(pruned);
+                    self.relay
+                        .known(remote.id, txs.iter().map(|tx| tx.double_hash()));
                     for tx in txs {
                         self.send(remote.id, ServerMessage::SendMsg(Message::Tx(Box::new(tx))))
                             .await?;
                     }
+                    if !missing.is_empty() {
+                        self.send(
+                            remote.id,
+                            ServerMessage::SendMsg(Message::NotFound(missing)),
+                        )
+                        .await?;
+                    }
+                }
+            }
+            ConnectionMessageContent::NotFound(not_found) => {
+                if let Source::Connection(remote) = message.source {
+                    let mut blocks = Vec::new();
+                    let mut txs: std::collections::HashMap<u64, Vec<InvVect>> =
+                        std::collections::HashMap::new();
+                    for inv_vect in not_found {
+                        match inv_vect.data_type {
+                            ResourceType::Block => blocks.push(inv_vect.hash),
+                            ResourceType::Transaction => {
+                                match self.relay.tx_not_found(&inv_vect.hash, remote.id) {
+                                    Some(peer) => txs.entry(peer).or_default().push(inv_vect),
+                                    None => debug!(
+                                        "Not requesting tx {} again",
+                                        ensicoin_serializer::hash_to_string(&inv_vect.hash)
+                                    ),
+                                }
+                            }
+                        }
+                    }
+                    self.download_manager.not_found(remote.id, &blocks);
+                    for (peer, inv) in txs {
+                        self.send(peer, ServerMessage::SendMsg(Message::GetData(inv)))
+                            .await?;
+                    }
+                    self.request_blocks().await;
                 }
             }
             ConnectionMessageContent::SyncBlocks(get_blocks) => {
@@ -456,20 +894,79 @@ impl Server {
                     }
                 }
             }
+            ConnectionMessageContent::SyncHeaders(get_headers) => {
+                let headers = self
+                    .blockchain
+                    .lock()
+                    .await
+                    .generate_headers(&get_headers)?;
+                if !headers.is_empty() {
+                    if let Source::Connection(remote) = message.source {
+                        self.send(remote.id, ServerMessage::SendMsg(Message::Headers(headers)))
+                            .await?;
+                    }
+                }
+            }
+            ConnectionMessageContent::NewHeaders(headers) => {
+                if let Source::Connection(remote) = message.source {
+                    let (added, invalid) = match self.blockchain.lock().await.add_headers(&headers)
+                    {
+                        Ok(a) => a,
+                        Err(e) => {
+                            warn!("Could not add headers from [{}]: {}", remote.id, e);
+                            return Ok(true);
+                        }
+                    };
+                    if !added.is_empty() {
+                        info!(
+                            "Headers synced up to height {}",
+                            self.blockchain.lock().await.best_header_height()?
+                        );
+                    }
+                    self.download_manager.enqueue(added);
+                    self.download_manager
+                        .announced(remote.id, headers.iter().map(|h| h.double_hash()));
+                    if let Some(e) = invalid {
+                        warn!("Recieved invalid headers from [{}]: {}", remote.id, e);
+                        self.punish(&remote, Misbehavior::InvalidHeaders).await?;
+                        self.request_blocks().await;
+                        return Ok(true);
+                    }
+                    if headers.len() == MAX_HEADERS {
+                        let last = headers[headers.len() - 1].double_hash();
+                        let get_headers = Message::GetHeaders(
+                            self.blockchain.lock().await.generate_get_headers(last)?,
+                        );
+                        self.send(remote.id, ServerMessage::SendMsg(get_headers))
+                            .await?;
+                    }
+                    self.request_blocks().await;
+                }
+            }
             ConnectionMessageContent::Connect(address) => {
                 let (ip, port) = (address.ip(), address.port());
                 let ip = match ip {
                     std::net::IpAddr::V4(i) => i.to_ipv6_mapped().octets(),
                     std::net::IpAddr::V6(i) => i.octets(),
                 };
+                if self.address_manager.is_banned(&ip) {
+                    warn!("Refusing to connect to banned peer {}", address);
+                    return Ok(true);
+                }
+                if self.slots.outbound_available() == 0 {
+                    warn!("No outbound slot left to connect to {}", address);
+                    return Ok(true);
+                }
                 let peer = crate::data::intern_messages::Peer { ip, port };
-                self.address_manager.register_addr(peer, true);
+                self.address_manager.attempt(peer);
                 let id = self.next_id_to_give;
                 self.next_id_to_give += 1;
                 if let Err(e) = Connection::initiate(
                     std::net::SocketAddr::from((ip, port)),
                     self.connection_sender.clone(),
                     self.origin_port,
+                    self.network.magic,
+                    self.pruned,
                     id,
                 )
                 .await
@@ -480,19 +977,68 @@ impl Server {
                         e
                     );
                     self.next_id_to_give -= 1;
+                } else {
+                    self.slots.initiated(id, peer.netgroup());
                 };
             }
             ConnectionMessageContent::NewTransaction(tx) => {
                 // TODO: Verify tx in mempool insert
                 let mut ltx = LinkedTransaction::new(*tx);
                 self.utxo_manager.link(&mut ltx);
-                self.mempool.lock().await.insert(ltx);
+                let hash = ltx.transaction.double_hash();
+                self.relay.tx_received(&hash);
+                let mut mempool = self.mempool.lock().await;
+                let known = mempool.get_tx_by_hash(&hash).is_some();
+                mempool.insert(ltx, message.source.connection_id());
+                if !known && mempool.get_tx_by_hash(&hash).is_some() {
+                    if let Source::Connection(remote) = &message.source {
+                        self.slots.useful_tx(remote.id);
+                    }
+                    self.relay.queue_tx(hash, message.source.connection_id());
+                }
             }
             ConnectionMessageContent::NewBlock(block) => {
-                self.handle_new_block(*block, message.source).await?;
+                for (source, block) in self.download_manager.received(message.source, *block) {
+                    self.handle_new_block(block, source).await?;
+                }
+                self.request_blocks().await;
+            }
+            ConnectionMessageContent::Trickle => {
+                for (peer, inv) in self.relay.trickle() {
+                    self.send_or_forget(peer, ServerMessage::SendMsg(Message::Inv(inv)))
+                        .await;
+                }
+            }
+            ConnectionMessageContent::Tick => {
+                self.mempool.lock().await.expire_orphans();
+                let pruned = self.blockchain.lock().await.prune()?;
+                if pruned > 0 {
+                    info!("Pruned {} blocks", pruned);
+                }
+                self.ticks += 1;
+                if self.ticks % MEMPOOL_SAVE_TICKS == 0 {
+                    self.save_mempool().await;
+                }
+                for peer in self.download_manager.timeouts() {
+                    warn!("[{}] stalled the block download", peer);
+                    self.send_or_forget(peer, ServerMessage::Terminate(TerminationReason::Stalled))
+                        .await;
+                }
+                for (source, block) in self.download_manager.ready() {
+                    self.handle_new_block(block, source).await?;
+                }
+                self.request_blocks().await;
             }
             ConnectionMessageContent::NewConnection(socket) => {
-                if self.connection_count < self.max_connections_count {
+                let banned = match socket.peer_addr() {
+                    Ok(addr) => self
+                        .address_manager
+                        .is_banned(&crate::data::intern_messages::Peer::from(addr).ip),
+                    Err(_) => true,
+                };
+                if banned {
+                    trace!("Dropped connection from banned peer");
+                } else if self.slots.can_accept() {
                     trace!("new connection");
                     let id = self.next_id_to_give;
                     self.next_id_to_give += 1;
@@ -500,17 +1046,58 @@ impl Server {
                         socket,
                         self.connection_sender.clone(),
                         self.origin_port,
+                        self.network.magic,
+                        self.pruned,
                         id,
                     );
+                    self.slots.accepted(id);
                 }
             }
         }
         Ok(true)
     }
 
+    async fn save_mempool(&self) {
+        if let Err(e) = self.mempool.lock().await.save(&self.mempool_file) {
+            warn!("Could not save the mempool: {}", e)
+        }
+    }
+
+    async fn request_blocks(&mut self) {
+        for (id, hashes) in self.download_manager.schedule() {
+            let inv = hashes
+                .into_iter()
+                .map(|hash| InvVect {
+                    data_type: ResourceType::Block,
+                    hash,
+                })
+                .collect();
+            self.send_or_forget(id, ServerMessage::SendMsg(Message::GetData(inv)))
+                .await;
+        }
+    }
+
+    /// Scores the offense of the remote and terminates the connection if it got banned
+    async fn punish(&mut self, remote: &RemoteIdentity, offense: Misbehavior) -> Result<(), Error> {
+        warn!("[{}] misbehaved: {:?}", remote.id, offense);
+        if self.address_manager.misbehaved(remote.socket.ip, offense) {
+            self.send(
+                remote.id,
+                ServerMessage::Terminate(TerminationReason::Banned),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
     // TODO: Be a good peer finder
     async fn find_new_peer(&mut self) {
-        for peer in self.address_manager.get_some_peers(10_usize) {
+        let available = self.slots.outbound_available();
+        if available == 0 {
+            return;
+        }
+        let used_groups = self.slots.outbound_netgroups();
+        for peer in self.address_manager.get_some_peers(available, &used_groups) {
             let address = std::net::SocketAddr::from((peer.ip, peer.port));
             let id = self.next_id_to_give;
             self.next_id_to_give += 1;
@@ -518,12 +1105,17 @@ impl Server {
                 address.clone(),
                 self.connection_sender.clone(),
                 self.origin_port,
+                self.network.magic,
+                self.pruned,
                 id,
             )
             .await
             {
                 warn!("New peer {} errored: {:?}", address, e);
                 self.next_id_to_give -= 1;
+                self.address_manager.failed(peer);
+            } else {
+                self.slots.initiated(id, peer.netgroup());
             }
         }
     }
@@ -537,23 +1129,20 @@ impl Server {
             info!("Handling block of height: {}", block.header.height);
             let mut lblock = LinkedBlock::new(block);
             let hash = lblock.header.double_hash();
-            self.utxo_manager.link_block(&mut lblock);
-            let new_target = self
-                .blockchain
-                .lock()
-                .await
-                .get_target_next_block(lblock.header.timestamp)?;
             debug!(
                 "Validating block {}",
                 ensicoin_serializer::hash_to_string(&lblock.header.double_hash())
             );
-            let prev_block = match self
-                .blockchain
-                .lock()
-                .await
-                .get_block(&lblock.header.prev_block)?
-            {
-                Some(b) => b,
+            let prev_header = {
+                let blockchain = self.blockchain.lock().await;
+                if blockchain.exists(&lblock.header.prev_block)? {
+                    blockchain.get_header(&lblock.header.prev_block)?
+                } else {
+                    None
+                }
+            };
+            let prev_header = match prev_header {
+                Some(h) => h,
                 None => {
                     warn!(
                         "Orphan block: {}",
@@ -563,111 +1152,133 @@ impl Server {
                     return Ok(());
                 }
             };
-            if lblock.is_valid(new_target, prev_block.header.height) {
-                let inv = vec![ensicoin_messages::message::InvVect {
-                    hash: lblock.header.double_hash(),
-                    data_type: ensicoin_messages::message::ResourceType::Block,
-                }];
-                self.broadcast_to_connections(ServerMessage::SendMsg(Message::Inv(inv)))
-                    .await?;
-                let addition = self.blockchain.lock().await.new_block(lblock.clone())?;
-                match addition {
-                    NewAddition::Fork => {
-                        info!("Handling fork");
-                        self.utxo_manager.register_block(&lblock)?;
-                        self.mempool.lock().await.remove_tx(&lblock);
-                        let best_block = self.blockchain.lock().await.best_block_hash()?;
-                        let common_hash = match self
-                            .blockchain
-                            .lock()
-                            .await
-                            .find_common_hash(best_block, hash)?
-                        {
-                            Some(h) => h,
-                            None => return Err(Error::NotFound("merge point".to_string())),
-                        };
-                        let new_branch = self
-                            .blockchain
-                            .lock()
-                            .await
-                            .chain_until(&hash, &common_hash)?;
-                        let pop_contex = self.blockchain.lock().await.pop_until(&common_hash)?;
-                        for utxo in pop_contex.utxo_to_remove {
-                            self.utxo_manager.delete(&utxo)?;
-                        }
-                        self.utxo_manager.restore(pop_contex.utxo_to_restore)?;
-                        for tx in pop_contex.txs_to_restore {
-                            let mut ltx = LinkedTransaction::new(tx);
-                            self.utxo_manager.link(&mut ltx);
-                            self.mempool.lock().await.insert(ltx);
-                        }
-                        let block_chain =
-                            self.blockchain.lock().await.chain_to_blocks(new_branch)?;
-                        let mut linked_chain: Vec<_> =
-                            block_chain.into_iter().map(LinkedBlock::new).collect();
-                        linked_chain
-                            .iter_mut()
-                            .for_each(|mut lb| self.utxo_manager.link_block(&mut lb));
-                        for lb in &linked_chain {
-                            self.utxo_manager.register_block(lb)?;
-                        }
-                        self.blockchain.lock().await.add_chain(linked_chain)?;
-                        trace!(
-                            "New best block after fork: {}",
-                            ensicoin_serializer::hash_to_string(&lblock.header.double_hash())
-                        );
-                        #[cfg(feature = "grpc")]
-                        {
-                            if self
-                                .broadcast_channel_tx
-                                .send(BroadcastMessage::BestBlock(
-                                    self.blockchain
-                                        .lock()
-                                        .await
-                                        .get_block(
-                                            &self.blockchain.lock().await.best_block_hash()?,
-                                        )?
-                                        .unwrap(),
-                                ))
+            let (new_target, linked) = {
+                let blockchain = self.blockchain.lock().await;
+                let new_target = blockchain.target_after(&prev_header, lblock.header.timestamp)?;
+                let linked = if lblock.header.prev_block == blockchain.best_block_hash()? {
+                    self.utxo_manager.link_block(&mut lblock);
+                    Ok(())
+                } else {
+                    blockchain.link_fork_block(&mut lblock)
+                };
+                (new_target, linked)
+            };
+            if let Err(e) = linked {
+                warn!(
+                    "Could not rebuild the outputs of the branch of {}: {}",
+                    ensicoin_serializer::hash_to_string(&hash),
+                    e
+                );
+                return Ok(());
+            }
+            let median_time_past = self
+                .blockchain
+                .lock()
+                .await
+                .median_time_past(&lblock.header.prev_block)?;
+            let context = BlockContext {
+                previous: prev_header,
+                target: new_target,
+                median_time_past,
+                now: self.clock.now(),
+                network: self.network,
+            };
+            match lblock.is_valid(&context) {
+                Ok(()) => {
+                    if let Source::Connection(remote) = &source {
+                        self.slots.useful_block(remote.id);
+                    }
+                    let inv = vec![ensicoin_messages::message::InvVect {
+                        hash: lblock.header.double_hash(),
+                        data_type: ensicoin_messages::message::ResourceType::Block,
+                    }];
+                    for peer in self.relay.block_targets(hash, source.connection_id()) {
+                        self.send(peer, ServerMessage::SendMsg(Message::Inv(inv.clone())))
+                            .await?;
+                    }
+                    let addition = self.blockchain.lock().await.new_block(lblock.clone())?;
+                    match addition {
+                        NewAddition::Fork => {
+                            info!("Handling fork");
+                            self.wallet.lock().await.register_block(&lblock)?;
+                            self.mempool.lock().await.remove_tx(&lblock);
+                            let best_block = self.blockchain.lock().await.best_block_hash()?;
+                            let common_hash = match self
+                                .blockchain
+                                .lock()
+                                .await
+                                .find_common_hash(best_block, hash)?
+                            {
+                                Some(h) => h,
+                                None => return Err(Error::NotFound("merge point".to_string())),
+                            };
+                            let new_branch = self
+                                .blockchain
+                                .lock()
+                                .await
+                                .chain_until(&hash, &common_hash)?;
+                            let pop_contex =
+                                self.blockchain.lock().await.pop_until(&common_hash)?;
+                            for utxo in pop_contex.utxo_to_remove {
+                                self.wallet.lock().await.delete(&utxo)?;
+                            }
+                            self.wallet
+                                .lock()
                                 .await
-                                .is_err()
+                                .restore(&pop_contex.utxo_to_restore)?;
+                            for tx in pop_contex.txs_to_restore {
+                                let mut ltx = LinkedTransaction::new(tx);
+                                self.utxo_manager.link(&mut ltx);
+                                self.mempool.lock().await.insert(ltx, None);
+                            }
+                            let block_chain =
+                                self.blockchain.lock().await.chain_to_blocks(new_branch)?;
+                            for block in block_chain {
+                                let mut lb = LinkedBlock::new(block);
+                                self.utxo_manager.link_block(&mut lb);
+                                self.wallet.lock().await.register_block(&lb)?;
+                                self.mempool.lock().await.remove_tx(&lb);
+                                self.blockchain.lock().await.add_block(lb)?;
+                            }
                             {
-                                error!("Could not broadcast");
+                                let mut mempool = self.mempool.lock().await;
+                                mempool.set_height(lblock.header.height);
+                                mempool.evict_immature();
                             }
+                            trace!(
+                                "New best block after fork: {}",
+                                ensicoin_serializer::hash_to_string(&lblock.header.double_hash())
+                            );
+                            #[cfg(feature = "grpc")]
+                            self.broadcast_best_block().await?;
                         }
-                    }
-                    NewAddition::BestBlock => {
-                        trace!(
-                            "New best block: {}",
-                            ensicoin_serializer::hash_to_string(&lblock.header.double_hash())
-                        );
-                        self.utxo_manager.register_block(&lblock)?;
-                        #[cfg(feature = "grpc")]
-                        {
-                            if self
-                                .broadcast_channel_tx
-                                .send(BroadcastMessage::BestBlock(
-                                    self.blockchain
-                                        .lock()
-                                        .await
-                                        .get_block(
-                                            &self.blockchain.lock().await.best_block_hash()?,
-                                        )?
-                                        .unwrap(),
-                                ))
-                                .await
-                                .is_err()
+                        NewAddition::BestBlock => {
+                            trace!(
+                                "New best block: {}",
+                                ensicoin_serializer::hash_to_string(&lblock.header.double_hash())
+                            );
+                            self.wallet.lock().await.register_block(&lblock)?;
                             {
-                                error!("Could not broadcast");
+                                let mut mempool = self.mempool.lock().await;
+                                mempool.remove_tx(&lblock);
+                                mempool.set_height(lblock.header.height);
                             }
+                            #[cfg(feature = "grpc")]
+                            self.broadcast_best_block().await?;
+                        }
+                        NewAddition::Nothing => {
+                            info!("Added block to a sidechain");
                         }
                     }
-                    NewAddition::Nothing => {
-                        info!("Added block to a sidechain");
+                }
+                Err(e) => {
+                    warn!("Recieved invalid Block from {}: {}", source, e);
+                    if let Source::Connection(remote) = &source {
+                        if e.is_context_free() {
+                            self.punish(remote, Misbehavior::InvalidBlock).await?;
+                        }
                     }
                 }
-            } else {
-                warn!("Recieved invalid Block from {}", source);
             }
             let best_block_hash = self.blockchain.lock().await.best_block_hash()?;
             let orphan_chain = self.orphan_manager.retrieve_chain(best_block_hash);
@@ -0,0 +1,209 @@
+use crate::data::intern_messages::RemoteIdentity;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+const PROTECTED_BY_NETGROUP: usize = 4;
+const PROTECTED_BY_LATENCY: usize = 4;
+const PROTECTED_BY_TX: usize = 4;
+const PROTECTED_BY_BLOCK: usize = 4;
+
+struct PeerInfo {
+    inbound: bool,
+    netgroup: Vec<u8>,
+    connected: Instant,
+    latency: Option<Duration>,
+    last_tx: Option<Instant>,
+    last_block: Option<Instant>,
+}
+
+pub enum Admission {
+    Accepted,
+    /// The connection is accepted in place of the given inbound connection
+    Evict(u64),
+    Refused,
+}
+
+/// Keeps inbound and outbound connections in separate slots so that remotes connecting to us
+/// can not take the place of the peers we chose
+pub struct ConnectionSlots {
+    peers: HashMap<u64, PeerInfo>,
+    pending: HashMap<u64, Vec<u8>>,
+    pending_inbound: HashSet<u64>,
+    max_inbound: u64,
+    max_outbound: u64,
+}
+
+fn protect<K: Ord, F: Fn(&PeerInfo) -> K>(
+    candidates: &mut Vec<(u64, &PeerInfo)>,
+    amount: usize,
+    key: F,
+) {
+    candidates.sort_by_key(|(_, info)| key(*info));
+    candidates.drain(..std::cmp::min(amount, candidates.len()));
+}
+
+impl ConnectionSlots {
+    pub fn new(max_inbound: u64, max_outbound: u64) -> ConnectionSlots {
+        ConnectionSlots {
+            peers: HashMap::new(),
+            pending: HashMap::new(),
+            pending_inbound: HashSet::new(),
+            max_inbound,
+            max_outbound,
+        }
+    }
+
+    /// Inbound connections, including the ones still in handshake
+    fn inbound_count(&self) -> u64 {
+        (self.peers.values().filter(|info| info.inbound).count() + self.pending_inbound.len())
+            as u64
+    }
+
+    /// Outbound connections, including the ones still in handshake
+    fn outbound_count(&self) -> u64 {
+        (self.peers.values().filter(|info| !info.inbound).count() + self.pending.len()) as u64
+    }
+
+    /// Number of outbound connections that can still be initiated
+    pub fn outbound_available(&self) -> usize {
+        self.max_outbound.saturating_sub(self.outbound_count()) as usize
+    }
+
+    /// An inbound connection can be accepted, maybe by evicting another one. Only one
+    /// handshake at a time can wait for an eviction
+    pub fn can_accept(&self) -> bool {
+        self.inbound_count() < self.max_inbound
+            || (self.pending_inbound.is_empty() && self.eviction_candidate().is_some())
+    }
+
+    /// Reserves an outbound slot for a connection in handshake
+    pub fn initiated(&mut self, id: u64, netgroup: Vec<u8>) {
+        self.pending.insert(id, netgroup);
+    }
+
+    /// Reserves an inbound slot for a connection in handshake
+    pub fn accepted(&mut self, id: u64) {
+        self.pending_inbound.insert(id);
+    }
+
+    /// Network groups we already have an outbound connection to
+    pub fn outbound_netgroups(&self) -> HashSet<Vec<u8>> {
+        self.peers
+            .values()
+            .filter(|info| !info.inbound)
+            .map(|info| &info.netgroup)
+            .chain(self.pending.values())
+            .cloned()
+            .collect()
+    }
+
+    pub fn register(&mut self, identity: &RemoteIdentity) -> Admission {
+        self.pending.remove(&identity.id);
+        self.pending_inbound.remove(&identity.id);
+        let admission = if identity.inbound {
+            if self.inbound_count() < self.max_inbound {
+                Admission::Accepted
+            } else {
+                match self.eviction_candidate() {
+                    Some(id) => {
+                        self.peers.remove(&id);
+                        Admission::Evict(id)
+                    }
+                    None => Admission::Refused,
+                }
+            }
+        } else if self.outbound_count() < self.max_outbound {
+            Admission::Accepted
+        } else {
+            Admission::Refused
+        };
+        if let Admission::Refused = admission {
+            return admission;
+        }
+        self.peers.insert(
+            identity.id,
+            PeerInfo {
+                inbound: identity.inbound,
+                netgroup: identity.socket.netgroup(),
+                connected: Instant::now(),
+                latency: None,
+                last_tx: None,
+                last_block: None,
+            },
+        );
+        admission
+    }
+
+    /// Frees the slot of a connection, returns true if it was registered
+    pub fn remove(&mut self, id: u64) -> bool {
+        self.pending.remove(&id);
+        self.pending_inbound.remove(&id);
+        self.peers.remove(&id).is_some()
+    }
+
+    pub fn set_latency(&mut self, id: u64, latency: Duration) {
+        if let Some(info) = self.peers.get_mut(&id) {
+            info.latency = Some(latency);
+        }
+    }
+
+    /// The peer relayed a transaction we did not know
+    pub fn useful_tx(&mut self, id: u64) {
+        if let Some(info) = self.peers.get_mut(&id) {
+            info.last_tx = Some(Instant::now());
+        }
+    }
+
+    /// The peer relayed a valid block
+    pub fn useful_block(&mut self, id: u64) {
+        if let Some(info) = self.peers.get_mut(&id) {
+            info.last_block = Some(Instant::now());
+        }
+    }
+
+    /// Picks the inbound connection to drop: the peers in the rarest network groups, the
+    /// fastest ones and the ones that relayed transactions or blocks recently are protected,
+    /// then the youngest connection of the most represented network group is chosen
+    fn eviction_candidate(&self) -> Option<u64> {
+        let mut group_sizes: HashMap<&[u8], usize> = HashMap::new();
+        for info in self.peers.values().filter(|info| info.inbound) {
+            *group_sizes.entry(info.netgroup.as_slice()).or_insert(0) += 1;
+        }
+        let mut candidates: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, info)| info.inbound)
+            .map(|(id, info)| (*id, info))
+            .collect();
+
+        protect(&mut candidates, PROTECTED_BY_NETGROUP, |info| {
+            group_sizes[info.netgroup.as_slice()]
+        });
+        protect(&mut candidates, PROTECTED_BY_LATENCY, |info| {
+            (info.latency.is_none(), info.latency)
+        });
+        protect(&mut candidates, PROTECTED_BY_TX, |info| {
+            Reverse(info.last_tx)
+        });
+        protect(&mut candidates, PROTECTED_BY_BLOCK, |info| {
+            Reverse(info.last_block)
+        });
+
+        let mut groups: HashMap<&[u8], Vec<(u64, Instant)>> = HashMap::new();
+        for (id, info) in candidates {
+            groups
+                .entry(info.netgroup.as_slice())
+                .or_default()
+                .push((id, info.connected));
+        }
+        groups
+            .into_iter()
+            .map(|(_, peers)| peers)
+            .max_by_key(|peers| (peers.len(), peers.iter().map(|(_, t)| *t).max()))
+            .and_then(|peers| peers.into_iter().max_by_key(|(_, t)| *t))
+            .map(|(id, _)| id)
+    }
+}
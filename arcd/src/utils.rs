@@ -1,5 +1,6 @@
 use ensicoin_serializer::Sha256Result;
 use num_bigint::BigUint;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn big_uint_to_hash(big: BigUint) -> Sha256Result {
     let mut bytes = big.to_bytes_be();
@@ -7,3 +8,10 @@ pub fn big_uint_to_hash(big: BigUint) -> Sha256Result {
     zeros.append(&mut bytes);
     Sha256Result::clone_from_slice(&zeros)
 }
+
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Back in time are you ?")
+        .as_secs()
+}
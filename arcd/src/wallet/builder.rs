@@ -0,0 +1,268 @@
+use crate::data::PairedUtxo;
+use ensicoin_messages::resource::{
+    script::{Script, OP},
+    tx::{TransactionInput, TransactionOutput},
+    Transaction,
+};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+#[derive(Debug)]
+pub enum BuildError {
+    NoOutputs,
+    NoChangeScript,
+    ValueOverflow,
+    InsufficientFunds { available: u64, needed: u64 },
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BuildError::NoOutputs => write!(f, "Transaction has no outputs"),
+            BuildError::NoChangeScript => write!(f, "Change is needed but no script was given"),
+            BuildError::ValueOverflow => write!(f, "Values overflow"),
+            BuildError::InsufficientFunds { available, needed } => write!(
+                f,
+                "Insufficient funds: {} available, {} needed",
+                available, needed
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Builds a transaction spending P2PKH utxos and signs every input
+pub struct TransactionBuilder {
+    coins: Vec<(PairedUtxo, SecretKey)>,
+    outputs: Vec<TransactionOutput>,
+    flags: Vec<String>,
+    fee: u64,
+    change: Option<Script>,
+}
+
+impl TransactionBuilder {
+    pub fn new() -> TransactionBuilder {
+        TransactionBuilder {
+            coins: Vec::new(),
+            outputs: Vec::new(),
+            flags: Vec::new(),
+            fee: 0,
+            change: None,
+        }
+    }
+
+    pub fn coin(mut self, utxo: PairedUtxo, key: SecretKey) -> Self {
+        self.coins.push((utxo, key));
+        self
+    }
+
+    pub fn pay(mut self, script: Script, value: u64) -> Self {
+        self.outputs.push(TransactionOutput { value, script });
+        self
+    }
+
+    pub fn flag(mut self, flag: String) -> Self {
+        self.flags.push(flag);
+        self
+    }
+
+    pub fn fee(mut self, fee: u64) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn change(mut self, script: Script) -> Self {
+        self.change = Some(script);
+        self
+    }
+
+    pub fn build(mut self) -> Result<Transaction, BuildError> {
+        if self.outputs.is_empty() {
+            return Err(BuildError::NoOutputs);
+        }
+        let needed = self
+            .outputs
+            .iter()
+            .try_fold(self.fee, |acc, output| acc.checked_add(output.value))
+            .ok_or(BuildError::ValueOverflow)?;
+
+        self.coins
+            .sort_by(|(a, _), (b, _)| b.data.value.cmp(&a.data.value));
+        let mut selected = Vec::new();
+        let mut available: u64 = 0;
+        for coin in self.coins {
+            if available >= needed {
+                break;
+            }
+            available = available
+                .checked_add(coin.0.data.value)
+                .ok_or(BuildError::ValueOverflow)?;
+            selected.push(coin);
+        }
+        if available < needed {
+            return Err(BuildError::InsufficientFunds { available, needed });
+        }
+
+        let mut outputs = self.outputs;
+        if available > needed {
+            match self.change {
+                Some(script) => outputs.push(TransactionOutput {
+                    value: available - needed,
+                    script,
+                }),
+                None => return Err(BuildError::NoChangeScript),
+            }
+        }
+        let mut tx = Transaction {
+            version: crate::constants::VERSION,
+            flags: self.flags,
+            inputs: selected
+                .iter()
+                .map(|(coin, _)| TransactionInput {
+                    previous_output: coin.outpoint.clone(),
+                    script: Script::from(Vec::new()),
+                })
+                .collect(),
+            outputs,
+        };
+
+        let secp = Secp256k1::signing_only();
+        let scripts: Vec<_> = selected
+            .iter()
+            .enumerate()
+            .map(|(i, (coin, key))| {
+                let hash = crate::consensus::signature_hash(&tx, i, coin.data.value);
+                let msg = Message::from_slice(&hash).expect("signature hash is 32 bytes");
+                let signature = secp.sign(&msg, key).serialize_compact();
+                let public_key = PublicKey::from_secret_key(&secp, key).serialize();
+                signature_script(&signature, &public_key)
+            })
+            .collect();
+        for (input, script) in tx.inputs.iter_mut().zip(scripts) {
+            input.script = script;
+        }
+        Ok(tx)
+    }
+}
+
+fn signature_script(signature: &[u8], public_key: &[u8]) -> Script {
+    let mut script = vec![OP::Push(signature.len() as u8)];
+    script.extend(signature.iter().copied().map(OP::Byte));
+    script.push(OP::Push(public_key.len() as u8));
+    script.extend(public_key.iter().copied().map(OP::Byte));
+    Script::from(script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildError, TransactionBuilder};
+    use crate::{
+        consensus::ValidationError,
+        data::{
+            linkedtx::{Dependency, DependencyType, LinkedTransaction},
+            PairedUtxo, UtxoData,
+        },
+        wallet::{p2pkh_script, pubkey_hash},
+    };
+    use ensicoin_messages::resource::{Outpoint, Transaction};
+    use ensicoin_serializer::Sha256Result;
+    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+    fn key(seed: u8) -> SecretKey {
+        SecretKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    fn coin(key: &SecretKey, value: u64, index: u32) -> PairedUtxo {
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), key);
+        PairedUtxo {
+            data: UtxoData {
+                script: p2pkh_script(&pubkey_hash(&public_key)),
+                value,
+                block_height: 1,
+                coin_base: false,
+            },
+            outpoint: Outpoint {
+                hash: Sha256Result::from([7; 32]),
+                index,
+            },
+        }
+    }
+
+    fn link(tx: Transaction, coins: &[PairedUtxo]) -> LinkedTransaction {
+        let mut ltx = LinkedTransaction::new(tx);
+        for coin in coins {
+            ltx.add_dependency(
+                coin.outpoint.clone(),
+                Dependency {
+                    dep_type: DependencyType::Block,
+                    data: coin.data.clone(),
+                },
+            );
+        }
+        ltx
+    }
+
+    #[test]
+    fn signed_tx_is_valid() {
+        let key = key(1);
+        let coins = vec![coin(&key, 30, 0), coin(&key, 50, 1)];
+        let tx = TransactionBuilder::new()
+            .coin(coins[0].clone(), key)
+            .coin(coins[1].clone(), key)
+            .pay(p2pkh_script(&[2; 20]), 60)
+            .fee(5)
+            .change(p2pkh_script(&[3; 20]))
+            .build()
+            .unwrap();
+        assert_eq!(tx.inputs.len(), 2);
+        assert_eq!(tx.outputs[1].value, 15);
+        let ltx = link(tx, &coins);
+        assert!(ltx.is_complete());
+        assert!(ltx.is_valid().is_ok());
+        assert_eq!(ltx.fee(), Some(5));
+    }
+
+    #[test]
+    fn tampered_tx_is_invalid() {
+        let key = key(1);
+        let coins = vec![coin(&key, 50, 0)];
+        let mut tx = TransactionBuilder::new()
+            .coin(coins[0].clone(), key)
+            .pay(p2pkh_script(&[2; 20]), 50)
+            .build()
+            .unwrap();
+        tx.outputs[0].value = 40;
+        match link(tx, &coins).is_valid() {
+            Err(ValidationError::InvalidScript(0)) => (),
+            _ => panic!("tampered tx should have an invalid script"),
+        }
+    }
+
+    #[test]
+    fn wrong_key_is_invalid() {
+        let coins = vec![coin(&key(1), 50, 0)];
+        let tx = TransactionBuilder::new()
+            .coin(coins[0].clone(), key(2))
+            .pay(p2pkh_script(&[2; 20]), 50)
+            .build()
+            .unwrap();
+        assert!(link(tx, &coins).is_valid().is_err());
+    }
+
+    #[test]
+    fn insufficient_funds() {
+        let key = key(1);
+        match TransactionBuilder::new()
+            .coin(coin(&key, 10, 0), key)
+            .pay(p2pkh_script(&[2; 20]), 10)
+            .fee(1)
+            .build()
+        {
+            Err(BuildError::InsufficientFunds {
+                available: 10,
+                needed: 11,
+            }) => (),
+            _ => panic!("builder should lack funds"),
+        }
+    }
+}
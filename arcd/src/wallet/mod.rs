@@ -0,0 +1,485 @@
+use crate::{
+    data::{linkedblock::LinkedBlock, ser_utxo_data, PairedUtxo, UtxoData},
+    manager::Mempool,
+};
+use bytes::BytesMut;
+use ensicoin_messages::resource::{
+    script::{Script, OP},
+    tx::fn_outpoint,
+    Outpoint,
+};
+use ensicoin_serializer::{Deserialize, Deserializer};
+use rand::RngCore;
+use ripemd160::{Digest, Ripemd160};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use std::collections::HashSet;
+
+mod builder;
+
+pub use builder::{BuildError, TransactionBuilder};
+
+pub type PubKeyHash = [u8; 20];
+
+#[derive(Debug)]
+pub enum WalletError {
+    ParseError(ensicoin_serializer::Error),
+    DbError(sled::Error),
+    KeyError(secp256k1::Error),
+    IoError(std::io::Error),
+}
+
+impl std::fmt::Display for WalletError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WalletError::ParseError(e) => write!(f, "Error parsing data: {}", e),
+            WalletError::DbError(e) => write!(f, "Error in database: {}", e),
+            WalletError::KeyError(e) => write!(f, "Invalid key: {}", e),
+            WalletError::IoError(e) => write!(f, "Error accessing wallet: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WalletError {}
+
+impl From<ensicoin_serializer::Error> for WalletError {
+    fn from(err: ensicoin_serializer::Error) -> Self {
+        WalletError::ParseError(err)
+    }
+}
+
+impl From<sled::Error> for WalletError {
+    fn from(err: sled::Error) -> Self {
+        WalletError::DbError(err)
+    }
+}
+
+impl From<secp256k1::Error> for WalletError {
+    fn from(err: secp256k1::Error) -> Self {
+        WalletError::KeyError(err)
+    }
+}
+
+impl From<std::io::Error> for WalletError {
+    fn from(err: std::io::Error) -> Self {
+        WalletError::IoError(err)
+    }
+}
+
+pub fn pubkey_hash(key: &PublicKey) -> PubKeyHash {
+    let mut hasher = Ripemd160::new();
+    hasher.input(&key.serialize()[..]);
+    let mut hash = [0; 20];
+    hash.copy_from_slice(&hasher.result());
+    hash
+}
+
+/// Script of an output spendable by the key hashing to `hash`
+pub fn p2pkh_script(hash: &PubKeyHash) -> Script {
+    let mut script = vec![OP::Dup, OP::Hash160, OP::Push(20)];
+    script.extend(hash.iter().copied().map(OP::Byte));
+    script.append(&mut vec![OP::Equal, OP::Verify, OP::Checksig]);
+    Script::from(script)
+}
+
+pub fn script_pubkey_hash(script: &Script) -> Option<PubKeyHash> {
+    let ops = script.clone().into_inner();
+    if ops.len() != 26
+        || ops[..3] != [OP::Dup, OP::Hash160, OP::Push(20)]
+        || ops[23..] != [OP::Equal, OP::Verify, OP::Checksig]
+    {
+        return None;
+    }
+    let mut hash = [0; 20];
+    for (i, op) in ops[3..23].iter().enumerate() {
+        match op {
+            OP::Byte(b) => hash[i] = *b,
+            _ => return None,
+        }
+    }
+    Some(hash)
+}
+
+pub struct Balance {
+    pub confirmed: u64,
+    pub unconfirmed: u64,
+}
+
+pub struct Wallet {
+    keys: sled::Tree,
+    coins: sled::Tree,
+    secp: Secp256k1<secp256k1::All>,
+}
+
+impl Wallet {
+    /// Secret keys are stored unencrypted in `data_dir/wallet`, on unix the directory is
+    /// only accessible by its owner
+    pub fn new(data_dir: &std::path::Path) -> Result<Wallet, WalletError> {
+        let mut wallet_dir = std::path::PathBuf::new();
+        wallet_dir.push(data_dir);
+        wallet_dir.push("wallet");
+        std::fs::create_dir_all(&wallet_dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&wallet_dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+        let db = sled::Db::open(wallet_dir)?;
+
+        Wallet::from_db(&db)
+    }
+
+    fn from_db(db: &sled::Db) -> Result<Wallet, WalletError> {
+        Ok(Wallet {
+            keys: db.open_tree("keys")?,
+            coins: db.open_tree("coins")?,
+            secp: Secp256k1::new(),
+        })
+    }
+
+    pub fn new_address(&mut self) -> Result<PubKeyHash, WalletError> {
+        let mut rng = rand::thread_rng();
+        let mut raw_key = [0; 32];
+        let secret_key = loop {
+            rng.fill_bytes(&mut raw_key);
+            if let Ok(k) = SecretKey::from_slice(&raw_key) {
+                break k;
+            }
+        };
+        let public_key = PublicKey::from_secret_key(&self.secp, &secret_key);
+        let hash = pubkey_hash(&public_key);
+        self.keys.insert(&hash, &secret_key[..])?;
+        Ok(hash)
+    }
+
+    pub fn addresses(&self) -> Vec<PubKeyHash> {
+        self.keys
+            .iter()
+            .keys()
+            .filter_map(|res| match res {
+                Ok(k) if k.len() == 20 => {
+                    let mut hash = [0; 20];
+                    hash.copy_from_slice(&k);
+                    Some(hash)
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    warn!("Error reading wallet keys: {}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn secret_key(&self, hash: &PubKeyHash) -> Result<Option<SecretKey>, WalletError> {
+        match self.keys.get(hash)? {
+            Some(raw) => Ok(Some(SecretKey::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_mine(&self, script: &Script) -> bool {
+        match script_pubkey_hash(script) {
+            Some(hash) => match self.keys.contains_key(&hash) {
+                Ok(b) => b,
+                Err(e) => {
+                    warn!("Error reading wallet keys: {}", e);
+                    false
+                }
+            },
+            None => false,
+        }
+    }
+
+    pub fn coins(&self) -> Vec<PairedUtxo> {
+        self.coins
+            .iter()
+            .filter_map(|res| {
+                let (key, value) = match res {
+                    Ok(kv) => kv,
+                    Err(e) => {
+                        warn!("Error reading wallet coins: {}", e);
+                        return None;
+                    }
+                };
+                let mut de = Deserializer::new(BytesMut::from(&*key));
+                let outpoint = Outpoint::deserialize(&mut de).ok()?;
+                let mut de = Deserializer::new(BytesMut::from(&*value));
+                let data = UtxoData::deserialize(&mut de).ok()?;
+                Some(PairedUtxo { data, outpoint })
+            })
+            .collect()
+    }
+
+    /// Transaction builder able to spend every coin of the wallet that is mature and not
+    /// already spent by a mempool transaction
+    pub fn builder(&self, mempool: &Mempool) -> Result<TransactionBuilder, WalletError> {
+        let mut builder = TransactionBuilder::new();
+        for coin in self
+            .coins()
+            .into_iter()
+            .filter(|coin| mempool.can_spend(coin))
+        {
+            let key = match script_pubkey_hash(&coin.data.script) {
+                Some(hash) => self.secret_key(&hash)?,
+                None => None,
+            };
+            if let Some(key) = key {
+                builder = builder.coin(coin, key);
+            }
+        }
+        Ok(builder)
+    }
+
+    fn add_coin(&self, outpoint: &Outpoint, data: &UtxoData) -> Result<(), WalletError> {
+        self.coins.insert(
+            ensicoin_messages::as_bytes(fn_outpoint(outpoint)),
+            ensicoin_messages::as_bytes(ser_utxo_data(data)),
+        )?;
+        Ok(())
+    }
+
+    pub fn register_block(&mut self, block: &LinkedBlock) -> Result<(), WalletError> {
+        for (i, ltx) in block.txs.iter().enumerate() {
+            let hash = ltx.transaction.double_hash();
+            for (index, output) in ltx.transaction.outputs.iter().enumerate() {
+                if self.is_mine(&output.script) {
+                    self.add_coin(
+                        &Outpoint {
+                            hash,
+                            index: index as u32,
+                        },
+                        &UtxoData::from_output(output, i == 0, block.header.height),
+                    )?;
+                }
+            }
+        }
+        for pairedutxo in block.spent_utxo() {
+            self.delete(&pairedutxo.outpoint)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete(&mut self, outpoint: &Outpoint) -> Result<(), WalletError> {
+        self.coins
+            .remove(ensicoin_messages::as_bytes(fn_outpoint(outpoint)))?;
+        Ok(())
+    }
+
+    /// Forgets every coin, before the blocks are registered again by a reindex
+    pub fn clear_coins(&mut self) -> Result<(), WalletError> {
+        for key in self.coins.iter().keys() {
+            self.coins.remove(key?)?;
+        }
+        Ok(())
+    }
+
+    pub fn restore(&mut self, utxos: &[PairedUtxo]) -> Result<(), WalletError> {
+        for pairedutxo in utxos {
+            if self.is_mine(&pairedutxo.data.script) {
+                self.add_coin(&pairedutxo.outpoint, &pairedutxo.data)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn balance(&self, mempool: &Mempool) -> Balance {
+        let coins = self.coins();
+        let confirmed = coins.iter().map(|c| c.data.value).sum();
+        let pending = mempool.get_tx();
+        let spent_outpoints: HashSet<&Outpoint> = pending
+            .iter()
+            .flat_map(|tx| tx.inputs.iter())
+            .map(|input| &input.previous_output)
+            .collect();
+        let spent: u64 = coins
+            .iter()
+            .filter(|coin| spent_outpoints.contains(&coin.outpoint))
+            .map(|coin| coin.data.value)
+            .sum();
+        let received: u64 = pending
+            .iter()
+            .flat_map(|tx| tx.outputs.iter())
+            .filter(|output| self.is_mine(&output.script))
+            .map(|output| output.value)
+            .sum();
+        Balance {
+            confirmed,
+            unconfirmed: confirmed - spent + received,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{p2pkh_script, script_pubkey_hash, PubKeyHash, TransactionBuilder, Wallet};
+    use crate::{
+        data::{
+            linkedblock::LinkedBlock,
+            linkedtx::{Dependency, DependencyType, LinkedTransaction},
+            PairedUtxo, UtxoData,
+        },
+        manager::Mempool,
+    };
+    use ensicoin_messages::resource::{
+        script::{Script, OP},
+        tx::{TransactionInput, TransactionOutput},
+        Block, BlockHeader, Outpoint, Transaction,
+    };
+    use ensicoin_serializer::Sha256Result;
+
+    const COIN_VALUE: u64 = 100_000;
+
+    fn wallet() -> Wallet {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Wallet::from_db(&db).unwrap()
+    }
+
+    fn tx(inputs: &[Outpoint], outputs: &[(Script, u64)]) -> Transaction {
+        Transaction {
+            version: 0,
+            flags: Vec::new(),
+            inputs: inputs
+                .iter()
+                .map(|outpoint| TransactionInput {
+                    previous_output: outpoint.clone(),
+                    script: Script::from(Vec::new()),
+                })
+                .collect(),
+            outputs: outputs
+                .iter()
+                .map(|(script, value)| TransactionOutput {
+                    value: *value,
+                    script: script.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn coin(hash: &PubKeyHash, index: u32) -> PairedUtxo {
+        PairedUtxo {
+            data: UtxoData {
+                script: p2pkh_script(hash),
+                value: COIN_VALUE,
+                block_height: 1,
+                coin_base: false,
+            },
+            outpoint: Outpoint {
+                hash: Sha256Result::from([7; 32]),
+                index,
+            },
+        }
+    }
+
+    /// Links the transaction to the coins it spends
+    fn linked(tx: &Transaction, spent: &[PairedUtxo]) -> LinkedTransaction {
+        let mut ltx = LinkedTransaction::new(tx.clone());
+        for coin in spent {
+            ltx.add_dependency(
+                coin.outpoint.clone(),
+                Dependency {
+                    dep_type: DependencyType::Block,
+                    data: coin.data.clone(),
+                },
+            );
+        }
+        ltx
+    }
+
+    fn block(height: u32, txs: Vec<LinkedTransaction>) -> LinkedBlock {
+        let mut block = LinkedBlock::new(Block {
+            header: BlockHeader {
+                version: 0,
+                flags: Vec::new(),
+                prev_block: Sha256Result::from([0; 32]),
+                merkle_root: Sha256Result::from([0; 32]),
+                timestamp: 0,
+                nonce: 0,
+                height,
+                target: Sha256Result::from([0xff; 32]),
+            },
+            txs: Vec::new(),
+        });
+        block.txs = txs;
+        block
+    }
+
+    #[test]
+    fn p2pkh_script_round_trips() {
+        let hash = [42; 20];
+        assert_eq!(script_pubkey_hash(&p2pkh_script(&hash)), Some(hash));
+        assert_eq!(script_pubkey_hash(&Script::from(vec![OP::True])), None);
+
+        let mut ops = p2pkh_script(&hash).into_inner();
+        ops[10] = OP::True;
+        assert_eq!(script_pubkey_hash(&Script::from(ops)), None);
+    }
+
+    #[test]
+    fn register_block_adds_received_and_removes_spent_coins() {
+        let mut wallet = wallet();
+        let address = wallet.new_address().unwrap();
+
+        let coinbase = tx(
+            &[],
+            &[
+                (p2pkh_script(&address), COIN_VALUE),
+                (Script::from(vec![OP::True]), COIN_VALUE),
+            ],
+        );
+        wallet
+            .register_block(&block(1, vec![linked(&coinbase, &[])]))
+            .unwrap();
+        let coins = wallet.coins();
+        assert_eq!(coins.len(), 1);
+        assert_eq!(coins[0].outpoint.hash, coinbase.double_hash());
+        assert_eq!(coins[0].outpoint.index, 0);
+        assert!(coins[0].data.coin_base);
+        assert_eq!(coins[0].data.block_height, 1);
+
+        let spend = tx(
+            &[coins[0].outpoint.clone()],
+            &[(Script::from(vec![OP::True]), COIN_VALUE)],
+        );
+        wallet
+            .register_block(&block(2, vec![linked(&spend, &coins)]))
+            .unwrap();
+        assert!(wallet.coins().is_empty());
+    }
+
+    #[test]
+    fn restore_keeps_only_own_coins() {
+        let mut wallet = wallet();
+        let address = wallet.new_address().unwrap();
+        let mine = coin(&address, 0);
+        let other = coin(&[1; 20], 1);
+
+        wallet.restore(&[mine.clone(), other]).unwrap();
+        let coins = wallet.coins();
+        assert_eq!(coins.len(), 1);
+        assert!(coins[0].outpoint == mine.outpoint);
+    }
+
+    #[test]
+    fn balance_accounts_for_pending_transactions() {
+        let mut wallet = wallet();
+        let address = wallet.new_address().unwrap();
+        let spent = coin(&address, 0);
+        let kept = coin(&address, 1);
+        wallet.restore(&[spent.clone(), kept]).unwrap();
+
+        let mut mempool = Mempool::new(100);
+        let key = wallet.secret_key(&address).unwrap().unwrap();
+        let payment = TransactionBuilder::new()
+            .coin(spent.clone(), key)
+            .pay(p2pkh_script(&address), 30_000)
+            .pay(Script::from(vec![OP::True]), 60_000)
+            .fee(10_000)
+            .build()
+            .unwrap();
+        mempool.insert(linked(&payment, &[spent]), None);
+
+        let balance = wallet.balance(&mempool);
+        assert_eq!(balance.confirmed, 2 * COIN_VALUE);
+        assert_eq!(balance.unconfirmed, COIN_VALUE + 30_000);
+    }
+}
@@ -10,7 +10,8 @@ use cookie_factory::{
 };
 use std::io::Write;
 
-pub use super::resource::{fn_block, fn_tx, Block, Transaction};
+use super::resource::block::fn_block_header;
+pub use super::resource::{fn_block, fn_tx, Block, BlockHeader, Transaction};
 
 #[derive(Deserialize, Clone)]
 pub struct GetBlocks {
@@ -149,6 +150,8 @@ pub enum MessageType {
     GetData,
     NotFound,
     GetBlocks,
+    GetHeaders,
+    Headers,
     GetMempool,
     GetAddr,
     Addr,
@@ -168,6 +171,8 @@ pub enum Message {
     Addr(Vec<Address>),
     GetMempool,
     GetBlocks(GetBlocks),
+    GetHeaders(GetBlocks),
+    Headers(Vec<BlockHeader>),
     Inv(Vec<InvVect>),
     GetData(Vec<InvVect>),
     NotFound(Vec<InvVect>),
@@ -229,6 +234,8 @@ impl Message {
             MessageType::GetAddr => Message::GetAddr,
             MessageType::Addr => Message::Addr(Vec::deserialize(&mut de)?),
             MessageType::GetBlocks => Message::GetBlocks(GetBlocks::deserialize(&mut de)?),
+            MessageType::GetHeaders => Message::GetHeaders(GetBlocks::deserialize(&mut de)?),
+            MessageType::Headers => Message::Headers(Vec::deserialize(&mut de)?),
             MessageType::Inv => Message::Inv(Vec::deserialize(&mut de)?),
             MessageType::GetData => Message::GetData(Vec::deserialize(&mut de)?),
             MessageType::NotFound => Message::NotFound(Vec::deserialize(&mut de)?),
@@ -245,6 +252,8 @@ impl Message {
             Message::Addr(_) => MessageType::Addr,
             Message::GetMempool => MessageType::GetMempool,
             Message::GetBlocks(_) => MessageType::GetBlocks,
+            Message::GetHeaders(_) => MessageType::GetHeaders,
+            Message::Headers(_) => MessageType::Headers,
             Message::Inv(_) => MessageType::Inv,
             Message::GetData(_) => MessageType::GetData,
             Message::NotFound(_) => MessageType::NotFound,
@@ -258,7 +267,8 @@ impl Message {
         match self {
             Message::Whoami(m) => crate::as_bytes(fn_whoami(m)),
             Message::Addr(a) => crate::as_bytes(fn_slice(a, |a| fn_address(*a))),
-            Message::GetBlocks(g) => crate::as_bytes(fn_getblocks(g)),
+            Message::GetBlocks(g) | Message::GetHeaders(g) => crate::as_bytes(fn_getblocks(g)),
+            Message::Headers(h) => crate::as_bytes(fn_slice(h, fn_block_header)),
             Message::Inv(v) | Message::GetData(v) | Message::NotFound(v) => {
                 crate::as_bytes(fn_slice(v, fn_inv_vect))
             }
@@ -293,6 +303,8 @@ pub fn fn_message_type<'c, W: Write + 'c>(msg_type: MessageType) -> impl Seriali
         MessageType::GetAddr => [103, 101, 116, 97, 100, 100, 114, 0, 0, 0, 0, 0],
         MessageType::Addr => [97, 100, 100, 114, 0, 0, 0, 0, 0, 0, 0, 0],
         MessageType::GetBlocks => [103, 101, 116, 98, 108, 111, 99, 107, 115, 0, 0, 0],
+        MessageType::GetHeaders => [103, 101, 116, 104, 101, 97, 100, 101, 114, 115, 0, 0],
+        MessageType::Headers => [104, 101, 97, 100, 101, 114, 115, 0, 0, 0, 0, 0],
         MessageType::GetMempool => [103, 101, 116, 109, 101, 109, 112, 111, 111, 108, 0, 0],
         MessageType::Inv => [105, 110, 118, 0, 0, 0, 0, 0, 0, 0, 0, 0],
         MessageType::GetData => [103, 101, 116, 100, 97, 116, 97, 0, 0, 0, 0, 0],
@@ -333,6 +345,10 @@ impl Deserialize for MessageType {
                 MessageType::NotFound
             } else if raw_type == [103, 101, 116, 98, 108, 111, 99, 107, 115, 0, 0, 0] {
                 MessageType::GetBlocks
+            } else if raw_type == [103, 101, 116, 104, 101, 97, 100, 101, 114, 115, 0, 0] {
+                MessageType::GetHeaders
+            } else if raw_type == [104, 101, 97, 100, 101, 114, 115, 0, 0, 0, 0, 0] {
+                MessageType::Headers
             } else if raw_type == [116, 120, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] {
                 MessageType::Transaction
             } else if raw_type == [98, 108, 111, 99, 107, 0, 0, 0, 0, 0, 0, 0] {
@@ -364,6 +380,8 @@ impl std::fmt::Display for MessageType {
                 MessageType::GetData => "GetData".to_string(),
                 MessageType::NotFound => "NotFound".to_string(),
                 MessageType::GetBlocks => "GetBlocks".to_string(),
+                MessageType::GetHeaders => "GetHeaders".to_string(),
+                MessageType::Headers => "Headers".to_string(),
                 MessageType::GetMempool => "GetMempool".to_string(),
                 MessageType::Transaction => "Transaction".to_string(),
                 MessageType::GetAddr => "GetAddr".to_string(),